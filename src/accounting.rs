@@ -0,0 +1,339 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    net::IpAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use log::{info, warn};
+use tokio::{sync::Notify, time::sleep};
+
+// 缓存的未写入客户端数量上限，超出时提前写入
+const MAX_PENDING: usize = 4096;
+// 日志文件至少达到这个大小才压缩
+const MIN_COMPACT_BYTES: u64 = 64 * 1024;
+// 进行中的连接每隔这么久记入一次流量增量
+pub const LIVE_INTERVAL: Duration = Duration::from_secs(10);
+
+// FsyncPolicy 写入日志后何时 fsync
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsyncPolicy {
+    // 每次写入后，断电最多丢失一个写入周期的数据
+    Always,
+    // 距离上次 fsync 超过指定时间后，减少闪存的写入次数
+    Interval(Duration),
+    // 交给操作系统
+    Never,
+}
+
+// 格式为 always、never 或 interval:SECS
+impl FromStr for FsyncPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "always" => Ok(FsyncPolicy::Always),
+            "never" => Ok(FsyncPolicy::Never),
+            _ => match s.strip_prefix("interval:") {
+                Some(secs) => secs
+                    .parse()
+                    .map(|secs| FsyncPolicy::Interval(Duration::from_secs(secs)))
+                    .map_err(|err| format!("invalid fsync interval {:?}: {}", secs, err)),
+                None => Err(format!("unknown fsync policy {}", s)),
+            },
+        }
+    }
+}
+
+// Usage 一个客户端地址累计的连接数和字节数
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Usage {
+    pub connections: u64,
+    pub up: u64,
+    pub down: u64,
+}
+
+impl Usage {
+    fn add(&mut self, other: Usage) {
+        self.connections += other.connections;
+        self.up += other.up;
+        self.down += other.down;
+    }
+}
+
+// parse_line 解析一行 "ip connections up down"
+fn parse_line(line: &str) -> Option<(IpAddr, Usage)> {
+    let mut fields = line.split(' ');
+    let ip = fields.next()?.parse().ok()?;
+    let usage = Usage {
+        connections: fields.next()?.parse().ok()?,
+        up: fields.next()?.parse().ok()?,
+        down: fields.next()?.parse().ok()?,
+    };
+    fields.next().is_none().then_some((ip, usage))
+}
+
+fn write_lines<'a, W: Write>(
+    out: &mut W,
+    usage: impl IntoIterator<Item = (&'a IpAddr, &'a Usage)>,
+) -> io::Result<u64> {
+    let mut bytes = 0;
+    for (ip, usage) in usage {
+        let line = format!("{} {} {} {}\n", ip, usage.connections, usage.up, usage.down);
+        out.write_all(line.as_bytes())?;
+        bytes += line.len() as u64;
+    }
+    Ok(bytes)
+}
+
+struct Journal {
+    file: File,
+    bytes: u64,
+    last_sync: Instant,
+}
+
+#[derive(Default)]
+struct Totals {
+    // 已经写入日志的累计值
+    journaled: HashMap<IpAddr, Usage>,
+    // 还没有写入日志的增量
+    pending: HashMap<IpAddr, Usage>,
+}
+
+// Accounting 按客户端地址统计流量，增量定期追加到日志文件，重启后从日志恢复
+// 日志每行是一个客户端的增量，所有行相加得到累计值；文件变大后重写为每个客户端一行
+pub struct Accounting {
+    path: PathBuf,
+    fsync: FsyncPolicy,
+    totals: Mutex<Totals>,
+    journal: Mutex<Journal>,
+    full: Notify,
+}
+
+impl Accounting {
+    // open 读取已有的日志并立即压缩
+    // 断电时写了一半的最后一行没有换行符，即使能解析 (例如数字被截断) 也丢弃
+    pub fn open(path: &Path, fsync: FsyncPolicy) -> io::Result<Arc<Self>> {
+        let mut journaled: HashMap<IpAddr, Usage> = HashMap::new();
+        match fs::read_to_string(path) {
+            Ok(content) => {
+                let (complete, torn) = match content.rfind('\n') {
+                    Some(end) => content.split_at(end + 1),
+                    None => ("", content.as_str()),
+                };
+                if !torn.is_empty() {
+                    warn!(
+                        "discarded torn last line {:?} in accounting journal {}",
+                        torn,
+                        path.display()
+                    );
+                }
+                let mut skipped = 0;
+                for line in complete.lines() {
+                    match parse_line(line) {
+                        Some((ip, usage)) => journaled.entry(ip).or_default().add(usage),
+                        None => skipped += 1,
+                    }
+                }
+                if skipped > 0 {
+                    warn!(
+                        "skipped {} malformed lines in accounting journal {}",
+                        skipped,
+                        path.display()
+                    );
+                }
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => (),
+            Err(err) => return Err(err),
+        }
+        let journal = compact(path, &journaled)?;
+        info!(
+            "loaded accounting for {} clients from {}",
+            journaled.len(),
+            path.display()
+        );
+        Ok(Arc::new(Accounting {
+            path: path.into(),
+            fsync,
+            totals: Mutex::new(Totals {
+                journaled,
+                pending: HashMap::new(),
+            }),
+            journal: Mutex::new(journal),
+            full: Notify::new(),
+        }))
+    }
+
+    fn add(&self, ip: IpAddr, usage: Usage) {
+        let mut totals = self.totals.lock().unwrap();
+        totals.pending.entry(ip).or_default().add(usage);
+        if totals.pending.len() >= MAX_PENDING {
+            self.full.notify_one();
+        }
+    }
+
+    // connection 开始统计一条进行中的连接
+    pub fn connection(self: &Arc<Self>, ip: IpAddr) -> ConnectionUsage {
+        ConnectionUsage {
+            accounting: self.clone(),
+            ip,
+            up: 0,
+            down: 0,
+        }
+    }
+
+    // usage 返回每个客户端的累计值，包括还没有写入的部分
+    pub fn usage(&self) -> HashMap<IpAddr, Usage> {
+        let totals = self.totals.lock().unwrap();
+        let mut usage = totals.journaled.clone();
+        for (ip, pending) in &totals.pending {
+            usage.entry(*ip).or_default().add(*pending);
+        }
+        usage
+    }
+
+    // flush 把增量追加到日志，按策略 fsync，文件过大时压缩，会阻塞
+    pub fn flush(&self) -> io::Result<()> {
+        let mut journal = self.journal.lock().unwrap();
+        let pending = std::mem::take(&mut self.totals.lock().unwrap().pending);
+        if pending.is_empty() {
+            return Ok(());
+        }
+        let mut buf = Vec::new();
+        write_lines(&mut buf, &pending)?;
+        if let Err(err) = journal.file.write_all(&buf) {
+            // 截掉写了一半的行，增量放回去下次再写
+            let _ = journal.file.set_len(journal.bytes);
+            let mut totals = self.totals.lock().unwrap();
+            for (ip, usage) in pending {
+                totals.pending.entry(ip).or_default().add(usage);
+            }
+            return Err(err);
+        }
+        journal.bytes += buf.len() as u64;
+        let journaled = {
+            let mut totals = self.totals.lock().unwrap();
+            for (ip, usage) in pending {
+                totals.journaled.entry(ip).or_default().add(usage);
+            }
+            // 每个客户端一行大约 40 字节，日志超过压缩后大小的 4 倍时压缩
+            (journal.bytes > MIN_COMPACT_BYTES.max(totals.journaled.len() as u64 * 160))
+                .then(|| totals.journaled.clone())
+        };
+        if let Some(journaled) = journaled {
+            match compact(&self.path, &journaled) {
+                Ok(compacted) => *journal = compacted,
+                Err(err) => {
+                    // rename 之后才失败时，旧的文件描述符指向已经被替换掉的文件，
+                    // 继续追加会丢数据，重新打开路径上的文件
+                    if let Ok(file) = OpenOptions::new().append(true).open(&self.path) {
+                        journal.bytes = file.metadata().map_or(journal.bytes, |meta| meta.len());
+                        journal.file = file;
+                    }
+                    return Err(err);
+                }
+            }
+            return Ok(());
+        }
+        let sync = match self.fsync {
+            FsyncPolicy::Always => true,
+            FsyncPolicy::Interval(interval) => journal.last_sync.elapsed() >= interval,
+            FsyncPolicy::Never => false,
+        };
+        if sync {
+            journal.file.sync_data()?;
+            journal.last_sync = Instant::now();
+        }
+        Ok(())
+    }
+
+    // run 每隔 period 或缓存满时在阻塞线程中写入
+    pub async fn run(self: Arc<Self>, period: Duration) {
+        loop {
+            tokio::select! {
+                _ = sleep(period) => (),
+                _ = self.full.notified() => (),
+            }
+            let accounting = self.clone();
+            match tokio::task::spawn_blocking(move || accounting.flush()).await {
+                Ok(Ok(())) => (),
+                Ok(Err(err)) => warn!(
+                    "failed to write accounting journal {}: {}",
+                    self.path.display(),
+                    err
+                ),
+                Err(err) => warn!("accounting flush panicked: {}", err),
+            }
+        }
+    }
+}
+
+// ConnectionUsage 一条进行中的连接的流量，update 把新增的字节数记入 Accounting，
+// drop 时 (包括连接被取消) 记入连接数，长连接的流量不用等到连接结束才写入日志
+pub struct ConnectionUsage {
+    accounting: Arc<Accounting>,
+    ip: IpAddr,
+    // 已经记入的字节数
+    up: u64,
+    down: u64,
+}
+
+impl ConnectionUsage {
+    // update 传入连接到目前为止的字节数
+    pub fn update(&mut self, up: u64, down: u64) {
+        if up == self.up && down == self.down {
+            return;
+        }
+        self.accounting.add(
+            self.ip,
+            Usage {
+                connections: 0,
+                up: up - self.up,
+                down: down - self.down,
+            },
+        );
+        self.up = up;
+        self.down = down;
+    }
+}
+
+impl Drop for ConnectionUsage {
+    fn drop(&mut self) {
+        self.accounting.add(
+            self.ip,
+            Usage {
+                connections: 1,
+                up: 0,
+                down: 0,
+            },
+        );
+    }
+}
+
+// compact 把累计值写入临时文件后替换日志，返回追加模式打开的新日志
+fn compact(path: &Path, journaled: &HashMap<IpAddr, Usage>) -> io::Result<Journal> {
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_path);
+    let mut tmp = File::create(&tmp_path)?;
+    let bytes = write_lines(&mut tmp, journaled)?;
+    tmp.sync_all()?;
+    drop(tmp);
+    fs::rename(&tmp_path, path)?;
+    // rename 需要 fsync 所在目录才能保证断电后生效
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    File::open(dir)?.sync_all()?;
+    let file = OpenOptions::new().append(true).open(path)?;
+    Ok(Journal {
+        file,
+        bytes,
+        last_sync: Instant::now(),
+    })
+}
@@ -0,0 +1,34 @@
+use std::net::{IpAddr, SocketAddr, SocketAddrV6};
+
+// 地址规范化，所有需要比较、匹配或记录地址的地方都先转换为规范形式
+// 双栈 socket 上的 IPv4 连接以 IPv4-mapped 地址 (::ffff:a.b.c.d) 出现，
+// 而 SO_ORIGINAL_DST、配置中的 CIDR 使用的是 IPv4 地址，不规范化就会出现同一个地址比较不相等
+
+// canonical_ip 将 IPv4-mapped 地址还原为 IPv4，其他地址不变
+pub fn canonical_ip(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+        IpAddr::V4(_) => ip,
+    }
+}
+
+// canonical_socket_addr 规范化 IP，并清除 IPv6 的 flowinfo (它不影响地址本身，但会影响比较)
+pub fn canonical_socket_addr(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V6(v6) => match v6.ip().to_ipv4_mapped() {
+            Some(v4) => SocketAddr::new(v4.into(), v6.port()),
+            None => SocketAddrV6::new(*v6.ip(), v6.port(), 0, v6.scope_id()).into(),
+        },
+        SocketAddr::V4(_) => addr,
+    }
+}
+
+// same_socket_addr 比较两个地址在规范化之后是否相同
+pub fn same_socket_addr(a: SocketAddr, b: SocketAddr) -> bool {
+    canonical_socket_addr(a) == canonical_socket_addr(b)
+}
+
+// is_loopback 同时识别 ::ffff:127.0.0.0/104
+pub fn is_loopback(ip: IpAddr) -> bool {
+    canonical_ip(ip).is_loopback()
+}
@@ -0,0 +1,79 @@
+use std::{future::Future, io, time::Duration};
+
+use tokio::time::{timeout_at, Instant};
+
+use crate::conn_id::ConnId;
+use crate::metrics::METRICS;
+use crate::trace::Stages;
+
+// Budget 从入站握手完成开始计算的建立连接总时间预算
+// 嗅探、连接上游、握手等阶段共享同一个截止时间，匹配到规则后可以换成规则的预算
+#[derive(Debug, Clone)]
+pub struct Budget {
+    id: ConnId,
+    start: Instant,
+    limit: Option<Duration>,
+    // 已经执行的阶段和各自的耗时，用于 trace
+    stages: Stages,
+}
+
+impl Budget {
+    pub fn new(id: ConnId, limit: Option<Duration>) -> Self {
+        Budget {
+            id,
+            start: Instant::now(),
+            limit,
+            stages: Stages::default(),
+        }
+    }
+
+    pub fn set_limit(&mut self, limit: Option<Duration>) {
+        self.limit = limit;
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.start.elapsed()
+    }
+
+    // stages 按执行顺序输出各阶段的耗时
+    pub fn stages(&self) -> String {
+        self.stages.to_string()
+    }
+
+    // run 在剩余预算内执行一个阶段，超时返回 TimedOut 并记录是在哪个阶段超时的
+    pub async fn run<T, F>(&mut self, stage: &'static str, fut: F) -> io::Result<T>
+    where
+        F: Future<Output = io::Result<T>>,
+    {
+        let begin = Instant::now();
+        let result = self.run_stage(stage, fut).await;
+        self.stages.push(stage, begin.elapsed());
+        result
+    }
+
+    async fn run_stage<T, F>(&self, stage: &str, fut: F) -> io::Result<T>
+    where
+        F: Future<Output = io::Result<T>>,
+    {
+        let limit = match self.limit {
+            Some(limit) => limit,
+            None => return fut.await,
+        };
+        match timeout_at(self.start + limit, fut).await {
+            Ok(result) => result,
+            Err(_) => {
+                METRICS.establish_timeouts.inc();
+                METRICS.establish_timeouts_last.record(self.id);
+                Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!(
+                        "connection not established within {:?}, gave up during {} after {:?}",
+                        limit,
+                        stage,
+                        self.elapsed()
+                    ),
+                ))
+            }
+        }
+    }
+}
@@ -1,22 +1,46 @@
 use bytes::{Bytes, BytesMut};
-use log::{debug, info};
+use log::{debug, info, warn};
+use std::os::unix::prelude::AsRawFd;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime};
 use std::{
-    borrow::Cow,
-    io,
+    fmt, io,
     net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    str::FromStr,
 };
 
-use crate::linux::{get_original_address_v4, get_original_address_v6};
+use crate::accounting::{ConnectionUsage, LIVE_INTERVAL};
+use crate::addr::{canonical_socket_addr, same_socket_addr};
+use crate::conn_id::ConnId;
+use crate::echo;
+use crate::hooks::{Event, EventKind, Hooks};
+use crate::linux::{get_tcp_rtt, set_dscp, set_mark};
+use crate::listener::Role;
+use crate::local::LocalPolicy;
+use crate::loop_guard::{EgressGuard, EgressRegistry};
+use crate::metrics::METRICS;
+use crate::nat64::translate_destination;
+use crate::outbound::OutboundKind;
+use crate::pinning::{self, DnsPinning};
+use crate::rules::{NoSniPolicy, Rule};
+use crate::shaper::{RateLimit, Shaped};
 use crate::tls;
-use crate::{config::Config, stream::pipe};
+use crate::trace::{Recording, Stages, Transcript};
+use crate::{
+    config::Config,
+    stream::{pipe, BiPipe, InboundStream, PipeStream},
+};
 
-use crate::protocols::handshake;
+use crate::protocols::detect::Protocol;
+use crate::protocols::peer::{
+    recv_metadata, PeerHello, PeerMetadata, FEATURE_PINNED_ADDR, METHOD_PEER,
+};
+use crate::protocols::{handshake, handshake_pipelined, handshake_traced};
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::TcpStream,
-    time::timeout,
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{lookup_host, TcpSocket, TcpStream},
+    sync::OwnedSemaphorePermit,
+    time::{interval_at, timeout},
 };
 
 #[derive(Clone, Debug)]
@@ -76,106 +100,383 @@ impl From<(Address, u16)> for Destination {
     }
 }
 
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Address::Ip(IpAddr::V6(ip)) => write!(f, "[{}]", ip),
+            Address::Ip(ip) => write!(f, "{}", ip),
+            Address::Domain(domain) => write!(f, "{}", domain),
+        }
+    }
+}
+
+impl fmt::Display for Destination {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.host, self.port)
+    }
+}
+
+impl FromStr for Destination {
+    type Err = &'static str;
+
+    // 支持 host:port、ipv4:port 和 [ipv6]:port
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(addr) = s.parse::<SocketAddr>() {
+            return Ok(addr.into());
+        }
+        let (host, port) = s.rsplit_once(':').ok_or("missing port")?;
+        let port = port.parse().map_err(|_| "invalid port")?;
+        if host.is_empty() || host.len() > 255 || host.contains(':') {
+            return Err("invalid host");
+        }
+        Ok((host, port).into())
+    }
+}
+
 pub struct Client {
+    id: ConnId,
     config: Arc<Config>,
-    left: TcpStream,
+    left: InboundStream,
     src: SocketAddr,
     pub dest: Destination,
     from_port: u16,
     pending_data: Option<Bytes>,
+    // TLS 嗅探得到的 server name
+    sni: Option<Box<str>>,
+    rule: Option<Arc<Rule>>,
+    egress: Option<EgressGuard>,
+    // DNS pinning: 目的地为域名时实际应该连接的 IP
+    pinned: Option<IpAddr>,
+    // 转发连接的原始目的地查询失败，等待从 SNI 得到目的地
+    unresolved: bool,
+    // --no-sni 指定的出口，只使用这一个
+    outbound: Option<usize>,
+    // 出口的连接数配额，随连接一起释放
+    outbound_permit: Option<OwnedSemaphorePermit>,
+    // 出口的带宽上限
+    shaper: Option<Arc<RateLimit>>,
+    // 目的地由隧道请求明确给出 (WebSocket)，不嗅探 SNI 覆盖它
+    fixed_dest: bool,
+    // 有规则打开 trace 时记录的入站握手，匹配到规则后输出
+    handshake: Option<Transcript>,
+    // 匹配到打开 trace 的规则时记录的 DNS 解析、TCP 连接和上游握手的耗时
+    stages: Stages,
+}
+
+// bind_egress 将出站 socket bind 到临时端口并登记本地地址
+fn bind_egress(
+    socket: &TcpSocket,
+    remote: SocketAddr,
+    registry: &Arc<EgressRegistry>,
+) -> io::Result<EgressGuard> {
+    let unspecified: IpAddr = if remote.is_ipv4() {
+        Ipv4Addr::UNSPECIFIED.into()
+    } else {
+        Ipv6Addr::UNSPECIFIED.into()
+    };
+    socket.bind(SocketAddr::new(unspecified, 0))?;
+    Ok(registry.register(socket.local_addr()?))
 }
 
-fn normalize_socket_addr(socket: &SocketAddr) -> Cow<SocketAddr> {
-    match socket {
-        SocketAddr::V4(sock) => {
-            let addr = sock.ip().to_ipv6_mapped();
-            let sock = SocketAddr::new(addr.into(), sock.port());
-            Cow::Owned(sock)
+// apply_socket_options 将规则中的 mark/dscp 设置到 socket 上，失败时只记录日志
+fn apply_socket_options<F: AsRawFd>(socket: &F, is_ipv6: bool, rule: &Rule) {
+    if let Some(mark) = rule.mark {
+        if let Err(err) = set_mark(socket, mark) {
+            warn!("failed to set SO_MARK {}: {}", mark, err);
+        }
+    }
+    if let Some(dscp) = rule.dscp {
+        if let Err(err) = set_dscp(socket, is_ipv6, dscp) {
+            warn!("failed to set DSCP {}: {}", dscp, err);
         }
-        _ => Cow::Borrowed(socket),
     }
 }
 
+// is_socks_greeting 查看客户端的第一个字节是否是 SOCKS5 的版本号，不消耗数据
+// 客户端一直不发送数据 (例如等待服务端先发 banner 的协议) 时不是 SOCKS5
+async fn is_socks_greeting(stream: &TcpStream) -> bool {
+    let mut ver = [0u8; 1];
+    matches!(
+        timeout(Duration::from_millis(500), stream.peek(&mut ver)).await,
+        Ok(Ok(1)) if ver[0] == 0x05
+    )
+}
+
+// connect_reply 成功的 CONNECT 回复，BND 为客户端连接的本地地址，v6 监听时 ATYP 为 0x04
+// +----+-----+-------+------+----------+----------+
+// |VER | REP |  RSV  | ATYP | BND.ADDR | BND.PORT |
+// +----+-----+-------+------+----------+----------+
+fn connect_reply(bound: SocketAddr) -> Vec<u8> {
+    let bound = canonical_socket_addr(bound);
+    let mut reply = vec![0x05, 0x00, 0x00];
+    match bound.ip() {
+        IpAddr::V4(ip) => {
+            reply.push(0x01);
+            reply.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            reply.push(0x04);
+            reply.extend_from_slice(&ip.octets());
+        }
+    }
+    reply.extend_from_slice(&bound.port().to_be_bytes());
+    reply
+}
+
 fn error_invalid_input<T>(msg: &'static str) -> io::Result<T> {
     Err(io::Error::new(io::ErrorKind::InvalidInput, msg))
 }
 
+// accept_socks 完成入站的 SOCKS5 握手，返回目的地和下游指定的固定地址
+// 第一个字节不是 SOCKS5 版本号时返回 None，由调用方按探测处理
+async fn accept_socks<S>(
+    stream: &mut S,
+    config: &Config,
+    id: ConnId,
+    left_src: SocketAddr,
+    local: SocketAddr,
+) -> io::Result<Option<(Destination, Option<IpAddr>)>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut pinned = None;
+    // Client 给出支持的握手协议
+    let ver = stream.read_u8().await?;
+    if ver != 0x05 {
+        return Ok(None);
+    }
+    let n_methods = stream.read_u8().await?;
+    let mut buf = vec![0u8; n_methods as usize];
+    stream.read_exact(&mut buf).await?;
+    // 元数据由客户端提供，只接受 --trusted-peer 中的下游，其他客户端按普通 SOCKS5 处理
+    let trusted = config.is_trusted_peer(left_src.ip());
+    if buf.contains(&METHOD_PEER) && !trusted {
+        debug!("{} {} offered peer method but is not trusted", id, left_src);
+    }
+    if buf.contains(&METHOD_PEER) && trusted {
+        // 下游同样是 socket_proxy，先接收它发送的连接元数据
+        stream.write_all(&[0x05, METHOD_PEER]).await?;
+        stream.flush().await?;
+        let meta = recv_metadata(stream).await?;
+        debug!("{} {} peer metadata {:?}", id, left_src, meta);
+        pinned = meta.pinned;
+    } else if buf.contains(&0) {
+        stream.write_all(&[0x05, 0x00]).await?;
+        stream.flush().await?;
+    } else {
+        return error_invalid_input("Socksv5, Only no auth supported");
+    }
+    buf.resize(4, 0);
+    stream.read_exact(&mut buf).await?;
+    if buf[0..2] != [0x05, 0x01] {
+        return error_invalid_input("Socksv5, CONNECT is required");
+    }
+    // Client 给出真实目的地
+    let addr: Address = match buf[3] {
+        0x01 => {
+            // ipv4
+            let mut buf = [0u8; 4];
+            stream.read_exact(&mut buf).await?;
+            buf.into()
+        }
+        0x03 => {
+            // domain
+            let domain_len = stream.read_u8().await? as usize;
+            buf.resize(domain_len, 0);
+            let _raw_ipv4 = stream.read_exact(&mut buf).await?;
+            let domain = String::from_utf8(buf).map_err(|_| {
+                io::Error::new(io::ErrorKind::InvalidInput, "Socksv5, invalid domain name")
+            })?;
+            // 客户端把 IP 字面量 (包括 [v6]) 当作域名发送时按 IP 处理，规则和日志才一致
+            let literal = domain.trim_start_matches('[').trim_end_matches(']');
+            match literal.parse::<IpAddr>() {
+                Ok(ip) => Address::Ip(ip),
+                Err(_) => domain.into(),
+            }
+        }
+        0x04 => {
+            // ipv6
+            let mut buf = [0u8; 16];
+            stream.read_exact(&mut buf).await?;
+            buf.into()
+        }
+        _ => return error_invalid_input("Socksv5, unknown adress type"),
+    };
+    let port = stream.read_u16().await?;
+    stream.write_all(&connect_reply(local)).await?;
+    stream.flush().await?;
+    Ok(Some(((addr, port).into(), pinned)))
+}
+
+// Tracked drop 时 (包括连接被取消或在关闭时被中止) 把最后一次记入之后的流量记入 accounting，
+// 并投递 open 被放行的连接的 close 事件
+struct Tracked<'a, L, R> {
+    pipe: &'a mut BiPipe<L, R>,
+    usage: Option<ConnectionUsage>,
+    close: Option<(&'a Hooks, Event)>,
+    start: Instant,
+}
+
+impl<L, R> Drop for Tracked<'_, L, R> {
+    fn drop(&mut self) {
+        let (up, down) = self.pipe.totals();
+        if let Some(ref mut usage) = self.usage {
+            usage.update(up, down);
+        }
+        if let Some((hooks, mut event)) = self.close.take() {
+            event.kind = EventKind::Close;
+            event.time = SystemTime::now();
+            event.protocol = Some(self.pipe.protocol().unwrap_or(Protocol::Unknown).name());
+            event.up = up;
+            event.down = down;
+            event.duration = Some(self.start.elapsed());
+            hooks.close(event);
+        }
+    }
+}
+
 impl Client {
+    // new 用于自带目的地的隧道连接 (例如 WebSocket)
+    pub fn new(
+        left: InboundStream,
+        dest: Destination,
+        config: Arc<Config>,
+        id: ConnId,
+    ) -> io::Result<Self> {
+        let src = canonical_socket_addr(left.tcp().peer_addr()?);
+        config.egress.check(&src, &config.local_addrs)?;
+        let from_port = left.tcp().local_addr()?.port();
+        Ok(Client {
+            id,
+            dest,
+            config,
+            from_port,
+            left,
+            src,
+            pending_data: None,
+            sni: None,
+            rule: None,
+            egress: None,
+            pinned: None,
+            unresolved: false,
+            outbound: None,
+            outbound_permit: None,
+            shaper: None,
+            fixed_dest: true,
+            handshake: None,
+            stages: Stages::default(),
+        })
+    }
+
     // from_socket 处理iptables转发的请求和client主动建联请求
-    pub async fn from_socket(mut peer_left: TcpStream, config: Arc<Config>) -> io::Result<Self> {
-        let left_src = peer_left.peer_addr()?;
-        let src_port = peer_left.local_addr()?.port();
+    // TLS 入站只会是 SOCKSv5 客户端
+    pub async fn from_socket(
+        mut peer_left: InboundStream,
+        config: Arc<Config>,
+        id: ConnId,
+        role: Role,
+    ) -> io::Result<Self> {
+        let left_src = canonical_socket_addr(peer_left.tcp().peer_addr()?);
+        config.egress.check(&left_src, &config.local_addrs)?;
+        let local = peer_left.tcp().local_addr()?;
+        let src_port = local.port();
         // 获取原始目的地
-        let dest = get_original_address_v4(&peer_left)
-            .map(SocketAddr::V4)
-            .or_else(|_| get_original_address_v6(&peer_left).map(SocketAddr::V6))
-            .or_else(|_| peer_left.local_addr())?;
+        let fallback = config.original_dst_fallback;
+        let lookup = match role {
+            // 显式客户端的监听端口不查询原始目的地
+            Role::Socks => Ok(local),
+            _ => fallback.lookup(peer_left.tcp()),
+        };
+        let (dest, unresolved) = match lookup {
+            Ok(dest) => (dest, false),
+            // TLS 和 WebSocket 入站只会是 SOCKSv5 客户端
+            Err(_) if peer_left.is_tunneled() => (local, false),
+            Err(_) if role == Role::Auto && is_socks_greeting(peer_left.tcp()).await => {
+                (local, false)
+            }
+            Err(err) if fallback.sni => {
+                warn!(
+                    "{} {} original destination lookup failed: {}, falling back to sni",
+                    id, left_src, err
+                );
+                (SocketAddr::new(local.ip(), 443), true)
+            }
+            Err(err) => {
+                config.probe_response.respond(&mut peer_left).await;
+                return Err(io::Error::new(
+                    io::ErrorKind::ConnectionRefused,
+                    format!(
+                        "original destination of {} unavailable ({}), rejected",
+                        left_src, err
+                    ),
+                ));
+            }
+        };
         #[cfg(not(target_os = "linux"))]
-        let dest = peer_left.local_addr()?;
-        let is_nated =
-            normalize_socket_addr(&dest) != normalize_socket_addr(&peer_left.local_addr()?);
+        let dest = local;
+        let is_nated = unresolved || (!peer_left.is_tunneled() && !same_socket_addr(dest, local));
+        if role == Role::Redirect && !is_nated {
+            config.probe_response.respond(&mut peer_left).await;
+            return Err(io::Error::new(
+                io::ErrorKind::ConnectionRefused,
+                format!(
+                    "{} connected to a redirect listener directly, rejected",
+                    left_src
+                ),
+            ));
+        }
 
-        debug!("local {} dest {}", peer_left.local_addr()?, dest);
+        debug!("{} {} local {} dest {}", id, left_src, local, dest);
 
+        let mut pinned = None;
+        let mut transcript = None;
         let dest = if cfg!(target_os = "linux") && is_nated {
-            dest.into()
+            canonical_socket_addr(dest).into()
         } else {
-            // 根据协议获取信息
-            // Client 给出支持的握手协议
-            let ver = peer_left.read_u8().await?;
-            if ver != 0x05 {
-                return error_invalid_input("Neither a NATed or SOCKSv5 connection");
-            }
-            let n_methods = peer_left.read_u8().await?;
-            let mut buf = vec![0u8; n_methods as usize];
-            peer_left.read_exact(&mut buf).await?;
-            if buf.iter().find(|&&m| m == 0).is_none() {
-                return error_invalid_input("Socksv5, Only no auth supported");
-            }
-            peer_left.write_all(&[0x05, 0x00]).await?;
-            buf.resize(4, 0);
-            peer_left.read_exact(&mut buf).await?;
-            if buf[0..2] != [0x05, 0x01] {
-                return error_invalid_input("Socksv5, CONNECT is required");
-            }
-            // Client 给出真实目的地
-            let addr: Address = match buf[3] {
-                0x01 => {
-                    // ipv4
-                    let mut buf = [0u8; 4];
-                    peer_left.read_exact(&mut buf).await?;
-                    buf.into()
+            // 握手停在中途的客户端和探测一样处理，不能一直占用连接数
+            let mut recording = Recording::new(&mut peer_left, config.rules.has_trace());
+            let handshake = accept_socks(&mut recording, &config, id, left_src, local);
+            let handshake = timeout(config.handshake_timeout, handshake).await;
+            transcript = recording.into_transcript();
+            match handshake {
+                Ok(Ok(Some((dest, pin)))) => {
+                    pinned = pin;
+                    dest
                 }
-                0x03 => {
-                    // domain
-                    let domain_len = peer_left.read_u8().await? as usize;
-                    buf.resize(domain_len, 0);
-                    let _raw_ipv4 = peer_left.read_exact(&mut buf).await?;
-                    let domain = String::from_utf8(buf).map_err(|_| {
-                        io::Error::new(io::ErrorKind::InvalidInput, "Socksv5, invalid domain name")
-                    })?;
-                    domain.into()
+                Ok(Ok(None)) => {
+                    config.probe_response.respond(&mut peer_left).await;
+                    return error_invalid_input("Neither a NATed or SOCKSv5 connection");
                 }
-                0x04 => {
-                    // ipv6
-                    let mut buf = [0u8; 16];
-                    peer_left.read_exact(&mut buf).await?;
-                    buf.into()
+                Ok(Err(err)) => return Err(err),
+                Err(_) => {
+                    config.probe_response.respond(&mut peer_left).await;
+                    return Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        format!("{} inbound socks5 handshake timed out", left_src),
+                    ));
                 }
-                _ => return error_invalid_input("Socksv5, unknown adress type"),
-            };
-            let port = peer_left.read_u16().await?;
-            peer_left.write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0]).await?;
-            (addr, port).into()
+            }
         };
 
         Ok(Client {
+            id,
             dest,
             config,
             from_port: src_port,
             left: peer_left,
             src: left_src,
             pending_data: None,
+            sni: None,
+            rule: None,
+            egress: None,
+            pinned,
+            unresolved,
+            outbound: None,
+            outbound_permit: None,
+            shaper: None,
+            fixed_dest: false,
+            handshake: transcript,
+            stages: Stages::default(),
         })
     }
 }
@@ -185,13 +486,37 @@ impl Client {
     // REDIRECT 情况下不会有 socks 的握手流程，起手流量是 TLS client hello，则需要我们从 TLS 嗅探出 domain name，用于做 DNS 远程解析
     pub async fn retrieve_dest(self) -> io::Result<Client> {
         let Client {
+            id,
             mut left,
             src,
             mut dest,
             from_port,
             config,
             pending_data: _pending_data,
+            mut sni,
+            rule,
+            egress,
+            mut pinned,
+            mut unresolved,
+            outbound,
+            outbound_permit,
+            shaper,
+            fixed_dest,
+            handshake,
+            stages,
         } = self;
+        // 达到 --max-sniffing 时等待，等待时间同样计入建立连接的时间预算
+        let permit = match config.sniff_limit {
+            Some(ref limit) => Some(match limit.try_acquire() {
+                Ok(permit) => permit,
+                Err(_) => {
+                    let _waiting = METRICS.sniff_waiters.track();
+                    limit.acquire().await.map_err(io::Error::other)?
+                }
+            }),
+            None => None,
+        };
+        let sniffing = METRICS.sniffing.track();
         let wait = Duration::from_millis(500);
         let mut buf = BytesMut::with_capacity(2048);
         let mut pending_data = None;
@@ -201,10 +526,29 @@ impl Client {
             // 这样保证往 socket 回写时不会写入初始化时的 0
             buf.truncate(len);
             match tls::parse_client_hello(&buf) {
-                Err(err) => info!("failed to parse hello:{}", err),
+                Err(err) => info!("{} failed to parse hello:{}", id, err),
                 Ok(hello) => {
                     if let Some(server_name) = hello.server_name {
+                        match dest.host {
+                            // 原始目的地未知时 dest 只是监听地址，不是客户端连接的 IP
+                            Address::Ip(_) if unresolved => (),
+                            Address::Ip(ip) => match config.dns_pinning {
+                                Some(DnsPinning::Hint) => pinned = Some(ip),
+                                Some(DnsPinning::Verify) => {
+                                    tokio::spawn(pinning::verify(
+                                        id,
+                                        src,
+                                        server_name.clone(),
+                                        SocketAddr::new(ip, dest.port),
+                                    ));
+                                }
+                                None => (),
+                            },
+                            Address::Domain(_) => (),
+                        }
                         dest = (server_name.as_ref(), dest.port).into();
+                        sni = Some(server_name);
+                        unresolved = false;
                     }
                 }
             }
@@ -214,18 +558,302 @@ impl Client {
             // 由于没有证书，无法做 https 代理，所以建立 tcp socket 后将 client 读取的 tls hello 透明发送给 server
             pending_data = Some(buf.freeze());
         }
+        drop(sniffing);
+        drop(permit);
         Ok(Client {
+            id,
             from_port,
             dest,
             left,
             src,
             pending_data,
             config,
+            sni,
+            rule,
+            egress,
+            pinned,
+            unresolved,
+            outbound,
+            outbound_permit,
+            shaper,
+            fixed_dest,
+            handshake,
+            stages,
         })
     }
 
-    // connect_remote_server 连接 socks5 server
-    pub async fn connect_remote_server(&self) -> io::Result<TcpStream> {
+    pub fn id(&self) -> ConnId {
+        self.id
+    }
+
+    pub fn src(&self) -> SocketAddr {
+        self.src
+    }
+
+    // is_unresolved 原始目的地未知，且还没有从 SNI 得到目的地
+    pub fn is_unresolved(&self) -> bool {
+        self.unresolved
+    }
+
+    // is_echo 目的地是内置的回显地址，并且客户端在 --echo-source 中
+    pub fn is_echo(&self) -> bool {
+        echo::is_echo(&self.dest)
+            && self
+                .config
+                .echo_sources
+                .iter()
+                .any(|cidr| cidr.contains(self.src.ip()))
+    }
+
+    pub fn has_fixed_dest(&self) -> bool {
+        self.fixed_dest
+    }
+
+    // pinned 连接需要使用的 IP，来自 --dns-pinning hint 或可信下游的元数据
+    pub fn pinned(&self) -> Option<IpAddr> {
+        self.pinned
+    }
+
+    pub fn sni(&self) -> Option<&str> {
+        self.sni.as_deref()
+    }
+
+    // apply_no_sni 嗅探后没有得到 SNI 时调用，按匹配规则的 no_sni 或 --no-sni 处理，需要在 route 之后调用
+    pub fn apply_no_sni(&mut self) -> io::Result<()> {
+        METRICS.no_sni.inc();
+        let policy = self
+            .rule
+            .as_ref()
+            .and_then(|rule| rule.no_sni.as_ref())
+            .unwrap_or(&self.config.no_sni);
+        match policy {
+            NoSniPolicy::Ip => Ok(()),
+            NoSniPolicy::Reject => {
+                METRICS.no_sni_rejected.inc();
+                Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    format!("no sni for {}, rejected", self.dest),
+                ))
+            }
+            NoSniPolicy::Outbound(name) => {
+                let index = self
+                    .config
+                    .outbounds
+                    .iter()
+                    .position(|outbound| &outbound.name == name)
+                    .ok_or_else(|| {
+                        io::Error::new(
+                            io::ErrorKind::NotFound,
+                            format!("unknown outbound {}", name),
+                        )
+                    })?;
+                debug!(
+                    "{} no sni for {}, using outbound {}",
+                    self.id, self.dest, name
+                );
+                self.outbound = Some(index);
+                Ok(())
+            }
+        }
+    }
+
+    // route 根据最终的目的地匹配规则，并将规则中的 socket 选项设置到客户端连接上
+    pub fn route(&mut self) -> io::Result<()> {
+        self.rule = self.config.rules.find(&self.dest);
+        if let Some(ref rule) = self.rule {
+            debug!("{} {} matched rule {:?}", self.id, self.src, rule);
+            let left = self.left.tcp();
+            apply_socket_options(left, left.local_addr()?.is_ipv6(), rule);
+        }
+        Ok(())
+    }
+
+    // is_traced 匹配到的规则是否打开了 trace
+    pub fn is_traced(&self) -> bool {
+        self.rule.as_ref().is_some_and(|rule| rule.trace)
+    }
+
+    // trace_handshake 输出记下的入站握手，只在匹配到打开 trace 的规则后调用
+    pub fn trace_handshake(&self) {
+        if let Some(ref transcript) = self.handshake {
+            transcript.dump(self.id, "client");
+        }
+    }
+
+    // connect_stages 连接出口的各阶段耗时，只在匹配到打开 trace 的规则时记录
+    pub fn connect_stages(&self) -> &Stages {
+        &self.stages
+    }
+
+    // establish_timeout 建立连接的时间预算，匹配到的规则优先
+    pub fn establish_timeout(&self) -> Option<Duration> {
+        self.rule
+            .as_ref()
+            .and_then(|rule| rule.establish_timeout)
+            .or(self.config.establish_timeout)
+    }
+
+    // translate_address 转换目的地的地址族，需要在匹配规则之前调用
+    pub fn translate_address(&mut self) {
+        let before = self.dest.to_string();
+        if translate_destination(&mut self.dest, self.config.nat64.as_ref()) {
+            debug!("{} translate {} to {}", self.id, before, self.dest);
+        }
+    }
+
+    // local_policy 目的地是本机时返回配置的处理方式
+    pub fn local_policy(&self) -> Option<LocalPolicy> {
+        self.config
+            .local_addrs
+            .is_local(&self.dest)
+            .then_some(self.config.local_policy)
+    }
+
+    // outbound_socket 创建连接 remote 的 socket，设置 egress mark 和规则中的 socket 选项
+    fn outbound_socket(&self, remote: SocketAddr) -> io::Result<TcpSocket> {
+        let socket = if remote.is_ipv4() {
+            TcpSocket::new_v4()?
+        } else {
+            TcpSocket::new_v6()?
+        };
+        // 规则中的 mark 优先于 egress mark
+        if let Some(mark) = self
+            .config
+            .egress_mark
+            .filter(|_| self.rule.as_ref().and_then(|rule| rule.mark).is_none())
+        {
+            if let Err(err) = set_mark(&socket, mark) {
+                warn!("failed to set egress SO_MARK {}: {}", mark, err);
+            }
+        }
+        if let Some(ref rule) = self.rule {
+            apply_socket_options(&socket, remote.is_ipv6(), rule);
+        }
+        Ok(socket)
+    }
+
+    // connect_direct 不经过上游直接连接目的地，用于本机目的地和 direct 出口
+    // 和连接上游一样设置 mark 并在 connect 之前登记出站端口，下游给出的固定地址优先于解析结果
+    pub async fn connect_direct(&mut self) -> io::Result<TcpStream> {
+        let traced = self.is_traced();
+        let addrs: Vec<SocketAddr> = match (&self.dest.host, self.pinned) {
+            (Address::Ip(ip), _) => vec![SocketAddr::new(*ip, self.dest.port)],
+            (Address::Domain(domain), Some(ip)) => {
+                debug!("{} connect pinned address {} for {}", self.id, ip, domain);
+                vec![SocketAddr::new(ip, self.dest.port)]
+            }
+            (Address::Domain(domain), None) => {
+                let begin = Instant::now();
+                let resolved = lookup_host((domain.as_ref(), self.dest.port)).await;
+                if traced {
+                    self.stages.push("dns resolve", begin.elapsed());
+                }
+                resolved?.collect()
+            }
+        };
+        let mut last_err = None;
+        for addr in addrs {
+            let socket = self.outbound_socket(addr)?;
+            let mut egress = bind_egress(&socket, addr, &self.config.egress)?;
+            let begin = Instant::now();
+            let connected = socket.connect(addr).await;
+            if traced {
+                self.stages.push("tcp connect", begin.elapsed());
+            }
+            let mut stream = match connected {
+                Ok(stream) => stream,
+                Err(err) => {
+                    last_err = Some(err);
+                    continue;
+                }
+            };
+            egress.update(stream.local_addr()?);
+            if let Some(ref data) = self.pending_data {
+                stream.write_all(data).await?;
+            }
+            self.egress = Some(egress);
+            return Ok(stream);
+        }
+        let err = last_err.unwrap_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, "no addresses to connect to")
+        });
+        Err(io::Error::new(
+            err.kind(),
+            format!("connect {} directly failed: {}", self.dest, err),
+        ))
+    }
+
+    // connect_remote_server 按顺序尝试配置的出口，直到有一个连接成功
+    // 目的地最近一次成功的出口排在最前面，失败时退回配置顺序
+    pub async fn connect_remote_server(&mut self) -> io::Result<TcpStream> {
+        let _connecting = METRICS.upstream_connects.track();
+        let config = self.config.clone();
+        let key = self.dest.to_string();
+        let order = match self.outbound {
+            Some(index) => vec![index],
+            None => config.happy_path.order(&key, config.outbounds.len()),
+        };
+        if order.first().is_some_and(|&index| index != 0) {
+            METRICS.happy_path_hits.inc();
+        }
+        let mut last_err = None;
+        for (attempt, index) in order.into_iter().enumerate() {
+            let outbound = &config.outbounds[index];
+            if attempt > 0 {
+                METRICS.outbound_failovers.inc();
+            }
+            // 达到连接数上限不算出口失败，不影响健康状态和 happy path
+            let limiter = config.outbound_limits.get(outbound);
+            if let Some(ref limiter) = limiter {
+                match limiter.acquire(outbound.limits.when_full).await {
+                    Ok(permit) => self.outbound_permit = permit,
+                    Err(err) => {
+                        METRICS.outbound_full.inc();
+                        debug!("{} {} outbound {} skipped: {}", self.id, key, outbound, err);
+                        last_err = Some(err);
+                        continue;
+                    }
+                }
+            }
+            let result = match outbound.kind {
+                OutboundKind::Socks5(server) => self.connect_socks5(server).await,
+                OutboundKind::Direct => self.connect_direct().await,
+            };
+            match result {
+                Ok(_) => config.outbound_health.record_success(&outbound.name),
+                Err(ref err) => config.outbound_health.record_failure(&outbound.name, err),
+            }
+            if result.is_ok() {
+                self.shaper = limiter.and_then(|limiter| limiter.bandwidth());
+            } else {
+                self.outbound_permit = None;
+            }
+            match result {
+                Ok(stream) if self.outbound.is_some() => return Ok(stream),
+                Ok(stream) => {
+                    config.happy_path.record(&key, index);
+                    if attempt > 0 {
+                        info!("{} {} connected via outbound {}", self.id, key, outbound);
+                    }
+                    return Ok(stream);
+                }
+                Err(err) => {
+                    config.happy_path.forget(&key, index);
+                    if config.outbounds.len() > 1 {
+                        warn!("{} {} outbound {} failed: {}", self.id, key, outbound, err);
+                    }
+                    last_err = Some(err);
+                }
+            }
+        }
+        Err(last_err
+            .unwrap_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no outbound configured")))
+    }
+
+    // connect_socks5 连接 socks5 server 并完成握手
+    async fn connect_socks5(&mut self, socks_server: SocketAddr) -> io::Result<TcpStream> {
+        let traced = self.is_traced();
+        let socket = self.outbound_socket(socks_server)?;
         let Client {
             ref dest,
             from_port: ref _from_port,
@@ -233,8 +861,23 @@ impl Client {
             config,
             ..
         } = self;
-        let socks_server = config.socket5_server;
-        let mut stream = match TcpStream::connect(socks_server).await {
+        // 缓冲区需要在 connect 之前设置，才能影响 TCP 窗口扩大因子
+        let (send_buffer, recv_buffer) = config.tuning.buffer_sizes(socks_server);
+        if let Some(size) = send_buffer {
+            socket.set_send_buffer_size(size)?;
+        }
+        if let Some(size) = recv_buffer {
+            socket.set_recv_buffer_size(size)?;
+        }
+        // 先 bind 得到本地端口并登记，环路连接可能在 connect 返回之前就被 accept
+        // guard 在成功后才交给 Client，握手失败或 future 被取消时随 stream 一起释放
+        let mut egress = bind_egress(&socket, socks_server, &config.egress)?;
+        let begin = Instant::now();
+        let connected = socket.connect(socks_server).await;
+        if traced {
+            self.stages.push("tcp connect", begin.elapsed());
+        }
+        let mut stream = match connected {
             Ok(stream) => stream,
             Err(err) => {
                 return Err(io::Error::new(
@@ -243,14 +886,149 @@ impl Client {
                 ))
             }
         };
+        egress.update(stream.local_addr()?);
+
+        config.capabilities.detect(socks_server);
+        let caps = config.capabilities.get(socks_server);
+        // 已知上游不是 socket_proxy 时不再提供私有方法
+        let offer_peer = config.upstream_peer && caps.and_then(|caps| caps.peer) != Some(false);
+        let metadata = offer_peer.then(|| PeerMetadata {
+            destination: Some(dest.to_string()),
+            client: Some(self.src),
+            sni: self.sni.as_deref().map(String::from),
+            request_id: Some(self.id.get()),
+            pinned: self.pinned,
+            hello: Some(PeerHello::LOCAL),
+        });
+        // 上游不是 socket_proxy 或协商结果不支持时由本地完成 pinning，直接请求下游给出的 IP
+        let peer_pins = metadata.is_some()
+            && caps
+                .and_then(|caps| caps.peer_hello)
+                .is_none_or(|hello| hello.supports(FEATURE_PINNED_ADDR));
+        let pinned_dest = match (self.pinned, &dest.host) {
+            (Some(ip), Address::Domain(domain)) if !peer_pins => {
+                debug!("{} connect pinned address {} for {}", self.id, ip, domain);
+                Some(Destination::from(SocketAddr::new(ip, dest.port)))
+            }
+            _ => None,
+        };
 
         // we should handshake with socks5 server as the socks client
-        handshake(&mut stream, dest, self.pending_data.clone()).await?;
+        let request_dest = pinned_dest.as_ref().unwrap_or(dest);
+        let pending_data = self.pending_data.clone();
+        let begin = Instant::now();
+        // trace 时不使用流水线握手，每一步的收发都单独输出
+        let result = if !traced && metadata.is_none() && caps.is_some_and(|caps| caps.pipelining) {
+            handshake_pipelined(&mut stream, request_dest, pending_data)
+                .await
+                .map(|_| None)
+        } else if traced {
+            handshake_traced(
+                &mut stream,
+                request_dest,
+                pending_data,
+                metadata.as_ref(),
+                self.id,
+            )
+            .await
+            .map(|(_, hello)| Some(hello))
+        } else {
+            handshake(&mut stream, request_dest, pending_data, metadata.as_ref())
+                .await
+                .map(|(_, hello)| Some(hello))
+        };
+        if traced {
+            self.stages.push("socks handshake", begin.elapsed());
+        }
+        if let (Some(hello), Some(_)) = (result?, &metadata) {
+            config.capabilities.record_peer(socks_server, hello);
+        }
+        // 握手经历了多个往返，此时内核的平滑 RTT 已经比较准确
+        match get_tcp_rtt(&stream) {
+            Ok(rtt) => {
+                config.tuning.record_rtt(socks_server, rtt);
+                debug!(
+                    "{} upstream {} rtt {:?} sndbuf {:?} rcvbuf {:?}",
+                    self.id, socks_server, rtt, send_buffer, recv_buffer
+                );
+            }
+            Err(err) => debug!("{} failed to get upstream rtt: {}", self.id, err),
+        }
+        self.egress = Some(egress);
         Ok(stream)
     }
 
-    pub async fn do_pipe(self, remote: TcpStream) -> io::Result<()> {
-        match pipe(self.left, remote).await {
+    pub async fn do_pipe<R: PipeStream>(mut self, remote: R) -> io::Result<()> {
+        match self.shaper.take() {
+            Some(limit) => self.pipe_to(Shaped::new(remote, limit)).await,
+            None => self.pipe_to(remote).await,
+        }
+    }
+
+    async fn pipe_to<R: PipeStream>(self, remote: R) -> io::Result<()> {
+        let mut pipe = pipe(self.left, remote).with_id(self.id);
+        if let Some(ref data) = self.pending_data {
+            pipe = pipe.with_initial_data(data);
+        }
+        if self.config.hooks.is_none() && self.config.accounting.is_none() {
+            return Self::pipe_result(pipe.await);
+        }
+        let event = Event {
+            kind: EventKind::Open,
+            time: SystemTime::now(),
+            id: self.id,
+            src: self.src,
+            dest: self.dest.to_string(),
+            sni: self.sni.as_deref().map(String::from),
+            protocol: None,
+            up: 0,
+            down: 0,
+            duration: None,
+        };
+        // open 被丢弃的连接也不投递 close
+        let close = match self.config.hooks {
+            Some(ref hooks) if hooks.open(event.clone()) => Some((hooks, event)),
+            _ => None,
+        };
+        let tracked = Tracked {
+            pipe: &mut pipe,
+            usage: self
+                .config
+                .accounting
+                .as_ref()
+                .map(|accounting| accounting.connection(self.src.ip())),
+            close,
+            start: Instant::now(),
+        };
+        Self::pipe_result(Self::pipe_tracked(tracked).await)
+    }
+
+    // pipe_tracked 连接期间定期把流量增量记入 accounting，长连接不会等到结束才计入
+    async fn pipe_tracked<L, R>(mut tracked: Tracked<'_, L, R>) -> io::Result<()>
+    where
+        L: PipeStream,
+        R: PipeStream,
+    {
+        if tracked.usage.is_none() {
+            return (&mut *tracked.pipe).await;
+        }
+        let start = tokio::time::Instant::now() + LIVE_INTERVAL;
+        let mut tick = interval_at(start, LIVE_INTERVAL);
+        loop {
+            tokio::select! {
+                result = &mut *tracked.pipe => return result,
+                _ = tick.tick() => {
+                    let (up, down) = tracked.pipe.totals();
+                    if let Some(ref mut usage) = tracked.usage {
+                        usage.update(up, down);
+                    }
+                }
+            }
+        }
+    }
+
+    fn pipe_result(result: io::Result<()>) -> io::Result<()> {
+        match result {
             Ok(()) => Ok(()),
             Err(err) => Err(io::Error::new(
                 io::ErrorKind::BrokenPipe,
@@ -1,7 +1,299 @@
-use std::net::{IpAddr, SocketAddr};
+use std::{
+    collections::BTreeMap,
+    fmt, fs, io, mem,
+    net::{IpAddr, SocketAddr},
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::Semaphore;
+
+use crate::accounting::Accounting;
+use crate::decoy::ProbeResponse;
+use crate::deny::DenyList;
+use crate::hooks::Hooks;
+use crate::listener::ListenerConfig;
+use crate::local::{LocalAddrs, LocalPolicy};
+use crate::loop_guard::EgressRegistry;
+use crate::nat64::Nat64;
+use crate::original_dst::OriginalDstFallback;
+use crate::outbound::{
+    HappyPathCache, Outbound, OutboundConfig, OutboundHealthMap, OutboundLimiters,
+};
+use crate::pinning::DnsPinning;
+use crate::protocols::capabilities::CapabilityCache;
+use crate::rules::{Cidr, NoSniPolicy, RuleConfig, Rules};
+use crate::tuning::BufferTuning;
+use crate::wildcard::wildcard_match;
+
+// --handshake-timeout 的默认值
+pub const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
 
 pub struct Config {
-    pub socket5_server: SocketAddr,
+    // 按顺序尝试的出口，至少有一个
+    pub outbounds: Vec<Outbound>,
+    pub happy_path: HappyPathCache,
+    // 每个出口的连接结果
+    pub outbound_health: OutboundHealthMap,
+    // 配置了上限的出口的连接数和带宽状态
+    pub outbound_limits: OutboundLimiters,
     pub host: IpAddr,
-    pub port: usize,
-}
\ No newline at end of file
+    pub port: u16,
+    pub rules: Rules,
+    // 上游是 socket_proxy 时发送连接元数据
+    pub upstream_peer: bool,
+    // 允许选择 METHOD_PEER 的下游网段，其他客户端只能使用普通的 SOCKS5 握手
+    pub trusted_peers: Vec<Cidr>,
+    // 可以使用内置回显目的地的客户端网段，为空时回显关闭，该目的地和其他域名一样交给上游
+    pub echo_sources: Vec<Cidr>,
+    pub tuning: BufferTuning,
+    // 设置到所有上游连接上的 SO_MARK，便于在 iptables 中排除代理自身的流量
+    pub egress_mark: Option<u32>,
+    pub egress: Arc<EgressRegistry>,
+    pub nat64: Option<Nat64>,
+    pub dns_pinning: Option<DnsPinning>,
+    pub local_policy: LocalPolicy,
+    pub local_addrs: LocalAddrs,
+    // 默认的建立连接时间预算，规则可以覆盖
+    pub establish_timeout: Option<Duration>,
+    // 入站 TLS/WebSocket/SOCKS5 握手的超时时间，客户端停在握手中时不会一直占用连接数
+    pub handshake_timeout: Duration,
+    pub original_dst_fallback: OriginalDstFallback,
+    // 上游 SOCKS5 服务端的能力，第一次连接时探测
+    pub capabilities: Arc<CapabilityCache>,
+    // 同时嗅探 SNI 的连接数上限，和总连接数上限分开，避免新连接的缓冲区挤占已建立的连接
+    pub sniff_limit: Option<Semaphore>,
+    // 拒绝的客户端网段
+    pub deny_sources: DenyList,
+    // 连接建立和结束时通知外部命令或 unix socket
+    pub hooks: Option<Hooks>,
+    // 非 SOCKS5 探测的处理方式
+    pub probe_response: ProbeResponse,
+    // 443 端口嗅探不到 SNI 时的默认处理方式，规则可以覆盖
+    pub no_sni: NoSniPolicy,
+    // 按客户端地址统计的流量，写入 --accounting-journal
+    pub accounting: Option<Arc<Accounting>>,
+}
+
+impl Config {
+    // new 使用默认设置创建配置，只指定上游地址
+    pub fn new(socket5_server: SocketAddr) -> Self {
+        Config {
+            outbounds: vec![Outbound::socks5(socket5_server)],
+            happy_path: HappyPathCache::default(),
+            outbound_health: OutboundHealthMap::default(),
+            outbound_limits: OutboundLimiters::default(),
+            host: IpAddr::from([0, 0, 0, 0]),
+            port: 1080,
+            rules: Rules::default(),
+            upstream_peer: false,
+            trusted_peers: Vec::new(),
+            echo_sources: Vec::new(),
+            tuning: BufferTuning::default(),
+            egress_mark: None,
+            egress: Arc::default(),
+            nat64: None,
+            dns_pinning: None,
+            local_policy: LocalPolicy::Upstream,
+            local_addrs: LocalAddrs::default(),
+            establish_timeout: None,
+            handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+            original_dst_fallback: OriginalDstFallback::default(),
+            capabilities: Arc::default(),
+            sniff_limit: None,
+            deny_sources: DenyList::default(),
+            hooks: None,
+            probe_response: ProbeResponse::default(),
+            no_sni: NoSniPolicy::Ip,
+            accounting: None,
+        }
+    }
+
+    // is_trusted_peer 客户端是否可以发送连接元数据
+    pub fn is_trusted_peer(&self, ip: IpAddr) -> bool {
+        self.trusted_peers.iter().any(|cidr| cidr.contains(ip))
+    }
+}
+
+// Permits 并发数上限，必须大于 0，且不能超过 Semaphore 能表示的许可数
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Permits(NonZeroUsize);
+
+impl Permits {
+    pub fn new(n: usize) -> Result<Self, String> {
+        if n > Semaphore::MAX_PERMITS {
+            return Err(format!(
+                "{} is too large, the maximum is {}",
+                n,
+                Semaphore::MAX_PERMITS
+            ));
+        }
+        NonZeroUsize::new(n)
+            .map(Permits)
+            .ok_or_else(|| "must not be 0".to_owned())
+    }
+
+    pub fn get(self) -> usize {
+        self.0.get()
+    }
+
+    pub fn semaphore(self) -> Semaphore {
+        Semaphore::new(self.get())
+    }
+}
+
+impl FromStr for Permits {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Permits::new(s.parse().map_err(|err| format!("{}", err))?)
+    }
+}
+
+// ConfigFile 通过 --config 指定的 TOML 配置文件
+// include 中的路径相对于所在文件，文件名部分可以使用 * 和 ? 通配，匹配到的文件按文件名排序加载
+// 规则按 自身的 rules、include 的文件 的顺序合并，先匹配的规则生效，所以文件自身的规则可以覆盖共享的规则
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigFile {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub include: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub rules: Vec<RuleConfig>,
+    // 通过 --profile 选择，profile 的规则排在公共规则之前
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub profiles: BTreeMap<String, Profile>,
+    // 监听端口，为空时使用 --host 和 --port
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub listeners: Vec<ListenerConfig>,
+    // 上游，为空时使用 --socks5
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub outbounds: Vec<OutboundConfig>,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub include: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub rules: Vec<RuleConfig>,
+}
+
+fn invalid_data(path: &Path, msg: impl fmt::Display) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("{}: {}", path.display(), msg),
+    )
+}
+
+// expand_include 展开 include 中的一项，没有通配符的路径必须存在
+fn expand_include(base: &Path, pattern: &str) -> io::Result<Vec<PathBuf>> {
+    let path = base.join(pattern);
+    let name = match path.file_name().and_then(|name| name.to_str()) {
+        Some(name) if name.contains(['*', '?']) => name.to_owned(),
+        _ => return Ok(vec![path]),
+    };
+    let dir = path.parent().unwrap_or(base);
+    if dir.to_string_lossy().contains(['*', '?']) {
+        return Err(invalid_data(
+            &path,
+            "wildcards are only supported in the file name",
+        ));
+    }
+    let mut paths = Vec::new();
+    for entry in fs::read_dir(dir)
+        .map_err(|err| io::Error::new(err.kind(), format!("{}: {}", dir.display(), err)))?
+    {
+        let entry = entry?;
+        let matched = entry
+            .file_name()
+            .to_str()
+            .is_some_and(|file| wildcard_match(name.as_bytes(), file.as_bytes(), false));
+        if matched && entry.file_type()?.is_file() {
+            paths.push(entry.path());
+        }
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+impl ConfigFile {
+    // load 读取配置文件并展开所有 include，返回的 ConfigFile 中 include 为空
+    pub fn load(path: &Path) -> io::Result<Self> {
+        Self::load_nested(path, &mut Vec::new())
+    }
+
+    fn parse(path: &Path) -> io::Result<Self> {
+        let content = fs::read_to_string(path)
+            .map_err(|err| io::Error::new(err.kind(), format!("{}: {}", path.display(), err)))?;
+        toml::from_str(&content).map_err(|err| invalid_data(path, err))
+    }
+
+    // stack 记录正在加载的文件，用于检测循环 include
+    fn load_nested(path: &Path, stack: &mut Vec<PathBuf>) -> io::Result<Self> {
+        let canonical = fs::canonicalize(path)
+            .map_err(|err| io::Error::new(err.kind(), format!("{}: {}", path.display(), err)))?;
+        if stack.contains(&canonical) {
+            return Err(invalid_data(path, "include cycle"));
+        }
+        stack.push(canonical);
+        let mut file = Self::parse(path)?;
+        let base = path.parent().unwrap_or_else(|| Path::new("."));
+        for pattern in mem::take(&mut file.include) {
+            for included in expand_include(base, &pattern)? {
+                let included = Self::load_nested(&included, stack)?;
+                file.rules.extend(included.rules);
+                file.listeners.extend(included.listeners);
+                file.outbounds.extend(included.outbounds);
+                for (name, profile) in included.profiles {
+                    file.profiles
+                        .entry(name)
+                        .or_default()
+                        .rules
+                        .extend(profile.rules);
+                }
+            }
+        }
+        for profile in file.profiles.values_mut() {
+            for pattern in mem::take(&mut profile.include) {
+                for included in expand_include(base, &pattern)? {
+                    let included = Self::load_nested(&included, stack)?;
+                    if !included.profiles.is_empty() {
+                        return Err(invalid_data(
+                            path,
+                            "files included by a profile can't define profiles",
+                        ));
+                    }
+                    profile.rules.extend(included.rules);
+                }
+            }
+        }
+        stack.pop();
+        Ok(file)
+    }
+
+    // rules 返回选中 profile 的规则和公共规则
+    pub fn rules(&self, profile: Option<&str>) -> io::Result<Vec<RuleConfig>> {
+        let mut rules = Vec::new();
+        if let Some(name) = profile {
+            let profile = self.profiles.get(name).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "unknown profile {}, available: {:?}",
+                        name,
+                        self.profiles.keys().collect::<Vec<_>>()
+                    ),
+                )
+            })?;
+            rules.extend(profile.rules.iter().cloned());
+        }
+        rules.extend(self.rules.iter().cloned());
+        Ok(rules)
+    }
+}
@@ -0,0 +1,34 @@
+use std::{
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+// ConnId 在 accept 时分配的连接编号，进程内唯一
+// 日志、BiPipe、指标和发给 socket_proxy 上游的元数据 (request_id) 都使用同一个编号，便于追踪一条连接
+// next 从 1 开始分配，默认值 0 表示没有编号的连接
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ConnId(u64);
+
+impl ConnId {
+    pub fn next() -> Self {
+        ConnId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn get(self) -> u64 {
+        self.0
+    }
+}
+
+impl From<u64> for ConnId {
+    fn from(id: u64) -> Self {
+        ConnId(id)
+    }
+}
+
+impl fmt::Display for ConnId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "#{}", self.0)
+    }
+}
@@ -0,0 +1,95 @@
+use std::{str::FromStr, time::Duration};
+
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    time::timeout,
+};
+
+// 回复 HTTP 后等待对方读完再关闭，避免未读的请求数据让内核发送 RST 冲掉回复
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+// delay:SECS 的上限，等待期间连接一直占用连接数
+pub const MAX_DELAY: Duration = Duration::from_secs(60);
+
+const DECOY_BODY: &str = "<html>\r\n<head><title>404 Not Found</title></head>\r\n<body>\r\n\
+                          <center><h1>404 Not Found</h1></center>\r\n<hr><center>nginx</center>\r\n\
+                          </body>\r\n</html>\r\n";
+
+// ProbeResponse 监听端口收到既不是 SOCKS5 也不是转发连接的数据时的处理方式
+// 端口暴露在公网时，扫描器据此判断端口上的服务，默认的立即关闭很容易被识别为代理
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ProbeResponse {
+    // 立即关闭连接
+    #[default]
+    Close,
+    // 读取并丢弃数据，超时后再关闭，看起来像等待特定协议的服务
+    Delay(Duration),
+    // 回复一个 404 页面，看起来像普通的 web 服务器
+    Http,
+}
+
+// 格式为 close、delay:SECS 或 http
+impl FromStr for ProbeResponse {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "close" => Ok(ProbeResponse::Close),
+            "http" => Ok(ProbeResponse::Http),
+            _ => match s.strip_prefix("delay:") {
+                Some(secs) => {
+                    let delay = secs
+                        .parse()
+                        .map(Duration::from_secs)
+                        .map_err(|err| format!("invalid delay {:?}: {}", secs, err))?;
+                    if delay > MAX_DELAY {
+                        return Err(format!(
+                            "delay {}s is longer than the maximum {}s",
+                            delay.as_secs(),
+                            MAX_DELAY.as_secs()
+                        ));
+                    }
+                    Ok(ProbeResponse::Delay(delay))
+                }
+                None => Err(format!("unknown probe response {}", s)),
+            },
+        }
+    }
+}
+
+// discard 读取并丢弃数据直到对方关闭或出错
+async fn discard<S: AsyncRead + Unpin>(stream: &mut S) {
+    let mut buf = [0u8; 1024];
+    while let Ok(n) = stream.read(&mut buf).await {
+        if n == 0 {
+            break;
+        }
+    }
+}
+
+impl ProbeResponse {
+    // respond 按配置回应探测，返回后调用方关闭连接
+    pub async fn respond<S>(self, stream: &mut S)
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        match self {
+            ProbeResponse::Close => (),
+            ProbeResponse::Delay(delay) => {
+                let _ = timeout(delay, discard(stream)).await;
+            }
+            ProbeResponse::Http => {
+                let response = format!(
+                    "HTTP/1.1 404 Not Found\r\nServer: nginx\r\nContent-Type: text/html\r\n\
+                     Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    DECOY_BODY.len(),
+                    DECOY_BODY
+                );
+                if stream.write_all(response.as_bytes()).await.is_ok()
+                    && stream.shutdown().await.is_ok()
+                {
+                    let _ = timeout(DRAIN_TIMEOUT, discard(stream)).await;
+                }
+            }
+        }
+    }
+}
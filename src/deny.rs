@@ -0,0 +1,145 @@
+use std::{io, net::IpAddr, os::unix::prelude::AsRawFd};
+
+use nix::libc::{self, sock_filter};
+
+use crate::linux::attach_filter;
+use crate::rules::Cidr;
+
+// BPF 中相对网络层头部的偏移，过滤器看到的数据从传输层头部开始
+const NET_OFF: u32 = libc::SKF_NET_OFF as u32;
+const IPV4_SRC: u32 = 12;
+const IPV6_SRC: u32 = 8;
+const ACCEPT: u32 = u32::MAX;
+const DROP: u32 = 0;
+
+fn stmt(code: u32, k: u32) -> sock_filter {
+    sock_filter {
+        code: code as u16,
+        jt: 0,
+        jf: 0,
+        k,
+    }
+}
+
+fn jump(code: u32, k: u32, jt: u8, jf: u8) -> sock_filter {
+    sock_filter {
+        code: code as u16,
+        jt,
+        jf,
+        k,
+    }
+}
+
+fn mask(prefix: u8) -> u32 {
+    u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0)
+}
+
+// DenyList 拒绝的客户端网段 (--deny-source)
+// 优先以 BPF 过滤器挂在监听 socket 上，在内核中丢弃 SYN，扫描流量不会占用 accept 和用户态；
+// 过滤器挂载失败时由 accept 之后的 contains 检查兜底
+#[derive(Debug, Default, Clone)]
+pub struct DenyList(Vec<Cidr>);
+
+impl DenyList {
+    pub fn new(cidrs: Vec<Cidr>) -> Self {
+        DenyList(cidrs)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        self.0.iter().any(|cidr| cidr.contains(ip))
+    }
+
+    // program 生成 classic BPF 程序：
+    // 先按 IP 版本分支，每个网段比较后紧跟一条 ret 0，条件跳转的偏移不会超过 u8
+    pub fn program(&self) -> io::Result<Vec<sock_filter>> {
+        let mut v4 = Vec::new();
+        let mut v6 = Vec::new();
+        for cidr in &self.0 {
+            match cidr.addr() {
+                IpAddr::V4(net) => {
+                    v4.push(stmt(
+                        libc::BPF_LD | libc::BPF_W | libc::BPF_ABS,
+                        NET_OFF + IPV4_SRC,
+                    ));
+                    v4.push(stmt(
+                        libc::BPF_ALU | libc::BPF_AND | libc::BPF_K,
+                        mask(cidr.prefix()),
+                    ));
+                    v4.push(jump(
+                        libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K,
+                        u32::from(net) & mask(cidr.prefix()),
+                        0,
+                        1,
+                    ));
+                    v4.push(stmt(libc::BPF_RET | libc::BPF_K, DROP));
+                }
+                IpAddr::V6(net) => {
+                    // 按 32 位分段比较，前缀没有覆盖到的段不需要比较
+                    let words = net.octets();
+                    let prefix = cidr.prefix();
+                    let count = (prefix as usize).div_ceil(32);
+                    let mut block = Vec::new();
+                    for i in 0..count {
+                        let bits = (prefix as usize - i * 32).min(32) as u8;
+                        let word = u32::from_be_bytes(words[i * 4..i * 4 + 4].try_into().unwrap());
+                        block.push(stmt(
+                            libc::BPF_LD | libc::BPF_W | libc::BPF_ABS,
+                            NET_OFF + IPV6_SRC + i as u32 * 4,
+                        ));
+                        block.push(stmt(
+                            libc::BPF_ALU | libc::BPF_AND | libc::BPF_K,
+                            mask(bits),
+                        ));
+                        // 不相等时跳过本网段剩下的指令，jf 在下面回填
+                        block.push(jump(
+                            libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K,
+                            word & mask(bits),
+                            0,
+                            0,
+                        ));
+                    }
+                    let len = block.len();
+                    for (i, ins) in block.iter_mut().enumerate() {
+                        if ins.code == (libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K) as u16 {
+                            // 跳到 ret 0 之后
+                            ins.jf = (len - i) as u8;
+                        }
+                    }
+                    v6.extend(block);
+                    v6.push(stmt(libc::BPF_RET | libc::BPF_K, DROP));
+                }
+            }
+        }
+        let mut program = vec![
+            stmt(libc::BPF_LD | libc::BPF_B | libc::BPF_ABS, NET_OFF),
+            stmt(libc::BPF_ALU | libc::BPF_RSH | libc::BPF_K, 4),
+            // 不是 IPv6 时跳过下面的 ja
+            jump(libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K, 6, 0, 1),
+            stmt(libc::BPF_JMP | libc::BPF_JA, v4.len() as u32 + 1),
+        ];
+        program.extend(v4);
+        program.push(stmt(libc::BPF_RET | libc::BPF_K, ACCEPT));
+        program.extend(v6);
+        program.push(stmt(libc::BPF_RET | libc::BPF_K, ACCEPT));
+        if program.len() > libc::BPF_MAXINSNS as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "too many denied networks, the filter needs {} instructions (max {})",
+                    program.len(),
+                    libc::BPF_MAXINSNS
+                ),
+            ));
+        }
+        Ok(program)
+    }
+
+    // attach 把过滤器挂到监听 socket 上
+    pub fn attach<F: AsRawFd>(&self, listener: &F) -> io::Result<()> {
+        attach_filter(listener, &self.program()?)
+    }
+}
@@ -0,0 +1,35 @@
+use log::info;
+use tokio::io::{copy, duplex, split, AsyncWriteExt, DuplexStream};
+
+use crate::client::{Address, Destination};
+
+// 内置的回显目的地，CONNECT 到 proxy-test.internal:7 时由代理自己回显数据
+// 连接同样经过握手、规则匹配和 BiPipe，只是不连接上游，可以用来端到端地检查代理本身
+pub const ECHO_HOST: &str = "proxy-test.internal";
+pub const ECHO_PORT: u16 = 7;
+
+const ECHO_BUF_SIZE: usize = 1024 * 64;
+
+pub fn is_echo(dest: &Destination) -> bool {
+    match dest.host {
+        Address::Domain(ref domain) => {
+            dest.port == ECHO_PORT && domain.trim_end_matches('.').eq_ignore_ascii_case(ECHO_HOST)
+        }
+        Address::Ip(_) => false,
+    }
+}
+
+// connect 返回连接到回显服务的内存流
+pub fn connect(dest: &Destination) -> DuplexStream {
+    let (stream, server) = duplex(ECHO_BUF_SIZE);
+    let dest = dest.to_string();
+    tokio::spawn(async move {
+        let (mut reader, mut writer) = split(server);
+        match copy(&mut reader, &mut writer).await {
+            Ok(n) => info!("echo {} served {} bytes", dest, n),
+            Err(err) => info!("echo {} error {}", dest, err),
+        }
+        let _ = writer.shutdown().await;
+    });
+    stream
+}
@@ -0,0 +1,126 @@
+use std::{io, net::SocketAddr};
+
+use log::info;
+use tokio::process::Command;
+
+const COMMENT: &str = "socket_proxy upstream";
+
+// FirewallExclusion --manage-firewall 在 nat 表的 REDIRECT 链最前面插入的 RETURN 规则
+// 发往上游的连接不会再被转发回监听端口，退出时只删除自己插入的规则
+pub struct FirewallExclusion {
+    chain: String,
+    upstream: SocketAddr,
+    // 规则是否由这个进程插入，已经存在的规则可能属于其他实例
+    inserted: bool,
+}
+
+impl FirewallExclusion {
+    pub fn new(chain: &str, upstream: SocketAddr) -> Self {
+        FirewallExclusion {
+            chain: chain.into(),
+            upstream,
+            inserted: false,
+        }
+    }
+
+    fn program(&self) -> &'static str {
+        if self.upstream.is_ipv4() {
+            "iptables"
+        } else {
+            "ip6tables"
+        }
+    }
+
+    // args 返回 iptables 的参数，op 为 -C、-I 或 -D
+    pub fn args(&self, op: &str) -> Vec<String> {
+        let mut args: Vec<String> = vec!["-w".into(), "-t".into(), "nat".into(), op.into()];
+        args.push(self.chain.clone());
+        // 插入到链的最前面，排在 REDIRECT 规则之前
+        if op == "-I" {
+            args.push("1".into());
+        }
+        for arg in [
+            "-p",
+            "tcp",
+            "-d",
+            &self.upstream.ip().to_string(),
+            "--dport",
+            &self.upstream.port().to_string(),
+            "-m",
+            "comment",
+            "--comment",
+            COMMENT,
+            "-j",
+            "RETURN",
+        ] {
+            args.push(arg.into());
+        }
+        args
+    }
+
+    // run 执行 iptables，-w 等待 xtables 锁时不阻塞运行时
+    async fn run(&self, op: &str) -> io::Result<bool> {
+        let output = Command::new(self.program())
+            .args(self.args(op))
+            .kill_on_drop(true)
+            .output()
+            .await
+            .map_err(|err| {
+                io::Error::new(
+                    err.kind(),
+                    format!("failed to run {}: {}", self.program(), err),
+                )
+            })?;
+        if output.status.success() {
+            return Ok(true);
+        }
+        // -C 在规则不存在时返回 1
+        if op == "-C" && output.status.code() == Some(1) {
+            return Ok(false);
+        }
+        Err(io::Error::other(format!(
+            "{} {} failed: {}",
+            self.program(),
+            self.args(op).join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )))
+    }
+
+    // install 插入规则，已经存在的同样规则 (上次异常退出留下的，或者其他实例的) 直接复用，退出时不删除
+    pub async fn install(&mut self) -> io::Result<()> {
+        if self.run("-C").await? {
+            info!(
+                "{} chain {} already excludes upstream {}, leaving the rule in place on exit",
+                self.program(),
+                self.chain,
+                self.upstream
+            );
+            return Ok(());
+        }
+        self.run("-I").await?;
+        self.inserted = true;
+        info!(
+            "{} chain {} excludes upstream {}",
+            self.program(),
+            self.chain,
+            self.upstream
+        );
+        Ok(())
+    }
+
+    // remove 删除 install 插入的规则，没有插入时什么都不做
+    pub async fn remove(&mut self) -> io::Result<()> {
+        if !self.inserted {
+            return Ok(());
+        }
+        self.run("-D").await?;
+        self.inserted = false;
+        info!(
+            "{} chain {} no longer excludes upstream {}",
+            self.program(),
+            self.chain,
+            self.upstream
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,278 @@
+use std::{
+    fmt::Write as _,
+    io,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    process::Stdio,
+    str::FromStr,
+    sync::Mutex,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use log::warn;
+use tokio::{
+    io::AsyncWriteExt,
+    net::UnixStream,
+    process::Command,
+    sync::{
+        mpsc::{self, error::TrySendError},
+        oneshot,
+    },
+    time::timeout,
+};
+
+use crate::{conn_id::ConnId, metrics::METRICS};
+
+// 排队等待投递的事件数上限，超出时丢弃新事件
+const QUEUE_SIZE: usize = 1024;
+// 单个命令或一次 socket 写入的最长时间，超时后放弃该事件，避免阻塞后续事件
+const DELIVER_TIMEOUT: Duration = Duration::from_secs(5);
+
+// HookTarget 连接事件的接收方
+// unix:PATH 连接 unix socket，每个事件一行 JSON；其他值作为 shell 命令执行，JSON 从 stdin 传入
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HookTarget {
+    Command(String),
+    Unix(PathBuf),
+}
+
+impl FromStr for HookTarget {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix("unix:") {
+            Some("") => Err("missing unix socket path"),
+            Some(path) => Ok(HookTarget::Unix(path.into())),
+            None if s.trim().is_empty() => Err("empty hook command"),
+            None => Ok(HookTarget::Command(s.into())),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventKind {
+    Open,
+    Close,
+}
+
+// Event 连接建立 (open) 和结束 (close) 时发送的元数据，close 事件带上流量和持续时间
+#[derive(Debug, Clone)]
+pub struct Event {
+    pub kind: EventKind,
+    // 事件发生的时间，投递可能排队，不能用投递时的时间
+    pub time: SystemTime,
+    pub id: ConnId,
+    pub src: SocketAddr,
+    pub dest: String,
+    pub sni: Option<String>,
+    pub protocol: Option<&'static str>,
+    pub up: u64,
+    pub down: u64,
+    pub duration: Option<Duration>,
+}
+
+// push_json_str 写入转义后的 JSON 字符串
+pub fn push_json_str(out: &mut String, value: &str) {
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+impl Event {
+    // to_json 编码为单行 JSON，字段固定，值为空的字段不输出
+    pub fn to_json(&self) -> String {
+        let mut out = String::from("{\"event\":");
+        push_json_str(
+            &mut out,
+            match self.kind {
+                EventKind::Open => "open",
+                EventKind::Close => "close",
+            },
+        );
+        let time = self.time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let _ = write!(
+            out,
+            ",\"time\":{}.{:03},\"id\":{},\"src\":",
+            time.as_secs(),
+            time.subsec_millis(),
+            self.id.get()
+        );
+        push_json_str(&mut out, &self.src.to_string());
+        out.push_str(",\"dest\":");
+        push_json_str(&mut out, &self.dest);
+        if let Some(ref sni) = self.sni {
+            out.push_str(",\"sni\":");
+            push_json_str(&mut out, sni);
+        }
+        if self.kind == EventKind::Close {
+            if let Some(protocol) = self.protocol {
+                out.push_str(",\"protocol\":");
+                push_json_str(&mut out, protocol);
+            }
+            let _ = write!(out, ",\"up\":{},\"down\":{}", self.up, self.down);
+            if let Some(duration) = self.duration {
+                let _ = write!(out, ",\"duration_ms\":{}", duration.as_millis());
+            }
+        }
+        out.push('}');
+        out
+    }
+}
+
+// RateLimit 每秒最多放行 rate 个事件
+struct RateLimit {
+    rate: u32,
+    window: Mutex<(Instant, u32)>,
+}
+
+impl RateLimit {
+    fn allow(&self) -> bool {
+        let mut window = self.window.lock().unwrap();
+        let now = Instant::now();
+        if now.duration_since(window.0) >= Duration::from_secs(1) {
+            *window = (now, 0);
+        }
+        if window.1 >= self.rate {
+            return false;
+        }
+        window.1 += 1;
+        true
+    }
+}
+
+enum Queued {
+    Event(Event),
+    // 之前排队的事件都处理完后通知 flush
+    Flush(oneshot::Sender<()>),
+}
+
+// Hooks 把连接事件异步投递给外部命令或 unix socket
+// 投递在后台任务中逐个进行，连接本身从不等待；队列满或超出速率的事件直接丢弃并计数
+// 速率只在 open 时判断，放行的连接一定会投递 close，接收方不会看到没有 close 的 open
+pub struct Hooks {
+    tx: mpsc::Sender<Queued>,
+    limit: Option<RateLimit>,
+}
+
+impl Hooks {
+    // spawn 启动投递任务，rate 为 0 时不限速，需要在 tokio 运行时中调用
+    pub fn spawn(target: HookTarget, rate: u32) -> Self {
+        let (tx, rx) = mpsc::channel(QUEUE_SIZE);
+        tokio::spawn(deliver_all(target, rx));
+        Hooks {
+            tx,
+            limit: (rate > 0).then(|| RateLimit {
+                rate,
+                window: Mutex::new((Instant::now(), 0)),
+            }),
+        }
+    }
+
+    // open 投递连接的 open 事件，返回 false 表示事件被丢弃，这时该连接不再投递 close
+    pub fn open(&self, event: Event) -> bool {
+        if let Some(ref limit) = self.limit {
+            if !limit.allow() {
+                METRICS.hook_dropped.inc();
+                return false;
+            }
+        }
+        self.send(event)
+    }
+
+    // close 投递 open 被放行的连接的 close 事件，不受速率限制
+    pub fn close(&self, event: Event) {
+        self.send(event);
+    }
+
+    // flush 等待已经排队的事件投递完 (或者投递失败)，关闭时在连接都结束后调用
+    pub async fn flush(&self) {
+        let (done, wait) = oneshot::channel();
+        if self.tx.send(Queued::Flush(done)).await.is_ok() {
+            let _ = wait.await;
+        }
+    }
+
+    fn send(&self, event: Event) -> bool {
+        match self.tx.try_send(Queued::Event(event)) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) | Err(TrySendError::Closed(_)) => {
+                METRICS.hook_dropped.inc();
+                false
+            }
+        }
+    }
+}
+
+async fn deliver_all(target: HookTarget, mut rx: mpsc::Receiver<Queued>) {
+    // unix socket 连接在事件之间复用，出错后下一个事件重新连接
+    let mut socket: Option<UnixStream> = None;
+    while let Some(queued) = rx.recv().await {
+        let event = match queued {
+            Queued::Event(event) => event,
+            Queued::Flush(done) => {
+                let _ = done.send(());
+                continue;
+            }
+        };
+        let mut line = event.to_json();
+        line.push('\n');
+        let delivered = match target {
+            HookTarget::Command(ref command) => {
+                timeout(DELIVER_TIMEOUT, run_command(command, &line)).await
+            }
+            HookTarget::Unix(ref path) => {
+                timeout(DELIVER_TIMEOUT, send_unix(&mut socket, path, &line)).await
+            }
+        };
+        let err = match delivered {
+            Ok(Ok(())) => continue,
+            Ok(Err(err)) => err,
+            Err(_) => io::Error::new(io::ErrorKind::TimedOut, "hook timed out"),
+        };
+        socket = None;
+        METRICS.hook_failures.inc();
+        warn!(
+            "{} failed to deliver {:?} hook: {}",
+            event.id, event.kind, err
+        );
+    }
+}
+
+async fn run_command(command: &str, line: &str) -> io::Result<()> {
+    let mut child = Command::new("/bin/sh")
+        .arg("-c")
+        .arg(command)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .kill_on_drop(true)
+        .spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        // 命令不读 stdin 就退出时写入会失败，这不算投递失败
+        let _ = stdin.write_all(line.as_bytes()).await;
+    }
+    let status = child.wait().await?;
+    if !status.success() {
+        return Err(io::Error::other(format!("command exited with {}", status)));
+    }
+    Ok(())
+}
+
+async fn send_unix(socket: &mut Option<UnixStream>, path: &Path, line: &str) -> io::Result<()> {
+    let stream = match socket {
+        Some(stream) => stream,
+        None => socket.insert(UnixStream::connect(path).await?),
+    };
+    stream.write_all(line.as_bytes()).await
+}
@@ -1,6 +1,32 @@
+pub mod accounting;
+pub mod addr;
+pub mod budget;
 pub mod client;
 pub mod config;
-pub mod stream;
+pub mod conn_id;
+pub mod decoy;
+pub mod deny;
+pub mod echo;
+pub mod firewall;
+pub mod hooks;
 pub mod linux;
+pub mod listener;
+pub mod local;
+pub mod loop_guard;
+pub mod metrics;
+pub mod nat64;
+pub mod original_dst;
+pub mod outbound;
+pub mod pinning;
+pub mod protocols;
+pub mod report;
+pub mod rules;
+pub mod server;
+pub mod shaper;
+pub mod stream;
+pub mod testing;
 pub mod tls;
-pub mod protocols;
\ No newline at end of file
+pub mod trace;
+pub mod tuning;
+pub mod websocket;
+pub mod wildcard;
@@ -1,7 +1,9 @@
+use nix::ifaddrs::getifaddrs;
 use nix::libc;
-use std::io::ErrorKind;
-use std::net::SocketAddrV4;
+use nix::sys::socket::SockAddr;
+use std::net::{IpAddr, SocketAddrV4};
 use std::os::unix::prelude::AsRawFd;
+use std::time::Duration;
 use std::{io, mem, net::SocketAddrV6};
 
 use libc::{c_void, socklen_t};
@@ -13,7 +15,7 @@ where
 {
     let addr = getsockopt(fd.as_raw_fd(), OriginalDst).map_err(|e| match e {
         nix::Error::Sys(err) => io::Error::from(err),
-        _ => io::Error::new(ErrorKind::Other, e),
+        _ => io::Error::other(e),
     })?;
     let addr = SocketAddrV4::new(
         u32::from_be(addr.sin_addr.s_addr).into(),
@@ -38,7 +40,7 @@ where
         )
     };
     if res != 0 {
-        return Err(io::Error::new(ErrorKind::Other, "getsockopt failed"));
+        return Err(io::Error::last_os_error());
     }
     let addr = SocketAddrV6::new(
         sockaddr.sin6_addr.s6_addr.into(),
@@ -48,3 +50,138 @@ where
     );
     Ok(addr)
 }
+
+fn set_int_option<F>(
+    fd: &F,
+    level: libc::c_int,
+    name: libc::c_int,
+    value: libc::c_int,
+) -> io::Result<()>
+where
+    F: AsRawFd,
+{
+    let res = unsafe {
+        libc::setsockopt(
+            fd.as_raw_fd(),
+            level,
+            name,
+            &value as *const _ as *const c_void,
+            mem::size_of::<libc::c_int>() as socklen_t,
+        )
+    };
+    if res != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// set_mark 设置 SO_MARK，需要 CAP_NET_ADMIN
+pub fn set_mark<F>(fd: &F, mark: u32) -> io::Result<()>
+where
+    F: AsRawFd,
+{
+    set_int_option(fd, libc::SOL_SOCKET, libc::SO_MARK, mark as libc::c_int)
+}
+
+// set_defer_accept 设置 TCP_DEFER_ACCEPT，连接在客户端发送数据或超时之后才进入 accept 队列
+// 超时后内核重传 SYN-ACK，收到 ACK 时仍然交给 accept，不会丢弃连接
+pub fn set_defer_accept<F>(fd: &F, secs: u64) -> io::Result<()>
+where
+    F: AsRawFd,
+{
+    let secs = secs.min(libc::c_int::MAX as u64) as libc::c_int;
+    set_int_option(fd, libc::IPPROTO_TCP, libc::TCP_DEFER_ACCEPT, secs)
+}
+
+// set_dscp 设置 IP 头中的 DSCP，ipv6 socket 同时设置 TCLASS 和 TOS (v4-mapped 连接使用 TOS)
+pub fn set_dscp<F>(fd: &F, is_ipv6: bool, dscp: u8) -> io::Result<()>
+where
+    F: AsRawFd,
+{
+    let tos = (dscp as libc::c_int) << 2;
+    if is_ipv6 {
+        set_int_option(fd, libc::IPPROTO_IPV6, libc::IPV6_TCLASS, tos)?;
+        let _ = set_int_option(fd, libc::IPPROTO_IP, libc::IP_TOS, tos);
+        Ok(())
+    } else {
+        set_int_option(fd, libc::IPPROTO_IP, libc::IP_TOS, tos)
+    }
+}
+
+fn get_tcp_info<F>(fd: &F) -> io::Result<libc::tcp_info>
+where
+    F: AsRawFd,
+{
+    let mut info: libc::tcp_info = unsafe { mem::zeroed() };
+    let mut len = mem::size_of::<libc::tcp_info>() as socklen_t;
+    let res = unsafe {
+        libc::getsockopt(
+            fd.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            libc::TCP_INFO,
+            &mut info as *mut _ as *mut c_void,
+            &mut len,
+        )
+    };
+    if res != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(info)
+}
+
+// get_tcp_rtt 通过 TCP_INFO 获取内核估计的平滑 RTT
+pub fn get_tcp_rtt<F>(fd: &F) -> io::Result<Duration>
+where
+    F: AsRawFd,
+{
+    Ok(Duration::from_micros(get_tcp_info(fd)?.tcpi_rtt as u64))
+}
+
+// get_accept_queue 返回监听 socket 的 (accept 队列长度, 队列上限)
+// 对 LISTEN 状态的 socket，内核在 tcpi_unacked/tcpi_sacked 中填写这两个值
+pub fn get_accept_queue<F>(fd: &F) -> io::Result<(u32, u32)>
+where
+    F: AsRawFd,
+{
+    let info = get_tcp_info(fd)?;
+    Ok((info.tcpi_unacked, info.tcpi_sacked))
+}
+
+// attach_filter 在 socket 上挂载 classic BPF 过滤器，监听 socket 上的过滤器作用于 SYN，
+// 被丢弃的连接不会进入 accept 队列
+pub fn attach_filter<F>(fd: &F, program: &[libc::sock_filter]) -> io::Result<()>
+where
+    F: AsRawFd,
+{
+    let prog = libc::sock_fprog {
+        len: program.len() as libc::c_ushort,
+        filter: program.as_ptr() as *mut libc::sock_filter,
+    };
+    let res = unsafe {
+        libc::setsockopt(
+            fd.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_ATTACH_FILTER,
+            &prog as *const _ as *const c_void,
+            mem::size_of::<libc::sock_fprog>() as socklen_t,
+        )
+    };
+    if res != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// get_local_addresses 返回本机所有网卡上的 IP 地址
+pub fn get_local_addresses() -> io::Result<Vec<IpAddr>> {
+    let addrs = getifaddrs().map_err(|e| match e {
+        nix::Error::Sys(err) => io::Error::from(err),
+        _ => io::Error::other(e),
+    })?;
+    Ok(addrs
+        .filter_map(|ifaddr| match ifaddr.address {
+            Some(SockAddr::Inet(addr)) => Some(addr.ip().to_std()),
+            _ => None,
+        })
+        .collect())
+}
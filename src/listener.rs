@@ -0,0 +1,132 @@
+use std::{
+    fmt,
+    net::SocketAddr,
+    sync::{Arc, Mutex, Weak},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::metrics::Counter;
+
+// Role 监听端口接受的连接类型
+// 同一进程可以同时监听只给本机客户端用的 SOCKS5 端口和给局域网转发用的透明代理端口，
+// 两者共享上游、规则、缓存和指标
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    // 查询原始目的地，查不到时根据第一个字节判断是否是 SOCKS5 客户端
+    #[default]
+    Auto,
+    // 只接受 SOCKS5 客户端，不查询原始目的地
+    Socks,
+    // 只接受 iptables 转发的连接，直接连接监听端口的客户端按 --probe-response 处理
+    Redirect,
+}
+
+impl Role {
+    pub fn name(self) -> &'static str {
+        match self {
+            Role::Auto => "auto",
+            Role::Socks => "socks",
+            Role::Redirect => "redirect",
+        }
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+fn is_false(value: &bool) -> bool {
+    !value
+}
+
+// ListenerConfig 配置文件中的一个监听端口
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ListenerConfig {
+    // 指标和日志中使用的名字，默认为监听地址
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    pub listen: SocketAddr,
+    #[serde(default)]
+    pub role: Role,
+    // 使用 --tls-cert 的证书提供 TLS，只适用于 SOCKS5 客户端
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub tls: bool,
+    // TCP_DEFER_ACCEPT 的秒数，0 表示关闭，redirect 端口默认打开
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub defer_accept: Option<u64>,
+}
+
+// redirect 端口默认的 TCP_DEFER_ACCEPT 秒数
+// 服务端先发数据的协议最多因此多等这么久 (内核按 SYN-ACK 重传的次数取整)
+pub const DEFAULT_DEFER_ACCEPT: u64 = 1;
+
+impl ListenerConfig {
+    pub fn name(&self) -> String {
+        self.name.clone().unwrap_or_else(|| self.listen.to_string())
+    }
+
+    // defer_accept 客户端发送数据之前不唤醒 accept 的秒数，0 表示不等待
+    // SOCKS5 客户端同样先发数据，但 auto 端口常被本机程序直接使用，只有 redirect 端口默认打开
+    pub fn defer_accept(&self) -> u64 {
+        match (self.defer_accept, self.role) {
+            (Some(secs), _) => secs,
+            (None, Role::Redirect) => DEFAULT_DEFER_ACCEPT,
+            (None, _) => 0,
+        }
+    }
+}
+
+// ListenerStats 每个监听端口的连接数，监听端口和它的连接都结束后不再出现在指标中
+pub struct ListenerStats {
+    pub name: String,
+    pub role: Role,
+    // 累计 accept 的连接数和当前的连接数
+    pub accepted: Counter,
+    pub active: Counter,
+}
+
+static LISTENERS: Mutex<Vec<Weak<ListenerStats>>> = Mutex::new(Vec::new());
+
+// live 返回还在使用的统计，同时清理已经释放的
+pub(crate) fn live(list: &mut Vec<Weak<ListenerStats>>) -> Vec<Arc<ListenerStats>> {
+    list.retain(|stats| stats.strong_count() > 0);
+    list.iter().filter_map(Weak::upgrade).collect()
+}
+
+impl ListenerStats {
+    // register 创建并登记监听端口的统计，之后在指标中输出
+    pub fn register(name: String, role: Role) -> Arc<ListenerStats> {
+        let stats = Arc::new(ListenerStats {
+            name,
+            role,
+            accepted: Counter::new(),
+            active: Counter::new(),
+        });
+        LISTENERS.lock().unwrap().push(Arc::downgrade(&stats));
+        stats
+    }
+
+    // all 返回进程中所有还在使用的监听端口
+    pub fn all() -> Vec<Arc<ListenerStats>> {
+        live(&mut LISTENERS.lock().unwrap())
+    }
+
+    // track 将当前连接数加一，返回的 guard 在连接结束时减一
+    pub fn track(self: &Arc<Self>) -> ActiveConnection {
+        self.active.inc();
+        ActiveConnection(self.clone())
+    }
+}
+
+pub struct ActiveConnection(Arc<ListenerStats>);
+
+impl Drop for ActiveConnection {
+    fn drop(&mut self) {
+        self.0.active.dec();
+    }
+}
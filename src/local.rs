@@ -0,0 +1,70 @@
+use std::{collections::HashSet, io, net::IpAddr, str::FromStr};
+
+use crate::addr::{canonical_ip, is_loopback};
+use crate::client::{Address, Destination};
+use crate::linux::get_local_addresses;
+
+// LocalPolicy 目的地是代理所在主机 (回环地址、localhost 或本机网卡地址) 时的处理方式
+// 这类连接交给上游没有意义，透明代理下还可能绕回自己，所以在连接上游之前处理
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LocalPolicy {
+    // 由代理直接连接
+    Direct,
+    // 拒绝连接，避免客户端借助代理访问只监听在本机的服务
+    Reject,
+    // 和其他目的地一样交给上游
+    Upstream,
+}
+
+impl FromStr for LocalPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "direct" => Ok(LocalPolicy::Direct),
+            "reject" => Ok(LocalPolicy::Reject),
+            "upstream" => Ok(LocalPolicy::Upstream),
+            _ => Err(format!("unknown local destination policy {}", s)),
+        }
+    }
+}
+
+// LocalAddrs 启动时记录的本机地址，之后新增的网卡地址不会被识别
+#[derive(Debug, Default)]
+pub struct LocalAddrs(HashSet<IpAddr>);
+
+impl FromIterator<IpAddr> for LocalAddrs {
+    fn from_iter<I: IntoIterator<Item = IpAddr>>(iter: I) -> Self {
+        LocalAddrs(iter.into_iter().map(canonical_ip).collect())
+    }
+}
+
+impl LocalAddrs {
+    pub fn load() -> io::Result<Self> {
+        Ok(LocalAddrs(
+            get_local_addresses()?
+                .into_iter()
+                .map(canonical_ip)
+                .collect(),
+        ))
+    }
+
+    pub fn is_local(&self, dest: &Destination) -> bool {
+        match dest.host {
+            Address::Ip(ip) => self.is_local_ip(ip),
+            // SOCKS 客户端也可能把 IP 当作域名发送
+            Address::Domain(ref domain) => match domain.parse() {
+                Ok(ip) => self.is_local_ip(ip),
+                Err(_) => {
+                    let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+                    domain == "localhost" || domain.ends_with(".localhost")
+                }
+            },
+        }
+    }
+
+    pub fn is_local_ip(&self, ip: IpAddr) -> bool {
+        let ip = canonical_ip(ip);
+        is_loopback(ip) || ip.is_unspecified() || self.0.contains(&ip)
+    }
+}
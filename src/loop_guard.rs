@@ -0,0 +1,86 @@
+use std::{
+    collections::HashSet,
+    io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{Arc, Mutex},
+};
+
+use crate::addr::canonical_socket_addr;
+use crate::local::LocalAddrs;
+
+// EgressRegistry 记录本进程连接上游时使用的本地地址
+// 如果 REDIRECT 规则没有排除上游地址，代理自己发出的连接会被重定向回监听端口，
+// 此时入站连接的对端地址正好是某个出站连接的本地地址
+// 出站 socket 在 connect 之前 bind 并登记，环路连接可能在 connect 返回之前就被 accept，
+// 这时只知道端口，登记的是通配地址，connect 之后再换成实际的本地地址
+// 通配地址只匹配来自本机地址的对端，其他主机的客户端源端口碰巧相同时不算环路
+#[derive(Debug, Default)]
+pub struct EgressRegistry(Mutex<HashSet<SocketAddr>>);
+
+// EgressGuard 在出站连接关闭时移除登记的地址
+#[derive(Debug)]
+pub struct EgressGuard {
+    registry: Arc<EgressRegistry>,
+    addr: SocketAddr,
+}
+
+impl EgressGuard {
+    // update 将登记的地址换成 connect 之后的实际本地地址
+    pub fn update(&mut self, local: SocketAddr) {
+        let addr = canonical_socket_addr(local);
+        let mut addrs = self.registry.0.lock().unwrap();
+        addrs.remove(&self.addr);
+        addrs.insert(addr);
+        self.addr = addr;
+    }
+}
+
+impl Drop for EgressGuard {
+    fn drop(&mut self) {
+        self.registry.0.lock().unwrap().remove(&self.addr);
+    }
+}
+
+impl EgressRegistry {
+    pub fn register(self: &Arc<Self>, local: SocketAddr) -> EgressGuard {
+        let addr = canonical_socket_addr(local);
+        self.0.lock().unwrap().insert(addr);
+        EgressGuard {
+            registry: self.clone(),
+            addr,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // contains 对端地址是否是本进程某个出站连接的本地地址，local 为本机地址
+    pub fn contains(&self, peer: &SocketAddr, local: &LocalAddrs) -> bool {
+        let addrs = self.0.lock().unwrap();
+        let port = peer.port();
+        addrs.contains(&canonical_socket_addr(*peer))
+            || (local.is_local_ip(peer.ip())
+                && (addrs.contains(&(Ipv4Addr::UNSPECIFIED, port).into())
+                    || addrs.contains(&(Ipv6Addr::UNSPECIFIED, port).into())))
+    }
+
+    // check 拒绝来自本进程出站连接的入站连接
+    pub fn check(&self, peer: &SocketAddr, local: &LocalAddrs) -> io::Result<()> {
+        if self.contains(peer, local) {
+            return Err(io::Error::new(
+                io::ErrorKind::ConnectionRefused,
+                format!(
+                    "forwarding loop detected: {} is one of our own upstream connections, \
+                     exclude the upstream address (or --egress-mark) from the redirect rules",
+                    peer
+                ),
+            ));
+        }
+        Ok(())
+    }
+}
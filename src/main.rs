@@ -1,28 +1,166 @@
 use std::{
-    io::{self, Write},
+    fmt, fs,
+    io::Write,
     net::{IpAddr, SocketAddr},
+    num::{NonZeroU16, NonZeroU64},
+    path::Path,
+    process,
+    str::FromStr,
     sync::Arc,
-    u16,
+    time::Duration,
 };
 
-use clap::{load_yaml, AppSettings};
-use log::{error, info, LevelFilter};
-use socket_proxy::{client::Client, config::Config};
-use tokio::net::{TcpListener, TcpStream};
+use clap::{load_yaml, AppSettings, ArgMatches};
+use log::{debug, error, info, warn, LevelFilter};
+use socket_proxy::{
+    accounting::{Accounting, FsyncPolicy},
+    config::{Config, ConfigFile, Permits},
+    deny::DenyList,
+    firewall::FirewallExclusion,
+    hooks::{HookTarget, Hooks},
+    linux::set_defer_accept,
+    listener::{ListenerConfig, Role},
+    local::LocalAddrs,
+    metrics,
+    nat64::Nat64,
+    outbound::{OutboundConfig, OutboundKind, WhenFull},
+    pinning::DnsPinning,
+    report::ShutdownReport,
+    rules::{Cidr, NoSniPolicy, Rules},
+    server::ProxyServer,
+    tls, trace,
+    tuning::{mbit_to_bytes, BufferTuning},
+};
+use tokio::{
+    net::TcpListener,
+    signal::{
+        ctrl_c,
+        unix::{signal, SignalKind},
+    },
+    time::timeout,
+};
+
+// 关闭时等待 hook 队列投递完的最长时间
+const HOOK_FLUSH_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Fatal 导致进程退出的错误，不同的类别使用不同的退出码，便于 supervisor 和脚本区分处理
+enum Fatal {
+    // 命令行参数无法解析，clap 的错误信息已经包含用法
+    Usage(String),
+    // 命令行参数或配置文件错误，重启也不会恢复
+    Config(String),
+    // 监听端口失败，通常是端口被占用或没有权限
+    Bind(String),
+    // 运行期间的错误
+    Runtime(String),
+}
+
+impl Fatal {
+    fn code(&self) -> i32 {
+        match self {
+            Fatal::Runtime(_) => 1,
+            Fatal::Usage(_) | Fatal::Config(_) => 2,
+            Fatal::Bind(_) => 3,
+        }
+    }
+}
+
+impl fmt::Display for Fatal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Fatal::Usage(msg) => write!(f, "{}", msg),
+            Fatal::Config(msg) => write!(f, "configuration error: {}", msg),
+            Fatal::Bind(msg) => write!(f, "bind error: {}", msg),
+            Fatal::Runtime(msg) => write!(f, "runtime error: {}", msg),
+        }
+    }
+}
+
+// parse_arg 解析可选的参数，错误信息中带上参数名和原始值
+fn parse_arg<T>(app: &ArgMatches, name: &str) -> Result<Option<T>, Fatal>
+where
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    app.value_of(name)
+        .map(|value| {
+            value
+                .parse()
+                .map_err(|err| Fatal::Config(format!("invalid --{} {:?}: {}", name, value, err)))
+        })
+        .transpose()
+}
+
+// required_arg 解析有默认值或必填的参数
+fn required_arg<T>(app: &ArgMatches, name: &str) -> Result<T, Fatal>
+where
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    parse_arg(app, name)?.ok_or_else(|| Fatal::Config(format!("missing --{}", name)))
+}
+
+// cidr_args 解析可以重复指定的 CIDR 参数
+fn cidr_args(app: &ArgMatches, name: &str) -> Result<Vec<Cidr>, Fatal> {
+    app.values_of(name)
+        .into_iter()
+        .flatten()
+        .map(|cidr| {
+            cidr.parse::<Cidr>()
+                .map_err(|err| Fatal::Config(format!("invalid --{}: {}", name, err)))
+        })
+        .collect()
+}
+
+async fn bind(addr: SocketAddr, what: &str, deny: &DenyList) -> Result<TcpListener, Fatal> {
+    let listener = TcpListener::bind(addr)
+        .await
+        .map_err(|err| Fatal::Bind(format!("failed to bind {} {}: {}", what, addr, err)))?;
+    if !deny.is_empty() {
+        if let Err(err) = deny.attach(&listener) {
+            warn!(
+                "failed to attach source filter to {} {}, denied sources are checked after accept: {}",
+                what, addr, err
+            );
+        }
+    }
+    Ok(listener)
+}
 
 #[tokio::main]
 async fn main() {
+    if let Err(err) = run().await {
+        // 日志同样输出到 stderr，只输出一次，日志级别关闭时也能看到
+        eprintln!("socket_proxy: {}", err);
+        process::exit(err.code());
+    }
+}
+
+async fn run() -> Result<(), Fatal> {
     let yaml = load_yaml!("./cli.yaml");
-    let app = clap::App::from_yaml(&yaml)
+    let app = match clap::App::from_yaml(yaml)
         .setting(AppSettings::ColoredHelp)
         .setting(AppSettings::UnifiedHelpMessage)
-        .get_matches();
+        .get_matches_safe()
+    {
+        Ok(app) => app,
+        Err(err) if err.use_stderr() => return Err(Fatal::Usage(err.message)),
+        // --help 和 --version
+        Err(err) => err.exit(),
+    };
     let mut logger = env_logger::Builder::new();
-    let log_level: &str = app.value_of("log-level").expect("need log level");
+    let log_level: LevelFilter = required_arg(&app, "log-level")?;
+    // --emit-config - 时 stdout 留给配置文件
+    let target = match app.value_of("emit-config") {
+        Some("-") => env_logger::Target::Stderr,
+        _ => env_logger::Target::Stdout,
+    };
     logger
-        .filter(None, log_level.parse().expect("unknown log level"))
+        .filter(None, log_level)
         .filter_module("tokio_net", LevelFilter::Warn)
-        .target(env_logger::Target::Stdout)
+        // 规则打开 trace 的连接总是输出
+        .filter_module(trace::TARGET, LevelFilter::Debug)
+        .target(target)
         .format(|buf, r| {
             writeln!(
                 buf,
@@ -36,46 +174,380 @@ async fn main() {
         .init();
     info!("start");
 
-    let host: IpAddr = app
-        .value_of("host")
-        .expect("missing host")
-        .parse()
-        .expect("invalid address");
-    let port: usize = app
-        .value_of("port")
-        .expect("missing port")
-        .parse()
-        .expect("invalid port number");
-    let socks_proxy_server: SocketAddr = app
-        .value_of("socks5")
-        .expect("missing socks5 server address")
-        .parse()
-        .expect("invalid socks5 address");
+    let host: IpAddr = required_arg(&app, "host")?;
+    // 端口 0 会监听随机端口或连接失败，都不是有效的配置
+    let port = required_arg::<NonZeroU16>(&app, "port")?.get();
+    let acceptor = match app.value_of("tls-cert") {
+        Some(cert) => {
+            let key = app
+                .value_of("tls-key")
+                .ok_or_else(|| Fatal::Config("--tls-cert requires --tls-key".into()))?;
+            let acceptor = tls::server::build_acceptor(
+                Path::new(cert),
+                Path::new(key),
+                app.value_of("tls-client-ca").map(Path::new),
+            )
+            .map_err(|err| Fatal::Config(format!("failed to load tls certificate: {}", err)))?;
+            Some(acceptor)
+        }
+        None => None,
+    };
+    let mut config_file = match app.value_of("config") {
+        Some(path) => ConfigFile::load(Path::new(path))
+            .map_err(|err| Fatal::Config(format!("failed to load config file {}", err)))?,
+        None => ConfigFile::default(),
+    };
+    migrate_legacy_flags(&app, &mut config_file, host, port)?;
+    if let Some(path) = app.value_of("emit-config") {
+        return emit_config(&config_file, path);
+    }
+    if config_file.outbounds.is_empty() {
+        return Err(Fatal::Config(
+            "missing upstream: add [[outbounds]] to the config file or pass --socks5".into(),
+        ));
+    }
+    let outbounds = config_file
+        .outbounds
+        .iter()
+        .map(OutboundConfig::to_outbound)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(Fatal::Config)?;
+    let rules = config_file
+        .rules(app.value_of("profile"))
+        .map_err(|err| Fatal::Config(err.to_string()))?;
+    for rule in &rules {
+        debug!("rule {:?}", rule);
+    }
+    // queue 的出口在名额释放前一直等待，只有时间预算能让它放弃
+    if app.value_of("establish-timeout").is_none() {
+        for outbound in &outbounds {
+            if outbound.limits.when_full == WhenFull::Queue {
+                warn!(
+                    "outbound {} queues when full but --establish-timeout is not set, connections without a rule establish_timeout_ms wait until a slot frees up",
+                    outbound.name
+                );
+            }
+        }
+    }
+    // no_sni 引用的出口必须存在
+    let no_sni: NoSniPolicy = required_arg(&app, "no-sni")?;
+    let no_sni_policies = rules
+        .iter()
+        .filter_map(|rule| rule.no_sni.as_deref())
+        .map(|policy| policy.parse::<NoSniPolicy>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|err| Fatal::Config(format!("invalid rule: {}", err)))?;
+    for policy in no_sni_policies.iter().chain([&no_sni]) {
+        if let NoSniPolicy::Outbound(name) = policy {
+            if !outbounds.iter().any(|outbound| &outbound.name == name) {
+                return Err(Fatal::Config(format!(
+                    "no_sni refers to unknown outbound {}",
+                    name
+                )));
+            }
+        }
+    }
+    let rules = Rules::from_config(&rules)
+        .map_err(|err| Fatal::Config(format!("invalid rule: {}", err)))?;
+    let dns_pinning: Option<DnsPinning> = parse_arg(&app, "dns-pinning")?;
+    if dns_pinning == Some(DnsPinning::Hint) && !app.is_present("upstream-peer") {
+        return Err(Fatal::Config(
+            "--dns-pinning hint requires --upstream-peer".into(),
+        ));
+    }
+    let mut nat64: Option<Nat64> = parse_arg(&app, "nat64-prefix")?;
+    if let Some(ref mut nat64) = nat64 {
+        nat64.synthesize = app.is_present("nat64-synthesize");
+    }
+    let bandwidth = parse_arg::<u64>(&app, "upstream-bandwidth")?
+        .map(|mbit| {
+            mbit_to_bytes(mbit).ok_or_else(|| {
+                Fatal::Config(format!(
+                    "invalid --upstream-bandwidth: {} is too large",
+                    mbit
+                ))
+            })
+        })
+        .transpose()?;
+    let trusted_peers = cidr_args(&app, "trusted-peer")?;
+    let echo_sources = cidr_args(&app, "echo-source")?;
+    let deny_sources = DenyList::new(cidr_args(&app, "deny-source")?);
+    // 指令数超出限制时无法挂载，直接报配置错误
+    deny_sources
+        .program()
+        .map_err(|err| Fatal::Config(format!("invalid --deny-source: {}", err)))?;
+    let hook_rate: u32 = required_arg(&app, "hook-rate")?;
+    let hooks =
+        parse_arg::<HookTarget>(&app, "hook")?.map(|target| Hooks::spawn(target, hook_rate));
+    let fsync: FsyncPolicy = required_arg(&app, "accounting-fsync")?;
+    let accounting = match app.value_of("accounting-journal") {
+        Some(path) => Some(Accounting::open(Path::new(path), fsync).map_err(|err| {
+            Fatal::Runtime(format!(
+                "failed to open accounting journal {}: {}",
+                path, err
+            ))
+        })?),
+        None => None,
+    };
+    if let Some(ref accounting) = accounting {
+        let secs: u64 = required_arg(&app, "accounting-interval")?;
+        tokio::spawn(accounting.clone().run(Duration::from_secs(secs.max(1))));
+    }
     let config = Arc::new(Config {
-        socket5_server: socks_proxy_server,
+        outbounds,
+        happy_path: Default::default(),
+        outbound_health: Default::default(),
+        outbound_limits: Default::default(),
         host,
         port,
+        rules,
+        upstream_peer: app.is_present("upstream-peer"),
+        trusted_peers,
+        echo_sources,
+        tuning: BufferTuning::new(
+            bandwidth,
+            parse_arg(&app, "upstream-sndbuf")?,
+            parse_arg(&app, "upstream-rcvbuf")?,
+        ),
+        egress_mark: parse_arg(&app, "egress-mark")?,
+        egress: Default::default(),
+        nat64,
+        dns_pinning,
+        local_policy: required_arg(&app, "local-dest")?,
+        establish_timeout: parse_arg::<u64>(&app, "establish-timeout")?.map(Duration::from_secs),
+        handshake_timeout: Duration::from_secs(
+            required_arg::<NonZeroU64>(&app, "handshake-timeout")?.get(),
+        ),
+        original_dst_fallback: required_arg(&app, "original-dst-fallback")?,
+        capabilities: Default::default(),
+        sniff_limit: parse_arg::<Permits>(&app, "max-sniffing")?.map(Permits::semaphore),
+        deny_sources,
+        hooks,
+        probe_response: required_arg(&app, "probe-response")?,
+        no_sni,
+        accounting,
+        local_addrs: LocalAddrs::load()
+            .map_err(|err| Fatal::Runtime(format!("failed to list local addresses: {}", err)))?,
     });
+    if let Some(secs) = parse_arg::<u64>(&app, "metrics-interval")? {
+        tokio::spawn(metrics::report(Duration::from_secs(secs.max(1))));
+    }
+    let max_connections = parse_arg::<Permits>(&app, "max-connections")?;
+    let hold: u64 = required_arg(&app, "saturation-warn")?;
+    let drain_timeout: u64 = required_arg(&app, "drain-timeout")?;
+    let server = ProxyServer::new(config.clone(), max_connections);
+    // 接受连接的任务，退出时先停止它们再等待现有连接结束
+    let mut accepting = Vec::new();
+    // watch 采样 accept 队列的所有监听端口
+    let mut watched = Vec::new();
+    if let Some(ws_port) = parse_arg::<NonZeroU16>(&app, "ws-port")? {
+        let path: Arc<str> = app
+            .value_of("ws-path")
+            .ok_or_else(|| Fatal::Config("missing --ws-path".into()))?
+            .into();
+        let ws_addr = SocketAddr::new(host, ws_port.get());
+        let ws_listener = Arc::new(bind(ws_addr, "websocket port", &config.deny_sources).await?);
+        info!("websocket listen on {}", ws_addr);
+        watched.push(ws_listener.clone());
+        accepting.push(tokio::spawn(server.clone().serve_websocket(
+            ws_listener,
+            server.register_listener("websocket".into(), Role::Socks),
+            path,
+        )));
+    }
+    let listener_configs = config_file.listeners;
     // 开始监听
-    let addr = SocketAddr::new(host, port as u16);
-    let listener = TcpListener::bind(&addr).await.expect("failed to bind port");
-    info!("listen on {}", addr);
-    while let Ok((socks, _addr)) = listener.accept().await {
-        let result = handle_client(socks, config.clone()).await;
-        if let Err(err) = result {
-            error!("handle client error {}", err);
+    let mut listeners = Vec::new();
+    for listener_config in &listener_configs {
+        let addr = listener_config.listen;
+        let tls = match (listener_config.tls, &acceptor) {
+            (false, _) => None,
+            (true, _) if listener_config.role == Role::Redirect => {
+                return Err(Fatal::Config(format!(
+                    "listener {}: redirect listeners can't use tls",
+                    listener_config.name()
+                )))
+            }
+            (true, Some(acceptor)) => Some(acceptor.clone()),
+            (true, None) => {
+                return Err(Fatal::Config(format!(
+                    "listener {}: tls requires --tls-cert",
+                    listener_config.name()
+                )))
+            }
+        };
+        let what = format!("listener {}", listener_config.name());
+        let listener = Arc::new(bind(addr, &what, &config.deny_sources).await?);
+        let defer_accept = listener_config.defer_accept();
+        if defer_accept > 0 {
+            if let Err(err) = set_defer_accept(&*listener, defer_accept) {
+                warn!(
+                    "failed to set TCP_DEFER_ACCEPT on {} {}: {}",
+                    what, addr, err
+                );
+            }
+        }
+        info!(
+            "listen on {} role={}{}",
+            addr,
+            listener_config.role,
+            if tls.is_some() { " (tls)" } else { "" }
+        );
+        let stats = server.register_listener(listener_config.name(), listener_config.role);
+        watched.push(listener.clone());
+        listeners.push((listener, stats, tls));
+    }
+    tokio::spawn(metrics::watch(
+        watched,
+        max_connections.map(|n| n.get() as u64),
+        Duration::from_secs(hold),
+    ));
+    let mut firewall = Vec::new();
+    if let Some(chain) = app.value_of("manage-firewall") {
+        for outbound in &config.outbounds {
+            if let OutboundKind::Socks5(upstream) = outbound.kind {
+                let mut exclusion = FirewallExclusion::new(chain, upstream);
+                if let Err(err) = exclusion.install().await {
+                    remove_exclusions(&mut firewall).await;
+                    return Err(Fatal::Runtime(format!(
+                        "failed to exclude upstream from firewall: {}",
+                        err
+                    )));
+                }
+                firewall.push(exclusion);
+            }
         }
     }
+    for (listener, stats, acceptor) in listeners {
+        accepting.push(tokio::spawn(
+            server.clone().serve_listener(listener, stats, acceptor),
+        ));
+    }
+    let signal = shutdown_signal().await;
+    info!("received {}, shutting down", signal);
+    // 关闭过程卡住时 (例如 hook 命令不退出)，再次收到信号直接退出
+    tokio::spawn(async {
+        let signal = shutdown_signal().await;
+        warn!(
+            "received {} again, exiting without finishing shutdown",
+            signal
+        );
+        process::exit(1);
+    });
+    for task in &accepting {
+        task.abort();
+    }
+    // 超时后仍在进行的连接被中止，中止时照常记账和投递 close 事件
+    let force_closed = server.drain(Duration::from_secs(drain_timeout)).await;
+    remove_exclusions(&mut firewall).await;
+    if let Some(ref accounting) = config.accounting {
+        if let Err(err) = accounting.flush() {
+            error!("failed to write accounting journal: {}", err);
+        }
+    }
+    if let Some(ref hooks) = config.hooks {
+        if timeout(HOOK_FLUSH_TIMEOUT, hooks.flush()).await.is_err() {
+            warn!("hook events still queued after {:?}", HOOK_FLUSH_TIMEOUT);
+        }
+    }
+    let report = ShutdownReport {
+        signal: signal.into(),
+        snapshot: server.snapshot(),
+        force_closed,
+    };
+    info!("shutdown report {}", report);
+    if let Some(path) = app.value_of("shutdown-report") {
+        fs::write(path, report.to_json() + "\n").map_err(|err| {
+            Fatal::Runtime(format!("failed to write shutdown report {}: {}", path, err))
+        })?;
+    }
+    Ok(())
 }
 
-async fn handle_client(peer_left: TcpStream, config: Arc<Config>) -> io::Result<()> {
-    let mut client = Client::from_socket(peer_left, config).await?;
-    let remote = if client.dest.port == 443 {
-        client = client.retrieve_dest().await?;
-        client.connect_remote_server().await?
-    } else {
-        client.connect_remote_server().await?
-    };
-    client.do_pipe(remote).await?;
+async fn remove_exclusions(firewall: &mut [FirewallExclusion]) {
+    for exclusion in firewall {
+        if let Err(err) = exclusion.remove().await {
+            error!("failed to remove firewall exclusion: {}", err);
+        }
+    }
+}
+
+// migrate_legacy_flags 把 --host/--port/--socks5 映射到配置文件的 listeners 和 outbounds，
+// 显式使用这些参数时输出废弃告警
+// 配置文件没有指定监听端口，或者命令行显式指定了 --host/--port 时监听命令行的地址
+fn migrate_legacy_flags(
+    app: &ArgMatches,
+    config_file: &mut ConfigFile,
+    host: IpAddr,
+    port: u16,
+) -> Result<(), Fatal> {
+    for (flag, replacement) in [
+        ("host", "[[listeners]] listen"),
+        ("port", "[[listeners]] listen"),
+        ("socks5", "[[outbounds]] socks5"),
+    ] {
+        if app.occurrences_of(flag) > 0 {
+            warn!(
+                "deprecated flag=--{} value={} replacement=\"{}\" hint=\"--emit-config writes the equivalent config file\"",
+                flag,
+                app.value_of(flag).unwrap_or_default(),
+                replacement
+            );
+        }
+    }
+    let explicit = app.occurrences_of("host") > 0 || app.occurrences_of("port") > 0;
+    if config_file.listeners.is_empty() || explicit {
+        config_file.listeners.insert(
+            0,
+            ListenerConfig {
+                name: None,
+                listen: SocketAddr::new(host, port),
+                role: Role::Auto,
+                tls: app.is_present("tls-cert"),
+                defer_accept: None,
+            },
+        );
+    }
+    if let Some(socks5) = parse_arg::<SocketAddr>(app, "socks5")? {
+        if !config_file.outbounds.is_empty() {
+            return Err(Fatal::Config(
+                "--socks5 conflicts with [[outbounds]] in the config file".into(),
+            ));
+        }
+        config_file.outbounds.push(OutboundConfig {
+            socks5: Some(socks5),
+            ..Default::default()
+        });
+    }
     Ok(())
 }
+
+// emit_config 输出合并了命令行参数的配置文件，include 已经展开，path 为 - 时输出到 stdout
+fn emit_config(config_file: &ConfigFile, path: &str) -> Result<(), Fatal> {
+    let content = toml::to_string(config_file)
+        .map_err(|err| Fatal::Runtime(format!("failed to serialize config: {}", err)))?;
+    if path == "-" {
+        print!("{}", content);
+        return Ok(());
+    }
+    fs::write(path, content)
+        .map_err(|err| Fatal::Runtime(format!("failed to write config {}: {}", path, err)))?;
+    info!("wrote config to {}", path);
+    Ok(())
+}
+
+// shutdown_signal 等待 SIGINT 或 SIGTERM，返回信号名
+async fn shutdown_signal() -> &'static str {
+    let mut term = match signal(SignalKind::terminate()) {
+        Ok(term) => term,
+        Err(err) => {
+            error!("failed to listen for SIGTERM: {}", err);
+            let _ = ctrl_c().await;
+            return "SIGINT";
+        }
+    };
+    tokio::select! {
+        _ = ctrl_c() => "SIGINT",
+        _ = term.recv() => "SIGTERM",
+    }
+}
@@ -0,0 +1,405 @@
+use std::{
+    fmt, io,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use log::{info, warn};
+use tokio::{
+    net::TcpListener,
+    time::{interval, Instant},
+};
+
+use crate::conn_id::ConnId;
+use crate::linux::get_accept_queue;
+use crate::listener::ListenerStats;
+use crate::protocols::detect::Protocol;
+
+// Counter 既可作为只增的计数器，也可作为可增减的 gauge 使用
+#[derive(Debug, Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub const fn new() -> Self {
+        Counter(AtomicU64::new(0))
+    }
+
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn dec(&self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    pub fn set(&self, n: u64) {
+        self.0.store(n, Ordering::Relaxed);
+    }
+
+    // track 将 gauge 加一，返回的 guard 在 drop 时减一，future 被取消时也能正确恢复
+    pub fn track(&'static self) -> Tracked {
+        self.inc();
+        Tracked(self)
+    }
+}
+
+pub struct Tracked(&'static Counter);
+
+impl Drop for Tracked {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+// Exemplar 记录最近一次计入某个计数器的连接，从指标可以直接找到对应连接的日志
+#[derive(Debug, Default)]
+pub struct Exemplar(AtomicU64);
+
+impl Exemplar {
+    pub const fn new() -> Self {
+        Exemplar(AtomicU64::new(0))
+    }
+
+    pub fn record(&self, id: ConnId) {
+        self.0.store(id.get(), Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> Option<ConnId> {
+        match self.0.load(Ordering::Relaxed) {
+            0 => None,
+            id => Some(id.into()),
+        }
+    }
+}
+
+impl fmt::Display for Exemplar {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.get() {
+            Some(id) => write!(f, "({})", id),
+            None => Ok(()),
+        }
+    }
+}
+
+// ProtocolStats 按识别出的协议统计的连接数和字节数
+pub struct ProtocolStats {
+    pub connections: Counter,
+    // 客户端到上游
+    pub bytes_up: Counter,
+    // 上游到客户端
+    pub bytes_down: Counter,
+}
+
+impl ProtocolStats {
+    const fn new() -> Self {
+        ProtocolStats {
+            connections: Counter::new(),
+            bytes_up: Counter::new(),
+            bytes_down: Counter::new(),
+        }
+    }
+
+    fn bytes(&self) -> u64 {
+        self.bytes_up.get() + self.bytes_down.get()
+    }
+}
+
+// ErrorStats 按类别统计的连接错误数
+pub struct ErrorStats {
+    // 握手、嗅探或建立连接超时
+    pub timeout: Counter,
+    // 被规则、策略或上游拒绝
+    pub refused: Counter,
+    // 对方关闭或重置连接
+    pub reset: Counter,
+    // 客户端或上游发送了无法解析的数据
+    pub protocol: Counter,
+    pub other: Counter,
+}
+
+impl ErrorStats {
+    const fn new() -> Self {
+        ErrorStats {
+            timeout: Counter::new(),
+            refused: Counter::new(),
+            reset: Counter::new(),
+            protocol: Counter::new(),
+            other: Counter::new(),
+        }
+    }
+
+    pub fn record(&self, err: &io::Error) {
+        let counter = match err.kind() {
+            io::ErrorKind::TimedOut => &self.timeout,
+            io::ErrorKind::ConnectionRefused | io::ErrorKind::PermissionDenied => &self.refused,
+            io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::BrokenPipe
+            | io::ErrorKind::UnexpectedEof => &self.reset,
+            io::ErrorKind::InvalidData | io::ErrorKind::InvalidInput => &self.protocol,
+            _ => &self.other,
+        };
+        counter.inc();
+    }
+
+    // all 返回各类别的名字和错误数
+    pub fn all(&self) -> [(&'static str, u64); 5] {
+        [
+            ("timeout", self.timeout.get()),
+            ("refused", self.refused.get()),
+            ("reset", self.reset.get()),
+            ("protocol", self.protocol.get()),
+            ("other", self.other.get()),
+        ]
+    }
+}
+
+pub struct Metrics {
+    // 当前的客户端连接数
+    pub connections: Counter,
+    // 正在进行入站握手 (TLS、SOCKS5、WebSocket) 的连接数
+    pub handshakes: Counter,
+    // 正在连接上游并握手的连接数
+    pub upstream_connects: Counter,
+    // 正在嗅探 SNI 的连接数，以及因达到 --max-sniffing 而等待的连接数
+    pub sniffing: Counter,
+    pub sniff_waiters: Counter,
+    // 因达到 --max-connections 而等待的 accept 数
+    pub permit_waiters: Counter,
+    // 监听 socket 的 accept 队列长度和上限，由 watch 采样
+    pub accept_queue: Counter,
+    pub accept_queue_max: Counter,
+    // accept 之后才发现来自 --deny-source 网段的连接数 (过滤器没有挂上时)
+    pub denied_sources: Counter,
+    // --hook 因队列满或超出速率丢弃的事件数，以及投递失败的事件数
+    pub hook_dropped: Counter,
+    pub hook_failures: Counter,
+    // 443 端口嗅探不到 SNI 的连接数，以及其中被拒绝的连接数
+    pub no_sni: Counter,
+    pub no_sni_rejected: Counter,
+    // 先尝试目的地上次成功的出口的次数，以及出口失败后换下一个出口的次数
+    pub happy_path_hits: Counter,
+    pub outbound_failovers: Counter,
+    // 出口达到连接数上限而被跳过或等待的次数
+    pub outbound_full: Counter,
+    // 超出建立连接时间预算而放弃的连接数
+    pub establish_timeouts: Counter,
+    pub establish_timeouts_last: Exemplar,
+    // 规则匹配的次数和总耗时
+    pub rule_lookups: Counter,
+    pub rule_lookup_ns: Counter,
+    // 当前处于各模式的 BiPipe 数量
+    pub pipes_interactive: Counter,
+    pub pipes_bulk: Counter,
+    // BiPipe 切换到各模式的次数
+    pub switches_to_normal: Counter,
+    pub switches_to_interactive: Counter,
+    pub switches_to_bulk: Counter,
+    // 连接结束时的错误
+    pub errors: ErrorStats,
+    pub tls: ProtocolStats,
+    pub http: ProtocolStats,
+    pub ssh: ProtocolStats,
+    pub unknown: ProtocolStats,
+}
+
+pub static METRICS: Metrics = Metrics {
+    connections: Counter::new(),
+    handshakes: Counter::new(),
+    upstream_connects: Counter::new(),
+    sniffing: Counter::new(),
+    sniff_waiters: Counter::new(),
+    permit_waiters: Counter::new(),
+    accept_queue: Counter::new(),
+    accept_queue_max: Counter::new(),
+    denied_sources: Counter::new(),
+    hook_dropped: Counter::new(),
+    hook_failures: Counter::new(),
+    no_sni: Counter::new(),
+    no_sni_rejected: Counter::new(),
+    happy_path_hits: Counter::new(),
+    outbound_failovers: Counter::new(),
+    outbound_full: Counter::new(),
+    establish_timeouts: Counter::new(),
+    establish_timeouts_last: Exemplar::new(),
+    rule_lookups: Counter::new(),
+    rule_lookup_ns: Counter::new(),
+    pipes_interactive: Counter::new(),
+    pipes_bulk: Counter::new(),
+    switches_to_normal: Counter::new(),
+    switches_to_interactive: Counter::new(),
+    switches_to_bulk: Counter::new(),
+    errors: ErrorStats::new(),
+    tls: ProtocolStats::new(),
+    http: ProtocolStats::new(),
+    ssh: ProtocolStats::new(),
+    unknown: ProtocolStats::new(),
+};
+
+impl Metrics {
+    pub fn protocol(&self, protocol: Protocol) -> &ProtocolStats {
+        match protocol {
+            Protocol::Tls => &self.tls,
+            Protocol::Http => &self.http,
+            Protocol::Ssh => &self.ssh,
+            Protocol::Unknown => &self.unknown,
+        }
+    }
+}
+
+impl fmt::Display for Metrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "connections={} handshakes={} upstream_connects={} sniffing={} sniff_waiters={} permit_waiters={} \
+             accept_queue={}/{} denied_sources={} hook_dropped={} hook_failures={} no_sni={} no_sni_rejected={} happy_path_hits={} outbound_failovers={} outbound_full={} establish_timeouts={}{} rule_lookups={} rule_lookup_avg_ns={} pipes_interactive={} pipes_bulk={} switches_to_normal={} \
+             switches_to_interactive={} switches_to_bulk={}",
+            self.connections.get(),
+            self.handshakes.get(),
+            self.upstream_connects.get(),
+            self.sniffing.get(),
+            self.sniff_waiters.get(),
+            self.permit_waiters.get(),
+            self.accept_queue.get(),
+            self.accept_queue_max.get(),
+            self.denied_sources.get(),
+            self.hook_dropped.get(),
+            self.hook_failures.get(),
+            self.no_sni.get(),
+            self.no_sni_rejected.get(),
+            self.happy_path_hits.get(),
+            self.outbound_failovers.get(),
+            self.outbound_full.get(),
+            self.establish_timeouts.get(),
+            self.establish_timeouts_last,
+            self.rule_lookups.get(),
+            self.rule_lookup_ns
+                .get()
+                .checked_div(self.rule_lookups.get())
+                .unwrap_or(0),
+            self.pipes_interactive.get(),
+            self.pipes_bulk.get(),
+            self.switches_to_normal.get(),
+            self.switches_to_interactive.get(),
+            self.switches_to_bulk.get(),
+        )?;
+        for (class, count) in self.errors.all() {
+            write!(f, " errors_{}={}", class, count)?;
+        }
+        // 各协议的连接数、字节数和字节占比
+        let total: u64 = Protocol::ALL
+            .iter()
+            .map(|&p| self.protocol(p).bytes())
+            .sum();
+        for protocol in Protocol::ALL {
+            let stats = self.protocol(protocol);
+            write!(
+                f,
+                " {}=conns:{},up:{},down:{},share:{}%",
+                protocol,
+                stats.connections.get(),
+                stats.bytes_up.get(),
+                stats.bytes_down.get(),
+                (stats.bytes() * 100).checked_div(total).unwrap_or(0)
+            )?;
+        }
+        // 各监听端口当前和累计的连接数
+        for listener in ListenerStats::all() {
+            write!(
+                f,
+                " listener:{}=role:{},active:{},accepted:{}",
+                listener.name,
+                listener.role,
+                listener.active.get(),
+                listener.accepted.get()
+            )?;
+        }
+        Ok(())
+    }
+}
+
+// report 周期性地将指标输出到日志
+pub async fn report(period: Duration) {
+    let mut ticker = interval(period);
+    ticker.tick().await;
+    loop {
+        ticker.tick().await;
+        info!("metrics {}", METRICS);
+    }
+}
+
+// fullest_accept_queue 返回最满的监听端口的 accept 队列长度和上限
+pub fn fullest_accept_queue(listeners: &[Arc<TcpListener>]) -> (u64, u64) {
+    let mut queue = (0, 0);
+    for listener in listeners {
+        if let Ok((len, max)) = get_accept_queue(listener.as_ref()) {
+            let (len, max) = (len as u64, max as u64);
+            if max > 0 && len * queue.1 >= queue.0 * max {
+                queue = (len, max);
+            }
+        }
+    }
+    queue
+}
+
+// watch 每秒采样一次 accept 队列，某一项持续饱和超过 hold 时输出告警，恢复后再输出一次
+// 入站握手或上游连接占用超过一半的连接数上限也视为饱和，说明上游或客户端很慢
+pub async fn watch(listeners: Vec<Arc<TcpListener>>, max_connections: Option<u64>, hold: Duration) {
+    let mut since: [Option<Instant>; 6] = Default::default();
+    let mut warned = [false; 6];
+    let mut ticker = interval(Duration::from_secs(1));
+    loop {
+        ticker.tick().await;
+        let queue = fullest_accept_queue(&listeners);
+        METRICS.accept_queue.set(queue.0);
+        METRICS.accept_queue_max.set(queue.1);
+        let max = max_connections.unwrap_or(u64::MAX);
+        let checks = [
+            (
+                "accept queue",
+                METRICS.accept_queue_max.get() > 0
+                    && METRICS.accept_queue.get() >= METRICS.accept_queue_max.get(),
+            ),
+            ("connection limit", METRICS.connections.get() >= max),
+            ("connection limit waiters", METRICS.permit_waiters.get() > 0),
+            ("sniff limit waiters", METRICS.sniff_waiters.get() > 0),
+            ("inbound handshakes", METRICS.handshakes.get() > max / 2),
+            (
+                "upstream connects",
+                METRICS.upstream_connects.get() > max / 2,
+            ),
+        ];
+        let now = Instant::now();
+        for (i, (name, saturated)) in checks.into_iter().enumerate() {
+            if !saturated {
+                if warned[i] {
+                    info!("{} recovered: {}", name, METRICS);
+                }
+                since[i] = None;
+                warned[i] = false;
+                continue;
+            }
+            let start = *since[i].get_or_insert(now);
+            if !warned[i] && now - start >= hold {
+                warn!(
+                    "{} saturated for {}s: {}",
+                    name,
+                    (now - start).as_secs(),
+                    METRICS
+                );
+                warned[i] = true;
+            }
+        }
+    }
+}
@@ -0,0 +1,103 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    str::FromStr,
+};
+
+use crate::addr::canonical_ip;
+use crate::client::{Address, Destination};
+
+// Nat64 按 RFC 6052 在 IPv4 地址和嵌入了 IPv4 地址的 IPv6 地址之间转换
+// 只有 v6 的客户端通过 DNS64 拿到的是 prefix + IPv4 的合成地址，转换回 IPv4 后由上游直接连接；
+// synthesize 打开时反过来把 IPv4 目的地合成为 IPv6，适用于只有 v6 出口的上游
+#[derive(Debug, Clone, Copy)]
+pub struct Nat64 {
+    prefix: Ipv6Addr,
+    // 前缀长度 (字节)，只能是 4, 5, 6, 7, 8, 12
+    len: usize,
+    pub synthesize: bool,
+}
+
+impl FromStr for Nat64 {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = s
+            .split_once('/')
+            .ok_or_else(|| format!("missing prefix length {}", s))?;
+        let prefix_addr: Ipv6Addr = addr
+            .parse()
+            .map_err(|_| format!("invalid nat64 prefix {}", s))?;
+        let len = match prefix {
+            "32" => 4,
+            "40" => 5,
+            "48" => 6,
+            "56" => 7,
+            "64" => 8,
+            "96" => 12,
+            _ => {
+                return Err(format!(
+                    "nat64 prefix length must be 32, 40, 48, 56, 64 or 96: {}",
+                    s
+                ))
+            }
+        };
+        let mut octets = prefix_addr.octets();
+        octets[len..].iter_mut().for_each(|b| *b = 0);
+        Ok(Nat64 {
+            prefix: octets.into(),
+            len,
+            synthesize: false,
+        })
+    }
+}
+
+// IPv4 地址在 IPv6 地址中占用的字节下标，跳过保留的第 8 字节 (bits 64-71)
+fn v4_positions(len: usize) -> impl Iterator<Item = usize> {
+    (len..16).filter(|&i| i != 8).take(4)
+}
+
+impl Nat64 {
+    pub fn embed(&self, ip: Ipv4Addr) -> Ipv6Addr {
+        let mut octets = self.prefix.octets();
+        for (i, b) in v4_positions(self.len).zip(ip.octets()) {
+            octets[i] = b;
+        }
+        octets.into()
+    }
+
+    pub fn extract(&self, ip: Ipv6Addr) -> Option<Ipv4Addr> {
+        let octets = ip.octets();
+        if octets[..self.len] != self.prefix.octets()[..self.len] {
+            return None;
+        }
+        let mut v4 = [0u8; 4];
+        for (b, i) in v4.iter_mut().zip(v4_positions(self.len)) {
+            *b = octets[i];
+        }
+        Some(v4.into())
+    }
+
+    // translate 返回转换后的目的地址，不需要转换时返回 None
+    pub fn translate(&self, ip: IpAddr) -> Option<IpAddr> {
+        match ip {
+            IpAddr::V4(v4) if self.synthesize => Some(IpAddr::V6(self.embed(v4))),
+            IpAddr::V6(v6) if !self.synthesize => self.extract(v6).map(IpAddr::V4),
+            _ => None,
+        }
+    }
+}
+
+// translate_destination 统一目的地的地址族
+// IPv4-mapped 地址总是还原为 IPv4，配置了 nat64 时再按前缀转换
+pub fn translate_destination(dest: &mut Destination, nat64: Option<&Nat64>) -> bool {
+    let ip = match dest.host {
+        Address::Ip(ip) => ip,
+        Address::Domain(_) => return false,
+    };
+    let unmapped = canonical_ip(ip);
+    let translated = nat64
+        .and_then(|nat64| nat64.translate(unmapped))
+        .unwrap_or(unmapped);
+    dest.host = Address::Ip(translated);
+    translated != ip
+}
@@ -0,0 +1,62 @@
+use std::{io, net::SocketAddr, str::FromStr};
+
+use tokio::net::TcpStream;
+
+use crate::linux::{get_original_address_v4, get_original_address_v6};
+
+// OriginalDstFallback SO_ORIGINAL_DST 查询失败时的处理方式
+// 查询失败而客户端又不是在做 SOCKS5 握手，说明是 conntrack 记录已经失效的转发连接，
+// 此时不能当作直接连接监听端口处理，按 v6 查询、SNI 嗅探的顺序尝试，都不行就拒绝
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OriginalDstFallback {
+    // IPv4 查询失败后再查询一次 IPv6 (ip6tables 转发的连接)
+    pub v6: bool,
+    // 用 TLS ClientHello 中的 SNI 作为目的地，端口只能假定为 443
+    pub sni: bool,
+}
+
+impl Default for OriginalDstFallback {
+    fn default() -> Self {
+        OriginalDstFallback {
+            v6: true,
+            sni: true,
+        }
+    }
+}
+
+// 格式为逗号分隔的 v6,sni,reject，顺序固定，reject 总是最后一步可以省略
+impl FromStr for OriginalDstFallback {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut fallback = OriginalDstFallback {
+            v6: false,
+            sni: false,
+        };
+        let mut rejected = false;
+        for step in s.split(',').map(str::trim) {
+            if rejected {
+                return Err(format!("reject must be the last step: {}", s));
+            }
+            match step {
+                "v6" if !fallback.v6 && !fallback.sni => fallback.v6 = true,
+                "sni" if !fallback.sni => fallback.sni = true,
+                "reject" => rejected = true,
+                "v6" | "sni" => return Err(format!("steps must be in order v6,sni,reject: {}", s)),
+                _ => return Err(format!("unknown original destination fallback {}", step)),
+            }
+        }
+        Ok(fallback)
+    }
+}
+
+impl OriginalDstFallback {
+    // lookup 查询转发前的原始目的地，v6 关闭时只查询 IPv4
+    pub fn lookup(&self, stream: &TcpStream) -> io::Result<SocketAddr> {
+        match get_original_address_v4(stream) {
+            Ok(addr) => Ok(SocketAddr::V4(addr)),
+            Err(err) if !self.v6 => Err(err),
+            Err(_) => get_original_address_v6(stream).map(SocketAddr::V6),
+        }
+    }
+}
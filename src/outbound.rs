@@ -0,0 +1,333 @@
+use std::{
+    collections::HashMap,
+    fmt, io,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::config::Permits;
+use crate::shaper::RateLimit;
+use crate::tuning::mbit_to_bytes;
+
+// 缓存的目的地数量上限，超出时先清理过期的记录，仍然超出时不再记录新的目的地
+const HAPPY_PATH_CAPACITY: usize = 16384;
+// 记录的有效期，过期后重新按配置顺序尝试，恢复的首选上游可以重新被使用
+const HAPPY_PATH_TTL: Duration = Duration::from_secs(600);
+
+fn is_false(value: &bool) -> bool {
+    !value
+}
+
+// OutboundConfig 配置文件中的一个出口，socks5 和 direct 二选一
+// 多个出口按顺序尝试，前一个连接或握手失败时使用下一个
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct OutboundConfig {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub socks5: Option<SocketAddr>,
+    // 不经过上游直接连接目的地
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub direct: bool,
+    // 同时经过这个出口的连接数上限
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_connections: Option<usize>,
+    // 所有经过这个出口的连接合计的带宽上限 (Mbit/s)，两个方向的流量都计入
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_bandwidth_mbit: Option<u64>,
+    // 达到连接数上限时的处理方式
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub when_full: Option<WhenFull>,
+}
+
+// WhenFull 出口达到连接数上限时的处理方式
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WhenFull {
+    // 跳过这个出口，尝试下一个，没有下一个时拒绝连接
+    #[default]
+    Failover,
+    // 等待其他连接结束，受建立连接的时间预算限制
+    // 没有 --establish-timeout 或规则的 establish_timeout_ms 时一直等待
+    Queue,
+}
+
+// OutboundLimits 一个出口的连接数和带宽上限
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct OutboundLimits {
+    pub max_connections: Option<Permits>,
+    // 每秒的字节数
+    pub max_bandwidth: Option<u64>,
+    pub when_full: WhenFull,
+}
+
+impl OutboundConfig {
+    pub fn name(&self) -> String {
+        match (&self.name, self.socks5) {
+            (Some(name), _) => name.clone(),
+            (None, Some(addr)) => addr.to_string(),
+            (None, None) => "direct".into(),
+        }
+    }
+
+    pub fn to_outbound(&self) -> Result<Outbound, String> {
+        let kind = match (self.socks5, self.direct) {
+            (Some(addr), false) if addr.port() == 0 => {
+                return Err(format!("outbound {}: port must not be 0", self.name()))
+            }
+            (Some(addr), false) => OutboundKind::Socks5(addr),
+            (None, true) => OutboundKind::Direct,
+            _ => {
+                return Err(format!(
+                    "outbound {}: exactly one of socks5 and direct is required",
+                    self.name()
+                ))
+            }
+        };
+        let max_connections = self
+            .max_connections
+            .map(Permits::new)
+            .transpose()
+            .map_err(|err| format!("outbound {}: max_connections {}", self.name(), err))?;
+        let max_bandwidth = match self.max_bandwidth_mbit {
+            Some(0) => {
+                return Err(format!(
+                    "outbound {}: max_bandwidth_mbit must not be 0",
+                    self.name()
+                ))
+            }
+            Some(mbit) => Some(mbit_to_bytes(mbit).ok_or_else(|| {
+                format!("outbound {}: max_bandwidth_mbit is too large", self.name())
+            })?),
+            None => None,
+        };
+        if self.when_full.is_some() && self.max_connections.is_none() {
+            return Err(format!(
+                "outbound {}: when_full requires max_connections",
+                self.name()
+            ));
+        }
+        Ok(Outbound {
+            name: self.name(),
+            kind,
+            limits: OutboundLimits {
+                max_connections,
+                max_bandwidth,
+                when_full: self.when_full.unwrap_or_default(),
+            },
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutboundKind {
+    Socks5(SocketAddr),
+    Direct,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Outbound {
+    pub name: String,
+    pub kind: OutboundKind,
+    pub limits: OutboundLimits,
+}
+
+impl Outbound {
+    pub fn socks5(addr: SocketAddr) -> Self {
+        Outbound {
+            name: addr.to_string(),
+            kind: OutboundKind::Socks5(addr),
+            limits: OutboundLimits::default(),
+        }
+    }
+}
+
+impl fmt::Display for Outbound {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.name)
+    }
+}
+
+// HappyPathCache 记录每个目的地最近一次成功的出口，下次连接先尝试它
+// 只记录不是第一个出口的情况，首选出口正常时缓存为空
+#[derive(Default)]
+pub struct HappyPathCache(Mutex<HashMap<String, (usize, Instant)>>);
+
+impl HappyPathCache {
+    // order 返回尝试出口的顺序，缓存命中的出口排在最前面，其余保持配置顺序
+    pub fn order(&self, dest: &str, count: usize) -> Vec<usize> {
+        let cached = {
+            let mut entries = self.0.lock().unwrap();
+            match entries.get(dest) {
+                Some(&(index, at)) if index < count && at.elapsed() < HAPPY_PATH_TTL => Some(index),
+                Some(_) => {
+                    entries.remove(dest);
+                    None
+                }
+                None => None,
+            }
+        };
+        let mut order: Vec<usize> = (0..count).collect();
+        if let Some(index) = cached {
+            order.remove(index);
+            order.insert(0, index);
+        }
+        order
+    }
+
+    // record 记录成功的出口
+    pub fn record(&self, dest: &str, index: usize) {
+        let mut entries = self.0.lock().unwrap();
+        if index == 0 {
+            entries.remove(dest);
+            return;
+        }
+        if entries.len() >= HAPPY_PATH_CAPACITY && !entries.contains_key(dest) {
+            entries.retain(|_, (_, at)| at.elapsed() < HAPPY_PATH_TTL);
+            if entries.len() >= HAPPY_PATH_CAPACITY {
+                return;
+            }
+        }
+        entries.insert(dest.to_owned(), (index, Instant::now()));
+    }
+
+    // forget 缓存的出口失败时删除记录，之后按配置顺序重新尝试
+    pub fn forget(&self, dest: &str, index: usize) {
+        let mut entries = self.0.lock().unwrap();
+        if entries
+            .get(dest)
+            .is_some_and(|&(cached, _)| cached == index)
+        {
+            entries.remove(dest);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+// OutboundHealth 一个出口的连接结果，连接被时间预算取消时不计入
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct OutboundHealth {
+    pub connects: u64,
+    pub failures: u64,
+    // 最近一次成功之后连续失败的次数，为 0 表示最近一次连接成功
+    pub consecutive_failures: u64,
+    pub last_error: Option<String>,
+}
+
+// OutboundHealthMap 按出口名字记录连接结果
+#[derive(Default)]
+pub struct OutboundHealthMap(Mutex<HashMap<String, OutboundHealth>>);
+
+impl OutboundHealthMap {
+    pub fn record_success(&self, name: &str) {
+        let mut entries = self.0.lock().unwrap();
+        let health = entries.entry(name.to_owned()).or_default();
+        health.connects += 1;
+        health.consecutive_failures = 0;
+    }
+
+    pub fn record_failure(&self, name: &str, err: &io::Error) {
+        let mut entries = self.0.lock().unwrap();
+        let health = entries.entry(name.to_owned()).or_default();
+        health.connects += 1;
+        health.failures += 1;
+        health.consecutive_failures += 1;
+        health.last_error = Some(err.to_string());
+    }
+
+    pub fn get(&self, name: &str) -> OutboundHealth {
+        self.0
+            .lock()
+            .unwrap()
+            .get(name)
+            .cloned()
+            .unwrap_or_default()
+    }
+}
+
+// OutboundLimiter 一个出口的连接数信号量和共享的令牌桶
+pub struct OutboundLimiter {
+    connections: Option<Arc<Semaphore>>,
+    max_connections: usize,
+    bandwidth: Option<Arc<RateLimit>>,
+}
+
+impl OutboundLimiter {
+    fn new(limits: &OutboundLimits) -> Self {
+        OutboundLimiter {
+            connections: limits.max_connections.map(|n| Arc::new(n.semaphore())),
+            max_connections: limits.max_connections.map_or(0, Permits::get),
+            bandwidth: limits
+                .max_bandwidth
+                .map(|rate| Arc::new(RateLimit::new(rate))),
+        }
+    }
+
+    // acquire 占用一个连接名额，连接结束时释放；Failover 时名额已满返回 ConnectionRefused
+    pub async fn acquire(&self, when_full: WhenFull) -> io::Result<Option<OwnedSemaphorePermit>> {
+        let connections = match self.connections {
+            Some(ref connections) => connections,
+            None => return Ok(None),
+        };
+        let full = || {
+            io::Error::new(
+                io::ErrorKind::ConnectionRefused,
+                format!("connection limit {} reached", self.max_connections),
+            )
+        };
+        match connections.clone().try_acquire_owned() {
+            Ok(permit) => Ok(Some(permit)),
+            Err(_) if when_full == WhenFull::Queue => connections
+                .clone()
+                .acquire_owned()
+                .await
+                .map(Some)
+                .map_err(|_| full()),
+            Err(_) => Err(full()),
+        }
+    }
+
+    pub fn bandwidth(&self) -> Option<Arc<RateLimit>> {
+        self.bandwidth.clone()
+    }
+
+    // active 当前经过这个出口的连接数，没有连接数上限时不统计
+    pub fn active(&self) -> Option<u64> {
+        self.connections
+            .as_ref()
+            .map(|connections| (self.max_connections - connections.available_permits()) as u64)
+    }
+}
+
+// OutboundLimiters 按出口名字保存限制的状态，第一次使用时按出口的配置创建
+#[derive(Default)]
+pub struct OutboundLimiters(Mutex<HashMap<String, Arc<OutboundLimiter>>>);
+
+impl OutboundLimiters {
+    // get 出口没有配置任何上限时返回 None
+    pub fn get(&self, outbound: &Outbound) -> Option<Arc<OutboundLimiter>> {
+        if outbound.limits.max_connections.is_none() && outbound.limits.max_bandwidth.is_none() {
+            return None;
+        }
+        Some(
+            self.0
+                .lock()
+                .unwrap()
+                .entry(outbound.name.clone())
+                .or_insert_with(|| Arc::new(OutboundLimiter::new(&outbound.limits)))
+                .clone(),
+        )
+    }
+}
@@ -0,0 +1,58 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+};
+
+use log::{debug, warn};
+use tokio::net::lookup_host;
+
+use crate::addr::canonical_ip;
+use crate::conn_id::ConnId;
+
+// DnsPinning 用 SNI 替换目的地时如何处理客户端原本连接的 IP
+// hint: 通过 peer 元数据把原始 IP 发给 socket_proxy 上游，由上游直接连接该 IP 而不是重新解析
+// verify: 仍由上游解析域名，本地异步解析一次 SNI，结果中不包含原始 IP 时记录日志
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DnsPinning {
+    Hint,
+    Verify,
+}
+
+impl FromStr for DnsPinning {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "hint" => Ok(DnsPinning::Hint),
+            "verify" => Ok(DnsPinning::Verify),
+            _ => Err(format!("unknown dns pinning mode {}", s)),
+        }
+    }
+}
+
+// verify 检查 SNI 的解析结果是否包含客户端原本连接的 IP
+// 不一致不一定是攻击 (CDN、GeoDNS 都会返回不同的地址)，只记录下来供审查
+pub async fn verify(id: ConnId, src: SocketAddr, sni: Box<str>, original: SocketAddr) {
+    let resolved: Vec<IpAddr> = match lookup_host((sni.as_ref(), original.port())).await {
+        Ok(addrs) => addrs.map(|addr| canonical_ip(addr.ip())).collect(),
+        Err(err) => {
+            debug!(
+                "{} {} dns pinning, failed to resolve {}: {}",
+                id, src, sni, err
+            );
+            return;
+        }
+    };
+    let original_ip = canonical_ip(original.ip());
+    if resolved.contains(&original_ip) {
+        debug!(
+            "{} {} dns pinning, {} resolves to {}",
+            id, src, sni, original_ip
+        );
+        return;
+    }
+    warn!(
+        "{} {} dns pinning discrepancy: sni {} resolves to {:?} but the client connected to {}",
+        id, src, sni, resolved, original_ip
+    );
+}
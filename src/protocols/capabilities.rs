@@ -0,0 +1,263 @@
+use std::{
+    collections::HashMap,
+    fmt, io,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use log::{info, warn};
+
+use crate::protocols::peer::PeerHello;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    time::timeout,
+};
+
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+const METHOD_NO_AUTH: u8 = 0x00;
+const METHOD_USER_PASS: u8 = 0x02;
+const CMD_CONNECT: u8 = 0x01;
+const CMD_UDP_ASSOCIATE: u8 = 0x03;
+// 探测结果的有效期，过期后重新探测，上游升级或更换后随之更新
+pub const CAPABILITY_TTL: Duration = Duration::from_secs(600);
+
+// Capabilities 上游 SOCKS5 服务端支持的功能
+// 第一次连接某个上游时在后台探测，之后的握手据此调整
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Capabilities {
+    pub no_auth: bool,
+    pub user_pass: bool,
+    // 上游是否选择 socket_proxy 私有方法，只有在握手中提供过该方法才知道
+    pub peer: Option<bool>,
+    // 和 socket_proxy 上游协商的协议版本和功能
+    pub peer_hello: Option<PeerHello>,
+    pub udp: bool,
+    // 不等待 method 选择回复就发送请求，可以省掉一个往返
+    pub pipelining: bool,
+}
+
+fn yes_no(value: bool) -> &'static str {
+    if value {
+        "yes"
+    } else {
+        "no"
+    }
+}
+
+impl fmt::Display for Capabilities {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "no_auth={} user_pass={} peer={}",
+            yes_no(self.no_auth),
+            yes_no(self.user_pass),
+            self.peer.map_or("unknown", yes_no),
+        )?;
+        if let Some(hello) = self.peer_hello {
+            write!(
+                f,
+                " peer_version={} peer_features={:#x}",
+                hello.version, hello.features
+            )?;
+        }
+        write!(
+            f,
+            " udp={} pipelining={}",
+            yes_no(self.udp),
+            yes_no(self.pipelining)
+        )
+    }
+}
+
+enum Entry {
+    // 探测期间握手得到的 peer 结果先记在这里
+    Probing(Option<bool>, Option<PeerHello>),
+    // 探测结果和开始探测的时间
+    Known(Capabilities, Instant),
+}
+
+// CapabilityCache 按上游地址缓存探测结果
+pub struct CapabilityCache {
+    entries: Mutex<HashMap<SocketAddr, Entry>>,
+    ttl: Duration,
+}
+
+impl Default for CapabilityCache {
+    fn default() -> Self {
+        CapabilityCache::with_ttl(CAPABILITY_TTL)
+    }
+}
+
+impl CapabilityCache {
+    pub fn with_ttl(ttl: Duration) -> Self {
+        CapabilityCache {
+            entries: Mutex::default(),
+            ttl,
+        }
+    }
+
+    // get 返回已知的能力，还在探测时返回 None，过期后重新探测期间仍返回旧结果
+    pub fn get(&self, upstream: SocketAddr) -> Option<Capabilities> {
+        match self.entries.lock().unwrap().get(&upstream) {
+            Some(Entry::Known(caps, _)) => Some(*caps),
+            _ => None,
+        }
+    }
+
+    // detect 第一次连接上游或结果过期时在后台开始探测，探测失败时忘掉该上游，下次连接再探测
+    pub fn detect(self: &Arc<Self>, upstream: SocketAddr) {
+        {
+            let mut entries = self.entries.lock().unwrap();
+            match entries.get_mut(&upstream) {
+                Some(Entry::Probing(..)) => return,
+                Some(Entry::Known(_, checked)) if checked.elapsed() < self.ttl => return,
+                // 先更新时间，避免重新探测期间的连接重复探测
+                Some(Entry::Known(_, checked)) => *checked = Instant::now(),
+                None => {
+                    entries.insert(upstream, Entry::Probing(None, None));
+                }
+            }
+        }
+        let cache = self.clone();
+        tokio::spawn(async move {
+            let started = Instant::now();
+            match probe(upstream).await {
+                Ok(caps) => {
+                    let mut entries = cache.entries.lock().unwrap();
+                    // peer 结果只能从握手中得到，重新探测时保留
+                    let (peer, peer_hello) = match entries.get(&upstream) {
+                        Some(Entry::Probing(peer, hello)) => (*peer, *hello),
+                        Some(Entry::Known(known, _)) => (known.peer, known.peer_hello),
+                        None => (None, None),
+                    };
+                    let caps = Capabilities {
+                        peer,
+                        peer_hello,
+                        ..caps
+                    };
+                    info!("upstream {} capabilities {}", upstream, caps);
+                    entries.insert(upstream, Entry::Known(caps, started));
+                }
+                Err(err) => {
+                    warn!(
+                        "failed to probe upstream {} capabilities: {}",
+                        upstream, err
+                    );
+                    cache.entries.lock().unwrap().remove(&upstream);
+                }
+            }
+        });
+    }
+
+    // record_peer 记录握手中上游是否选择了私有方法和协商结果，变化时输出日志
+    // 上游升级或降级后协商结果随之更新
+    pub fn record_peer(&self, upstream: SocketAddr, hello: Option<PeerHello>) {
+        let selected = hello.is_some();
+        match self.entries.lock().unwrap().get_mut(&upstream) {
+            Some(Entry::Probing(peer, peer_hello)) => {
+                *peer = Some(selected);
+                *peer_hello = hello;
+            }
+            Some(Entry::Known(caps, _))
+                if caps.peer != Some(selected) || caps.peer_hello != hello =>
+            {
+                caps.peer = Some(selected);
+                caps.peer_hello = hello;
+                info!("upstream {} capabilities {}", upstream, caps);
+            }
+            _ => (),
+        }
+    }
+}
+
+async fn connect(upstream: SocketAddr) -> io::Result<TcpStream> {
+    timeout(PROBE_TIMEOUT, TcpStream::connect(upstream))
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "connect timed out"))?
+}
+
+// probe_method 只提供一种认证方法，上游选择了它就说明支持
+async fn probe_method(upstream: SocketAddr, method: u8) -> io::Result<bool> {
+    let mut stream = connect(upstream).await?;
+    stream.write_all(&[0x05, 0x01, method]).await?;
+    let mut reply = [0u8; 2];
+    match timeout(PROBE_TIMEOUT, stream.read_exact(&mut reply)).await {
+        Ok(Ok(_)) => Ok(reply == [0x05, method]),
+        // 不支持的方法也可能直接关闭连接
+        Ok(Err(err)) if err.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Ok(Err(err)) => Err(err),
+        Err(_) => Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "greeting timed out",
+        )),
+    }
+}
+
+// exchange 发送 greeting (只提供无认证) 和请求，返回请求回复中的 REP
+// pipelined 时 greeting 和请求一起发送，不等待 method 选择回复
+async fn exchange(upstream: SocketAddr, request: &[u8], pipelined: bool) -> io::Result<u8> {
+    let mut stream = connect(upstream).await?;
+    let greeting = [0x05, 0x01, METHOD_NO_AUTH];
+    let exchange = async {
+        let mut reply = [0u8; 2];
+        if pipelined {
+            stream.write_all(&[&greeting[..], request].concat()).await?;
+            stream.read_exact(&mut reply).await?;
+        } else {
+            stream.write_all(&greeting).await?;
+            stream.read_exact(&mut reply).await?;
+            stream.write_all(request).await?;
+        }
+        if reply != [0x05, METHOD_NO_AUTH] {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "unexpected method selected",
+            ));
+        }
+        let mut reply = [0u8; 2];
+        stream.read_exact(&mut reply).await?;
+        if reply[0] != 0x05 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "unexpected reply version",
+            ));
+        }
+        Ok(reply[1])
+    };
+    timeout(PROBE_TIMEOUT, exchange)
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "request timed out"))?
+}
+
+// probe_pipelining 用和实际握手相同的方式流水线发送 CONNECT
+// 目的地 0.0.0.0:0 不会真的建立连接，收到任何 REP 都说明上游正确处理了和 greeting 一起到达的请求
+// 丢弃多余数据的上游会一直等待请求直到超时，或者关闭连接，都按不支持处理
+async fn probe_pipelining(upstream: SocketAddr) -> bool {
+    let request = [0x05, CMD_CONNECT, 0x00, 0x01, 0, 0, 0, 0, 0, 0];
+    exchange(upstream, &request, true).await.is_ok()
+}
+
+// probe_udp 发送 UDP ASSOCIATE 请求，上游回复成功说明支持
+async fn probe_udp(upstream: SocketAddr) -> bool {
+    let request = [0x05, CMD_UDP_ASSOCIATE, 0x00, 0x01, 0, 0, 0, 0, 0, 0];
+    matches!(exchange(upstream, &request, false).await, Ok(0x00))
+}
+
+// probe 探测上游支持的认证方法、UDP ASSOCIATE 和流水线握手
+// 私有方法不主动探测 (选择后需要完成子协商)，由握手时 record_peer 记录
+pub async fn probe(upstream: SocketAddr) -> io::Result<Capabilities> {
+    let mut caps = Capabilities {
+        no_auth: probe_method(upstream, METHOD_NO_AUTH).await?,
+        user_pass: probe_method(upstream, METHOD_USER_PASS).await?,
+        ..Default::default()
+    };
+    if !caps.no_auth {
+        return Ok(caps);
+    }
+    caps.pipelining = probe_pipelining(upstream).await;
+    // 不支持 UDP ASSOCIATE 的上游可能直接关闭连接，同样按不支持处理
+    caps.udp = probe_udp(upstream).await;
+    Ok(caps)
+}
@@ -0,0 +1,58 @@
+use std::fmt;
+
+// Protocol 根据客户端发送的第一段数据识别的应用层协议，只用于统计
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    Tls,
+    Http,
+    Ssh,
+    Unknown,
+}
+
+impl Protocol {
+    pub const ALL: [Protocol; 4] = [
+        Protocol::Tls,
+        Protocol::Http,
+        Protocol::Ssh,
+        Protocol::Unknown,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Protocol::Tls => "tls",
+            Protocol::Http => "http",
+            Protocol::Ssh => "ssh",
+            Protocol::Unknown => "unknown",
+        }
+    }
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+const HTTP_METHODS: &[&[u8]] = &[
+    b"GET ",
+    b"POST ",
+    b"PUT ",
+    b"HEAD ",
+    b"DELETE ",
+    b"OPTIONS ",
+    b"PATCH ",
+    b"CONNECT ",
+    b"TRACE ",
+    b"PRI * HTTP/2",
+];
+
+// detect 只看开头几个字节，数据不完整时按 Unknown 处理
+pub fn detect(data: &[u8]) -> Protocol {
+    match data {
+        // handshake record, SSLv3/TLS 1.x
+        [0x16, 0x03, ..] => Protocol::Tls,
+        _ if data.starts_with(b"SSH-") => Protocol::Ssh,
+        _ if HTTP_METHODS.iter().any(|method| data.starts_with(method)) => Protocol::Http,
+        _ => Protocol::Unknown,
+    }
+}
@@ -1,3 +1,6 @@
+pub mod capabilities;
+pub mod detect;
+pub mod peer;
 pub mod socks5;
 
-pub use self::socks5::handshake;
+pub use self::socks5::{handshake, handshake_pipelined, handshake_traced};
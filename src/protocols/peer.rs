@@ -0,0 +1,255 @@
+use std::io::{self, ErrorKind};
+use std::net::{IpAddr, SocketAddr};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+// socket_proxy 之间使用的 SOCKS5 私有认证方法 (RFC 1928 中 0x80-0xFE 保留给私有方法)
+// 上游选择该方法表示它是 socket_proxy，随后在子协商阶段发送连接的元数据
+// 普通 SOCKS5 服务端不会选择该方法，握手不受影响
+pub const METHOD_PEER: u8 = 0x88;
+const SUBNEGOTIATION_VERSION: u8 = 0x01;
+const STATUS_OK: u8 = 0x00;
+const STATUS_REJECTED: u8 = 0x01;
+// 客户端发送了 TLV_HELLO 时服务端用这个状态表示成功，随后是 LEN 和服务端的 TLVS
+// 旧版本客户端不发送 TLV_HELLO，只会收到 STATUS_OK
+const STATUS_OK_HELLO: u8 = 0x02;
+
+const TLV_DESTINATION: u8 = 0x01;
+const TLV_CLIENT: u8 = 0x02;
+const TLV_SNI: u8 = 0x03;
+const TLV_REQUEST_ID: u8 = 0x04;
+const TLV_PINNED_ADDR: u8 = 0x05;
+const TLV_HELLO: u8 = 0x06;
+
+const PEER_MAGIC: [u8; 4] = *b"SPXY";
+// 1 是没有 TLV_HELLO 的最初版本
+pub const PROTOCOL_VERSION: u16 = 2;
+// 对方会使用 TLV_PINNED_ADDR 直接连接该 IP
+pub const FEATURE_PINNED_ADDR: u32 = 1 << 0;
+// 本地支持的功能，以后的传输方式 (多路复用、UDP、压缩) 各占一位，双方都支持时才启用
+pub const FEATURES: u32 = FEATURE_PINNED_ADDR;
+
+// PeerHello 子协商中交换的协议版本和功能位图
+// +-------+---------+----------+
+// | MAGIC | VERSION | FEATURES |
+// +-------+---------+----------+
+// |   4   |    2    |    4     |
+// +-------+---------+----------+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerHello {
+    pub version: u16,
+    pub features: u32,
+}
+
+impl PeerHello {
+    pub const LOCAL: PeerHello = PeerHello {
+        version: PROTOCOL_VERSION,
+        features: FEATURES,
+    };
+    // LEGACY 只回复 STATUS_OK 的对方，最初版本已经支持 TLV_PINNED_ADDR
+    pub const LEGACY: PeerHello = PeerHello {
+        version: 1,
+        features: FEATURE_PINNED_ADDR,
+    };
+
+    // negotiate 双方都支持的版本和功能
+    pub fn negotiate(self, other: PeerHello) -> PeerHello {
+        PeerHello {
+            version: self.version.min(other.version),
+            features: self.features & other.features,
+        }
+    }
+
+    pub fn supports(self, feature: u32) -> bool {
+        self.features & feature == feature
+    }
+
+    fn encode(self) -> [u8; 10] {
+        let mut buf = [0u8; 10];
+        buf[..4].copy_from_slice(&PEER_MAGIC);
+        buf[4..6].copy_from_slice(&self.version.to_be_bytes());
+        buf[6..].copy_from_slice(&self.features.to_be_bytes());
+        buf
+    }
+
+    fn decode(value: &[u8]) -> io::Result<Self> {
+        if value.len() < 10 || value[..4] != PEER_MAGIC {
+            return Err(invalid("peer metadata, invalid hello"));
+        }
+        // 以后的版本可以在后面追加字段
+        Ok(PeerHello {
+            version: u16::from_be_bytes([value[4], value[5]]),
+            features: u32::from_be_bytes([value[6], value[7], value[8], value[9]]),
+        })
+    }
+}
+
+// PeerMetadata 随连接发送给 socket_proxy 上游的元数据
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct PeerMetadata {
+    pub destination: Option<String>,
+    pub client: Option<SocketAddr>,
+    pub sni: Option<String>,
+    // 下游的连接编号 (ConnId)，上下游的日志可以据此对应起来
+    pub request_id: Option<u64>,
+    // 客户端原本连接的 IP，目的地是嗅探得到的域名时用于 DNS pinning
+    pub pinned: Option<IpAddr>,
+    // 发送方的协议版本和功能，旧版本没有
+    pub hello: Option<PeerHello>,
+}
+
+fn invalid(msg: &'static str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, msg)
+}
+
+fn push_tlv(buf: &mut Vec<u8>, tag: u8, value: &[u8]) {
+    buf.push(tag);
+    buf.extend_from_slice(&(value.len() as u16).to_be_bytes());
+    buf.extend_from_slice(value);
+}
+
+impl PeerMetadata {
+    // +-----+------+-------+
+    // | TAG | LEN  | VALUE |
+    // +-----+------+-------+
+    // |  1  |  2   |  LEN  |
+    // +-----+------+-------+
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        if let Some(ref dest) = self.destination {
+            push_tlv(buf, TLV_DESTINATION, dest.as_bytes());
+        }
+        if let Some(client) = self.client {
+            push_tlv(buf, TLV_CLIENT, client.to_string().as_bytes());
+        }
+        if let Some(ref sni) = self.sni {
+            push_tlv(buf, TLV_SNI, sni.as_bytes());
+        }
+        if let Some(id) = self.request_id {
+            push_tlv(buf, TLV_REQUEST_ID, &id.to_be_bytes());
+        }
+        match self.pinned {
+            Some(IpAddr::V4(ip)) => push_tlv(buf, TLV_PINNED_ADDR, &ip.octets()),
+            Some(IpAddr::V6(ip)) => push_tlv(buf, TLV_PINNED_ADDR, &ip.octets()),
+            None => (),
+        }
+        if let Some(hello) = self.hello {
+            push_tlv(buf, TLV_HELLO, &hello.encode());
+        }
+    }
+
+    // decode 忽略未知的 TAG，便于以后增加字段
+    pub fn decode(mut data: &[u8]) -> io::Result<Self> {
+        let mut meta = PeerMetadata::default();
+        while !data.is_empty() {
+            if data.len() < 3 {
+                return Err(invalid("peer metadata, truncated tlv header"));
+            }
+            let tag = data[0];
+            let len = u16::from_be_bytes([data[1], data[2]]) as usize;
+            let value = data
+                .get(3..3 + len)
+                .ok_or_else(|| invalid("peer metadata, truncated tlv value"))?;
+            data = &data[3 + len..];
+            let text = || {
+                String::from_utf8(value.to_vec())
+                    .map_err(|_| invalid("peer metadata, invalid utf8"))
+            };
+            match tag {
+                TLV_DESTINATION => meta.destination = Some(text()?),
+                TLV_CLIENT => {
+                    meta.client = Some(
+                        text()?
+                            .parse()
+                            .map_err(|_| invalid("peer metadata, invalid client address"))?,
+                    )
+                }
+                TLV_SNI => meta.sni = Some(text()?),
+                TLV_REQUEST_ID => {
+                    let id: [u8; 8] = value
+                        .try_into()
+                        .map_err(|_| invalid("peer metadata, invalid request id"))?;
+                    meta.request_id = Some(u64::from_be_bytes(id));
+                }
+                TLV_PINNED_ADDR => {
+                    meta.pinned = Some(match value.len() {
+                        4 => IpAddr::from(<[u8; 4]>::try_from(value).unwrap()),
+                        16 => IpAddr::from(<[u8; 16]>::try_from(value).unwrap()),
+                        _ => return Err(invalid("peer metadata, invalid pinned address")),
+                    })
+                }
+                TLV_HELLO => meta.hello = Some(PeerHello::decode(value)?),
+                _ => (),
+            }
+        }
+        Ok(meta)
+    }
+}
+
+// send_metadata 客户端一侧的子协商
+// +-----+--------+------+
+// | VER |  LEN   | TLVS |
+// +-----+--------+------+
+// |  1  |   2    | LEN  |
+// +-----+--------+------+
+// 服务端回复 VER STATUS，STATUS 为 STATUS_OK_HELLO 时后面还有 LEN TLVS
+// 返回双方协商的版本和功能
+pub async fn send_metadata<S>(stream: &mut S, meta: &PeerMetadata) -> io::Result<PeerHello>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut tlvs = Vec::new();
+    meta.encode(&mut tlvs);
+    let mut buf = vec![SUBNEGOTIATION_VERSION];
+    buf.extend_from_slice(&(tlvs.len() as u16).to_be_bytes());
+    buf.extend_from_slice(&tlvs);
+    stream.write_all(&buf).await?;
+    let mut reply = [0u8; 2];
+    stream.read_exact(&mut reply).await?;
+    let local = meta.hello.unwrap_or(PeerHello::LEGACY);
+    match reply {
+        [SUBNEGOTIATION_VERSION, STATUS_OK] => Ok(local.negotiate(PeerHello::LEGACY)),
+        [SUBNEGOTIATION_VERSION, STATUS_OK_HELLO] if meta.hello.is_some() => {
+            let len = stream.read_u16().await? as usize;
+            let mut buf = vec![0u8; len];
+            stream.read_exact(&mut buf).await?;
+            let remote = PeerMetadata::decode(&buf)?
+                .hello
+                .ok_or_else(|| invalid("peer metadata, missing hello in reply"))?;
+            Ok(local.negotiate(remote))
+        }
+        _ => Err(io::Error::other("peer rejected metadata")),
+    }
+}
+
+// recv_metadata 服务端一侧的子协商，客户端发送了 hello 时回复本地的 hello
+pub async fn recv_metadata<S>(stream: &mut S) -> io::Result<PeerMetadata>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let ver = stream.read_u8().await?;
+    if ver != SUBNEGOTIATION_VERSION {
+        stream
+            .write_all(&[SUBNEGOTIATION_VERSION, STATUS_REJECTED])
+            .await?;
+        return Err(invalid("peer metadata, unsupported version"));
+    }
+    let len = stream.read_u16().await? as usize;
+    let mut buf = vec![0u8; len];
+    stream.read_exact(&mut buf).await?;
+    let meta = PeerMetadata::decode(&buf);
+    let reply = match meta {
+        Ok(PeerMetadata { hello: Some(_), .. }) => {
+            let mut tlvs = Vec::new();
+            push_tlv(&mut tlvs, TLV_HELLO, &PeerHello::LOCAL.encode());
+            let mut reply = vec![SUBNEGOTIATION_VERSION, STATUS_OK_HELLO];
+            reply.extend_from_slice(&(tlvs.len() as u16).to_be_bytes());
+            reply.extend_from_slice(&tlvs);
+            reply
+        }
+        Ok(_) => vec![SUBNEGOTIATION_VERSION, STATUS_OK],
+        Err(_) => vec![SUBNEGOTIATION_VERSION, STATUS_REJECTED],
+    };
+    stream.write_all(&reply).await?;
+    stream.flush().await?;
+    meta
+}
@@ -1,11 +1,18 @@
 use std::io::{self, ErrorKind};
 use std::net::IpAddr;
+use std::time::Duration;
 
 use log::debug;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::net::TcpStream;
+use tokio::time::timeout;
 
 use crate::client::{Address, Destination};
+use crate::conn_id::ConnId;
+use crate::protocols::peer::{send_metadata, PeerHello, PeerMetadata, METHOD_PEER};
+use crate::trace::Traced;
+
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
 
 macro_rules! err {
     ($msg: expr) => {
@@ -13,26 +20,86 @@ macro_rules! err {
     };
 }
 
+// metadata 不为空时额外提供 socket_proxy 私有方法，上游选择后发送连接元数据
+// 返回上游选择的认证方法，以及上游是 socket_proxy 时协商的版本和功能
 pub async fn handshake<T>(
     remote: &mut TcpStream,
     dest: &Destination,
     data: Option<T>,
-) -> io::Result<()>
+    metadata: Option<&PeerMetadata>,
+) -> io::Result<(u8, Option<PeerHello>)>
+where
+    T: AsRef<[u8]>,
+{
+    run_handshake(remote, dest, data, metadata, false).await
+}
+
+// handshake_traced 和 handshake 相同，另外把收发的每段字节输出到 trace 日志
+pub async fn handshake_traced<T>(
+    remote: &mut TcpStream,
+    dest: &Destination,
+    data: Option<T>,
+    metadata: Option<&PeerMetadata>,
+    id: ConnId,
+) -> io::Result<(u8, Option<PeerHello>)>
+where
+    T: AsRef<[u8]>,
+{
+    let mut traced = Traced::new(remote, id, "upstream");
+    run_handshake(&mut traced, dest, data, metadata, false).await
+}
+
+// handshake_pipelined 不等待 method 选择回复，greeting 和请求一起发送
+// 只能用于已知支持流水线握手的上游，且不能提供私有方法
+pub async fn handshake_pipelined<T>(
+    remote: &mut TcpStream,
+    dest: &Destination,
+    data: Option<T>,
+) -> io::Result<u8>
 where
     T: AsRef<[u8]>,
+{
+    run_handshake(remote, dest, data, None, true)
+        .await
+        .map(|(method, _)| method)
+}
+
+async fn run_handshake<S, T>(
+    remote: &mut S,
+    dest: &Destination,
+    data: Option<T>,
+    metadata: Option<&PeerMetadata>,
+    pipelined: bool,
+) -> io::Result<(u8, Option<PeerHello>)>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    T: AsRef<[u8]>,
 {
     // 执行 socks5 握手🤝
     // https://datatracker.ietf.org/doc/html/rfc1928#section-3
-    do_handshake(remote, dest, data).await?;
-    Ok(())
+    match timeout(
+        HANDSHAKE_TIMEOUT,
+        do_handshake(remote, dest, data, metadata, pipelined),
+    )
+    .await
+    {
+        Ok(result) => result,
+        Err(_) => Err(io::Error::new(
+            ErrorKind::TimedOut,
+            "socks5 handshake with upstream timed out",
+        )),
+    }
 }
 
-async fn do_handshake<T>(
-    remote: &mut TcpStream,
+async fn do_handshake<S, T>(
+    remote: &mut S,
     dest: &Destination,
     data: Option<T>,
-) -> io::Result<()>
+    metadata: Option<&PeerMetadata>,
+    pipelined: bool,
+) -> io::Result<(u8, Option<PeerHello>)>
 where
+    S: AsyncRead + AsyncWrite + Unpin,
     T: AsRef<[u8]>,
 {
     // +----+----------+----------+
@@ -41,24 +108,58 @@ where
     // | 1  |    1     | 1 to 255 |
     // +----+----------+----------+
     // we don't support user auth;
-    remote.write_all(&[0x05, 0x01, 0x00]).await?;
+    let mut request = Vec::new();
+    build_request(&mut request, dest);
+    match metadata {
+        Some(_) => remote.write_all(&[0x05, 0x02, METHOD_PEER, 0x00]).await?,
+        None if pipelined => {
+            let mut buf = vec![0x05, 0x01, 0x00];
+            buf.extend_from_slice(&request);
+            remote.write_all(&buf).await?;
+        }
+        None => remote.write_all(&[0x05, 0x01, 0x00]).await?,
+    }
     let mut buf = vec![0; 2];
     remote.read_exact(&mut buf).await?;
-    match buf[..] {
-        [0x05, 0x00] => (),
-        _ => err!(""),
+    let method = buf[1];
+    let hello = match (&buf[..], metadata) {
+        ([0x05, 0x00], _) => None,
+        ([0x05, METHOD_PEER], Some(metadata)) => {
+            debug!("upstream is a socket_proxy peer, sending metadata");
+            Some(send_metadata(remote, metadata).await?)
+        }
+        (&[ver, _], _) if ver != 0x05 => err!("unexpected greeting version from server"),
+        _ => err!("unexpected method selected by server"),
+    };
+    if !pipelined {
+        remote.write_all(&request).await?;
     }
-    let mut buf = Vec::new();
-    build_request(&mut buf, dest);
-    remote.write_all(&buf).await?;
 
-    let mut buf = vec![0; 10];
-
-    // 0x05,0x00,0x00,0x01,0x00,0x00,0x00,0x00,0x00,0x00
+    // +----+-----+-------+------+----------+----------+
+    // |VER | REP |  RSV  | ATYP | BND.ADDR | BND.PORT |
+    // +----+-----+-------+------+----------+----------+
+    // | 1  |  1  | X'00' |  1   | Variable |    2     |
+    // +----+-----+-------+------+----------+----------+
+    let mut buf = [0u8; 4];
     remote.read_exact(&mut buf).await?;
-    if buf[..2] != [0x05, 0x00] {
-        err!("unexpected reply from server");
+    if buf[0] != 0x05 {
+        err!("unexpected reply version from server");
+    }
+    if buf[1] != 0x00 {
+        err!(format!(
+            "server rejected request with reply {:#04x}",
+            buf[1]
+        ));
     }
+    let addr_len = match buf[3] {
+        0x01 => 4,
+        0x03 => remote.read_u8().await? as usize,
+        0x04 => 16,
+        _ => err!("unknown address type in server reply"),
+    };
+    // BND.ADDR 和 BND.PORT 对 CONNECT 没有用处，读出后丢弃
+    let mut buf = vec![0; addr_len + 2];
+    remote.read_exact(&mut buf).await?;
 
     // 握手执行结束，将数据写回 stream
     if let Some(data) = data {
@@ -66,7 +167,7 @@ where
         remote.write_all(data.as_ref()).await?;
     }
 
-    Ok(())
+    Ok((method, hello))
 }
 
 fn build_request(buf: &mut Vec<u8>, dest: &Destination) {
@@ -75,6 +176,7 @@ fn build_request(buf: &mut Vec<u8>, dest: &Destination) {
     match dest.host {
         Address::Ip(ip) => match ip {
             IpAddr::V4(i) => {
+                buf.push(0x01);
                 // the address is a version-4 IP address, with a length of 4 octets
                 buf.extend_from_slice(&i.octets());
             }
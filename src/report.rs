@@ -0,0 +1,58 @@
+use std::fmt::{self, Write};
+
+use crate::hooks::push_json_str;
+use crate::server::Snapshot;
+
+// ShutdownReport 正常退出时输出的汇总，写入日志，也可以通过 --shutdown-report 写入 JSON 文件
+pub struct ShutdownReport {
+    // 触发退出的信号
+    pub signal: String,
+    pub snapshot: Snapshot,
+    // 等待超时后被强制关闭的连接数
+    pub force_closed: u64,
+}
+
+impl ShutdownReport {
+    // to_json 编码为单行 JSON
+    pub fn to_json(&self) -> String {
+        let snapshot = &self.snapshot;
+        let mut out = String::from("{\"signal\":");
+        push_json_str(&mut out, &self.signal);
+        let _ = write!(
+            out,
+            ",\"uptime_ms\":{},\"connections\":{},\"bytes_up\":{},\"bytes_down\":{},\"errors\":{{",
+            snapshot.uptime.as_millis(),
+            snapshot.accepted,
+            snapshot.bytes_up,
+            snapshot.bytes_down
+        );
+        for (i, (class, count)) in snapshot.errors.iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            push_json_str(&mut out, class);
+            let _ = write!(out, ":{}", count);
+        }
+        let _ = write!(out, "}},\"force_closed\":{}}}", self.force_closed);
+        out
+    }
+}
+
+impl fmt::Display for ShutdownReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let snapshot = &self.snapshot;
+        write!(
+            f,
+            "signal={} uptime={}s connections={} bytes_up={} bytes_down={}",
+            self.signal,
+            snapshot.uptime.as_secs(),
+            snapshot.accepted,
+            snapshot.bytes_up,
+            snapshot.bytes_down
+        )?;
+        for (class, count) in &snapshot.errors {
+            write!(f, " errors_{}={}", class, count)?;
+        }
+        write!(f, " force_closed={}", self.force_closed)
+    }
+}
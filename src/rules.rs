@@ -0,0 +1,452 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    io,
+    net::IpAddr,
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use regex::RegexSet;
+use serde::{Deserialize, Serialize};
+
+use crate::addr::canonical_ip;
+use crate::client::{Address, Destination};
+use crate::metrics::METRICS;
+use crate::wildcard::wildcard_match;
+
+// RuleConfig 配置文件中的一条规则
+// 目的地命中 domains 或 ips 之一，且端口命中 ports 时规则生效，为空的条件不做限制
+#[derive(Debug, Default, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct RuleConfig {
+    // 域名后缀，example.com 同时匹配 example.com 和 www.example.com
+    // 包含 * 或 ? 时按通配符匹配整个域名，*.cdn.example.com 只匹配子域名
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub domains: Vec<String>,
+    // 正则表达式，匹配整个域名 (不区分大小写)，需要自己写 ^ 和 $
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub domain_regex: Vec<String>,
+    // CIDR 或者单个 IP
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub ips: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub ports: Vec<u16>,
+    // 设置到客户端和上游 socket 上的 SO_MARK
+    pub mark: Option<u32>,
+    // 设置到客户端和上游 socket 上的 DSCP (0-63)
+    pub dscp: Option<u8>,
+    // 建立连接 (嗅探、连接上游和握手) 的总时间预算，覆盖 --establish-timeout
+    pub establish_timeout_ms: Option<u64>,
+    // 443 端口嗅探不到 SNI 时的处理方式，覆盖 --no-sni
+    pub no_sni: Option<String>,
+    // 匹配的连接输出握手的原始字节和各阶段耗时，不受 --log-level 限制
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub trace: bool,
+}
+
+fn is_false(value: &bool) -> bool {
+    !value
+}
+
+// NoSniPolicy 443 端口嗅探不到 SNI (没有 SNI、ECH 或者不是 TLS) 时的处理方式
+// 这时只能按 IP 匹配规则，按域名的路由不起作用
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NoSniPolicy {
+    // 按原始 IP 转发
+    Ip,
+    // 拒绝连接
+    Reject,
+    // 只使用指定名字的出口
+    Outbound(String),
+}
+
+// 格式为 ip、reject 或 outbound:NAME
+impl FromStr for NoSniPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ip" => Ok(NoSniPolicy::Ip),
+            "reject" => Ok(NoSniPolicy::Reject),
+            _ => match s.strip_prefix("outbound:") {
+                Some("") => Err(format!("missing outbound name: {}", s)),
+                Some(name) => Ok(NoSniPolicy::Outbound(name.into())),
+                None => Err(format!("unknown no sni policy {}", s)),
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr
+            .parse()
+            .map_err(|_| format!("invalid ip address {}", s))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse()
+                .ok()
+                .filter(|&p| p <= max)
+                .ok_or_else(|| format!("invalid prefix length {}", s))?,
+            None => max,
+        };
+        Ok(Cidr { addr, prefix })
+    }
+}
+
+impl Cidr {
+    pub fn addr(&self) -> IpAddr {
+        self.addr
+    }
+
+    pub fn prefix(&self) -> u8 {
+        self.prefix
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, canonical_ip(ip)) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug)]
+enum DomainMatcher {
+    Suffix(String),
+    Wildcard(String),
+}
+
+impl DomainMatcher {
+    fn new(domain: &str) -> Self {
+        if domain.contains(['*', '?']) {
+            DomainMatcher::Wildcard(domain.trim_end_matches('.').to_ascii_lowercase())
+        } else {
+            DomainMatcher::Suffix(domain.trim_matches('.').to_ascii_lowercase())
+        }
+    }
+
+    fn matches(&self, domain: &str) -> bool {
+        match self {
+            DomainMatcher::Suffix(suffix) => domain_matches(suffix, domain),
+            DomainMatcher::Wildcard(pattern) => wildcard_match(
+                pattern.as_bytes(),
+                domain.trim_end_matches('.').as_bytes(),
+                true,
+            ),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Rule {
+    domains: Vec<DomainMatcher>,
+    // 加载时编译成一个 RegexSet，匹配时只扫描一遍
+    domain_regex: Option<RegexSet>,
+    cidrs: Vec<Cidr>,
+    ports: Vec<u16>,
+    pub mark: Option<u32>,
+    pub dscp: Option<u8>,
+    pub establish_timeout: Option<Duration>,
+    pub no_sni: Option<NoSniPolicy>,
+    pub trace: bool,
+}
+
+fn domain_matches(suffix: &str, domain: &str) -> bool {
+    let domain = domain.trim_end_matches('.');
+    match domain.len().checked_sub(suffix.len()) {
+        Some(0) => domain.eq_ignore_ascii_case(suffix),
+        Some(n) => domain.as_bytes()[n - 1] == b'.' && domain[n..].eq_ignore_ascii_case(suffix),
+        None => false,
+    }
+}
+
+impl Rule {
+    pub fn from_config(config: &RuleConfig) -> io::Result<Self> {
+        let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidInput, msg);
+        let cidrs = config
+            .ips
+            .iter()
+            .map(|ip| ip.parse())
+            .collect::<Result<_, _>>()
+            .map_err(invalid)?;
+        if config.ports.contains(&0) {
+            return Err(invalid("invalid port 0 in rule".into()));
+        }
+        let domain_regex = match config.domain_regex.len() {
+            0 => None,
+            _ => Some(
+                RegexSet::new(config.domain_regex.iter().map(|re| format!("(?i){}", re)))
+                    .map_err(|err| invalid(format!("invalid domain_regex: {}", err)))?,
+            ),
+        };
+        if let Some(dscp) = config.dscp {
+            if dscp > 63 {
+                return Err(invalid(format!("invalid dscp {}, must be 0-63", dscp)));
+            }
+        }
+        Ok(Rule {
+            domains: config
+                .domains
+                .iter()
+                .map(|domain| DomainMatcher::new(domain))
+                .collect(),
+            domain_regex,
+            cidrs,
+            ports: config.ports.clone(),
+            mark: config.mark,
+            dscp: config.dscp,
+            establish_timeout: config.establish_timeout_ms.map(Duration::from_millis),
+            no_sni: config
+                .no_sni
+                .as_deref()
+                .map(str::parse)
+                .transpose()
+                .map_err(invalid)?,
+            trace: config.trace,
+        })
+    }
+
+    fn port_matches(&self, port: u16) -> bool {
+        self.ports.is_empty() || self.ports.contains(&port)
+    }
+
+    fn any_host(&self) -> bool {
+        self.domains.is_empty() && self.domain_regex.is_none() && self.cidrs.is_empty()
+    }
+
+    // pattern_matches 只检查通配符和正则，后缀由 RuleIndex 检查
+    fn pattern_matches(&self, domain: &str) -> bool {
+        self.domains
+            .iter()
+            .any(|m| matches!(m, DomainMatcher::Wildcard(_)) && m.matches(domain))
+            || self
+                .domain_regex
+                .as_ref()
+                .is_some_and(|set| set.is_match(domain.trim_end_matches('.')))
+    }
+
+    fn has_patterns(&self) -> bool {
+        self.domain_regex.is_some()
+            || self
+                .domains
+                .iter()
+                .any(|m| matches!(m, DomainMatcher::Wildcard(_)))
+    }
+
+    pub fn matches(&self, dest: &Destination) -> bool {
+        if !self.port_matches(dest.port) {
+            return false;
+        }
+        if self.any_host() {
+            return true;
+        }
+        match dest.host {
+            Address::Domain(ref domain) => {
+                self.domains.iter().any(|m| m.matches(domain))
+                    || self
+                        .domain_regex
+                        .as_ref()
+                        .is_some_and(|set| set.is_match(domain.trim_end_matches('.')))
+            }
+            Address::Ip(ip) => self.cidrs.iter().any(|cidr| cidr.contains(ip)),
+        }
+    }
+}
+
+// DomainTrie 按标签从右到左组织的域名后缀树，节点上记录以该后缀结尾的规则
+#[derive(Debug, Default)]
+struct DomainTrie {
+    children: HashMap<Box<str>, DomainTrie>,
+    rules: Vec<usize>,
+}
+
+impl DomainTrie {
+    fn insert(&mut self, suffix: &str, rule: usize) {
+        let node = suffix.rsplit('.').fold(self, |node, label| {
+            node.children.entry(label.into()).or_default()
+        });
+        if node.rules.last() != Some(&rule) {
+            node.rules.push(rule);
+        }
+    }
+
+    // lookup 收集 domain 的所有后缀上的规则，耗时只和域名的标签数有关
+    fn lookup(&self, domain: &str, out: &mut Vec<usize>) {
+        let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+        let mut node = self;
+        for label in domain.rsplit('.') {
+            match node.children.get(label) {
+                Some(child) => node = child,
+                None => return,
+            }
+            out.extend_from_slice(&node.rules);
+        }
+    }
+}
+
+// PrefixTable 按前缀长度分组的 CIDR 表，每个长度一次哈希查找
+// 查找次数不超过配置中出现过的前缀长度种数 (IPv4 最多 33 种，IPv6 最多 129 种)
+#[derive(Debug, Default)]
+struct PrefixTable {
+    v4: BTreeMap<u8, HashMap<u32, Vec<usize>>>,
+    v6: BTreeMap<u8, HashMap<u128, Vec<usize>>>,
+}
+
+fn mask_v4(ip: u32, prefix: u8) -> u32 {
+    ip & u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0)
+}
+
+fn mask_v6(ip: u128, prefix: u8) -> u128 {
+    ip & u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0)
+}
+
+impl PrefixTable {
+    fn insert(&mut self, cidr: &Cidr, rule: usize) {
+        let rules = match cidr.addr {
+            IpAddr::V4(net) => self
+                .v4
+                .entry(cidr.prefix)
+                .or_default()
+                .entry(mask_v4(net.into(), cidr.prefix))
+                .or_default(),
+            IpAddr::V6(net) => self
+                .v6
+                .entry(cidr.prefix)
+                .or_default()
+                .entry(mask_v6(net.into(), cidr.prefix))
+                .or_default(),
+        };
+        if rules.last() != Some(&rule) {
+            rules.push(rule);
+        }
+    }
+
+    fn lookup(&self, ip: IpAddr, out: &mut Vec<usize>) {
+        match canonical_ip(ip) {
+            IpAddr::V4(ip) => {
+                for (&prefix, nets) in &self.v4 {
+                    if let Some(rules) = nets.get(&mask_v4(ip.into(), prefix)) {
+                        out.extend_from_slice(rules);
+                    }
+                }
+            }
+            IpAddr::V6(ip) => {
+                for (&prefix, nets) in &self.v6 {
+                    if let Some(rules) = nets.get(&mask_v6(ip.into(), prefix)) {
+                        out.extend_from_slice(rules);
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Rules 按配置顺序匹配，第一条匹配的规则生效
+// 加载时为域名后缀和 CIDR 建立索引，匹配时先从索引中找出候选规则再按顺序检查端口，
+// 只有通配符、正则需要逐条检查，规则集很大 (数万个域名) 时每次匹配的耗时也不随之增长
+#[derive(Debug, Default)]
+pub struct Rules {
+    rules: Vec<Arc<Rule>>,
+    domains: DomainTrie,
+    cidrs: PrefixTable,
+    // 不限制目的地的规则
+    any_host: Vec<usize>,
+    // 有通配符或正则的规则
+    patterns: Vec<usize>,
+}
+
+impl Rules {
+    pub fn from_config(configs: &[RuleConfig]) -> io::Result<Self> {
+        let mut rules = Rules::default();
+        for (i, config) in configs.iter().enumerate() {
+            let rule = Rule::from_config(config)?;
+            for matcher in &rule.domains {
+                if let DomainMatcher::Suffix(suffix) = matcher {
+                    rules.domains.insert(suffix, i);
+                }
+            }
+            for cidr in &rule.cidrs {
+                rules.cidrs.insert(cidr, i);
+            }
+            if rule.any_host() {
+                rules.any_host.push(i);
+            }
+            if rule.has_patterns() {
+                rules.patterns.push(i);
+            }
+            rules.rules.push(Arc::new(rule));
+        }
+        Ok(rules)
+    }
+
+    pub fn len(&self) -> usize {
+        self.rules.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    // has_trace 是否有规则打开了 trace，没有时不需要记录入站握手
+    pub fn has_trace(&self) -> bool {
+        self.rules.iter().any(|rule| rule.trace)
+    }
+
+    pub fn find(&self, dest: &Destination) -> Option<Arc<Rule>> {
+        let start = Instant::now();
+        let rule = self.lookup(dest).map(|i| self.rules[i].clone());
+        METRICS.rule_lookups.inc();
+        METRICS
+            .rule_lookup_ns
+            .add(start.elapsed().as_nanos() as u64);
+        rule
+    }
+
+    fn lookup(&self, dest: &Destination) -> Option<usize> {
+        // 目的地已经命中的规则
+        let mut hits = self.any_host.clone();
+        match dest.host {
+            Address::Domain(ref domain) => self.domains.lookup(domain, &mut hits),
+            Address::Ip(ip) => self.cidrs.lookup(ip, &mut hits),
+        }
+        hits.sort_unstable();
+        hits.dedup();
+        let mut candidates = hits.clone();
+        if let Address::Domain(_) = dest.host {
+            candidates.extend_from_slice(&self.patterns);
+            candidates.sort_unstable();
+            candidates.dedup();
+        }
+        candidates.into_iter().find(|&i| {
+            let rule = &self.rules[i];
+            let host_matches = hits.binary_search(&i).is_ok()
+                || match dest.host {
+                    Address::Domain(ref domain) => rule.pattern_matches(domain),
+                    Address::Ip(_) => false,
+                };
+            host_matches && rule.port_matches(dest.port)
+        })
+    }
+}
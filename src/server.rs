@@ -0,0 +1,425 @@
+use std::{
+    future::Future,
+    io,
+    sync::{Arc, Mutex, Weak},
+    time::{Duration, Instant},
+};
+
+use futures_util::FutureExt;
+use log::{debug, error, warn};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::{OwnedSemaphorePermit, Semaphore},
+    task::JoinSet,
+    time::{sleep, timeout},
+};
+use tokio_rustls::TlsAcceptor;
+
+use crate::budget::Budget;
+use crate::client::Client;
+use crate::config::{Config, Permits};
+use crate::conn_id::ConnId;
+use crate::echo;
+use crate::listener::{self, ListenerStats, Role};
+use crate::local::LocalPolicy;
+use crate::metrics::METRICS;
+use crate::outbound::{OutboundHealth, OutboundKind};
+use crate::protocols::detect::Protocol;
+use crate::stream::InboundStream;
+use crate::trace;
+use crate::websocket;
+
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+// ProxyServer 共享配置和连接数上限的代理服务，可以在多个监听端口上运行
+// 嵌入到其他程序时通过 snapshot 读取状态，不需要解析指标日志
+#[derive(Clone)]
+pub struct ProxyServer {
+    config: Arc<Config>,
+    limit: Option<Arc<Semaphore>>,
+    started: Instant,
+    // 通过 register_listener 登记的监听端口
+    listeners: Arc<Mutex<Vec<Weak<ListenerStats>>>>,
+    // 这个 ProxyServer 接受的连接的任务，drain 超时后中止
+    connections: Arc<Mutex<JoinSet<()>>>,
+}
+
+// ProtocolSnapshot 按识别出的协议统计的连接数和字节数
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProtocolSnapshot {
+    pub protocol: Protocol,
+    pub connections: u64,
+    pub bytes_up: u64,
+    pub bytes_down: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ListenerSnapshot {
+    pub name: String,
+    pub role: Role,
+    pub accepted: u64,
+    pub active: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OutboundSnapshot {
+    pub name: String,
+    pub kind: OutboundKind,
+    pub health: OutboundHealth,
+    // 上游 SOCKS5 服务端最近一次握手测得的 RTT
+    pub rtt: Option<Duration>,
+    // 配置了 max_connections 时当前占用的连接数
+    pub active: Option<u64>,
+}
+
+// Snapshot 某一时刻的状态，各项分别读取，相互之间不保证一致
+// listeners、accepted 和 outbounds 属于这个 ProxyServer，
+// 其余的连接数、字节数、协议和错误来自全局的 METRICS，是进程内所有 ProxyServer 的合计
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    pub uptime: Duration,
+    // 这个 ProxyServer 的监听端口累计 accept 的连接数
+    pub accepted: u64,
+    // 当前的客户端连接数、入站握手数和上游连接数 (进程合计)
+    pub connections: u64,
+    pub handshakes: u64,
+    pub upstream_connects: u64,
+    // 所有协议累计的字节数 (进程合计)
+    pub bytes_up: u64,
+    pub bytes_down: u64,
+    pub protocols: Vec<ProtocolSnapshot>,
+    // 各类别的连接错误数，类别见 ErrorStats
+    pub errors: Vec<(&'static str, u64)>,
+    pub listeners: Vec<ListenerSnapshot>,
+    // 按配置顺序
+    pub outbounds: Vec<OutboundSnapshot>,
+}
+
+impl ProxyServer {
+    pub fn new(config: Arc<Config>, max_connections: Option<Permits>) -> Self {
+        ProxyServer {
+            config,
+            limit: max_connections.map(|n| Arc::new(n.semaphore())),
+            started: Instant::now(),
+            listeners: Arc::default(),
+            connections: Arc::default(),
+        }
+    }
+
+    // spawn 在 connections 中启动连接任务，顺便回收已经结束的任务
+    fn spawn<F>(&self, task: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let mut connections = self.connections.lock().unwrap();
+        while let Some(Some(_)) = connections.join_next().now_or_never() {}
+        connections.spawn(task);
+    }
+
+    // register_listener 创建监听端口的统计，snapshot 只列出这个 ProxyServer 登记的监听端口
+    pub fn register_listener(&self, name: String, role: Role) -> Arc<ListenerStats> {
+        let stats = ListenerStats::register(name, role);
+        self.listeners.lock().unwrap().push(Arc::downgrade(&stats));
+        stats
+    }
+
+    pub fn config(&self) -> &Arc<Config> {
+        &self.config
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        let protocols: Vec<ProtocolSnapshot> = Protocol::ALL
+            .iter()
+            .map(|&protocol| {
+                let stats = METRICS.protocol(protocol);
+                ProtocolSnapshot {
+                    protocol,
+                    connections: stats.connections.get(),
+                    bytes_up: stats.bytes_up.get(),
+                    bytes_down: stats.bytes_down.get(),
+                }
+            })
+            .collect();
+        let listeners: Vec<ListenerSnapshot> = listener::live(&mut self.listeners.lock().unwrap())
+            .into_iter()
+            .map(|listener| ListenerSnapshot {
+                name: listener.name.clone(),
+                role: listener.role,
+                accepted: listener.accepted.get(),
+                active: listener.active.get(),
+            })
+            .collect();
+        Snapshot {
+            uptime: self.started.elapsed(),
+            accepted: listeners.iter().map(|l| l.accepted).sum(),
+            connections: METRICS.connections.get(),
+            handshakes: METRICS.handshakes.get(),
+            upstream_connects: METRICS.upstream_connects.get(),
+            bytes_up: protocols.iter().map(|p| p.bytes_up).sum(),
+            bytes_down: protocols.iter().map(|p| p.bytes_down).sum(),
+            protocols,
+            errors: METRICS.errors.all().to_vec(),
+            listeners,
+            outbounds: self
+                .config
+                .outbounds
+                .iter()
+                .map(|outbound| OutboundSnapshot {
+                    name: outbound.name.clone(),
+                    kind: outbound.kind,
+                    health: self.config.outbound_health.get(&outbound.name),
+                    rtt: match outbound.kind {
+                        OutboundKind::Socks5(addr) => self.config.tuning.rtt(addr),
+                        OutboundKind::Direct => None,
+                    },
+                    active: self
+                        .config
+                        .outbound_limits
+                        .get(outbound)
+                        .and_then(|limiter| limiter.active()),
+                })
+                .collect(),
+        }
+    }
+
+    // drain 等待现有连接结束，最多等待 grace，之后中止剩余的连接并等它们退出，返回被中止的连接数
+    // 连接任务中的 guard 在中止时照常计入 accounting 和投递 close 事件
+    // 调用前应先停止接受新连接
+    pub async fn drain(&self, grace: Duration) -> u64 {
+        let mut connections = std::mem::take(&mut *self.connections.lock().unwrap());
+        let _ = timeout(grace, async {
+            while connections.join_next().await.is_some() {}
+        })
+        .await;
+        let left = connections.len() as u64;
+        if left > 0 {
+            warn!("aborting {} connections still open after drain", left);
+        }
+        connections.shutdown().await;
+        left
+    }
+
+    // serve_listener 接受监听端口上的连接，acceptor 不为空时先完成 TLS 握手
+    pub async fn serve_listener(
+        self,
+        listener: Arc<TcpListener>,
+        stats: Arc<ListenerStats>,
+        acceptor: Option<TlsAcceptor>,
+    ) {
+        loop {
+            let permit = acquire(&self.limit).await;
+            let (socks, addr) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    // EMFILE 等错误是暂时的，稍后重试而不是退出
+                    error!("failed to accept connection: {}", err);
+                    sleep(ACCEPT_RETRY_DELAY).await;
+                    continue;
+                }
+            };
+            if self.config.deny_sources.contains(addr.ip()) {
+                METRICS.denied_sources.inc();
+                debug!("denied connection from {}", addr);
+                continue;
+            }
+            let id = ConnId::next();
+            debug!("{} accepted from {} on {}", id, addr, stats.name);
+            stats.accepted.inc();
+            let config = self.config.clone();
+            let acceptor = acceptor.clone();
+            let active = stats.track();
+            let role = stats.role;
+            self.spawn(async move {
+                let _permit = permit;
+                let _connection = METRICS.connections.track();
+                let _active = active;
+                if let Err(err) = handle_client(socks, acceptor, config, id, role).await {
+                    METRICS.errors.record(&err);
+                    error!("{} handle client error {}", id, err);
+                }
+            });
+        }
+    }
+
+    // serve_websocket 接受 WebSocket 隧道，目的地在升级请求的路径中
+    pub async fn serve_websocket(
+        self,
+        listener: Arc<TcpListener>,
+        stats: Arc<ListenerStats>,
+        path: Arc<str>,
+    ) {
+        loop {
+            let permit = acquire(&self.limit).await;
+            let (socket, addr) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    error!("failed to accept websocket connection: {}", err);
+                    sleep(ACCEPT_RETRY_DELAY).await;
+                    continue;
+                }
+            };
+            if self.config.deny_sources.contains(addr.ip()) {
+                METRICS.denied_sources.inc();
+                debug!("denied websocket connection from {}", addr);
+                continue;
+            }
+            let id = ConnId::next();
+            debug!("{} accepted websocket from {}", id, addr);
+            stats.accepted.inc();
+            let config = self.config.clone();
+            let path = path.clone();
+            let active = stats.track();
+            self.spawn(async move {
+                let _permit = permit;
+                let _connection = METRICS.connections.track();
+                let _active = active;
+                if let Err(err) = handle_websocket(socket, &path, config, id).await {
+                    METRICS.errors.record(&err);
+                    error!("{} handle websocket client error {}", id, err);
+                }
+            });
+        }
+    }
+}
+
+// acquire 在达到连接数上限时等待，此时新连接留在内核的 accept 队列中
+async fn acquire(limit: &Option<Arc<Semaphore>>) -> Option<OwnedSemaphorePermit> {
+    let limit = limit.as_ref()?;
+    if let Ok(permit) = limit.clone().try_acquire_owned() {
+        return Some(permit);
+    }
+    let _waiting = METRICS.permit_waiters.track();
+    limit.clone().acquire_owned().await.ok()
+}
+
+fn handshake_timed_out(what: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::TimedOut,
+        format!("inbound {} handshake timed out", what),
+    )
+}
+
+async fn handle_client(
+    socket: TcpStream,
+    acceptor: Option<TlsAcceptor>,
+    config: Arc<Config>,
+    id: ConnId,
+    role: Role,
+) -> io::Result<()> {
+    let handshake = METRICS.handshakes.track();
+    let peer_left: InboundStream = match acceptor {
+        Some(acceptor) => timeout(config.handshake_timeout, acceptor.accept(socket))
+            .await
+            .map_err(|_| handshake_timed_out("tls"))??
+            .into(),
+        None => socket.into(),
+    };
+    let client = Client::from_socket(peer_left, config, id, role).await?;
+    drop(handshake);
+    serve(client).await
+}
+
+async fn handle_websocket(
+    socket: TcpStream,
+    path: &str,
+    config: Arc<Config>,
+    id: ConnId,
+) -> io::Result<()> {
+    let handshake = METRICS.handshakes.track();
+    let (stream, dest) = timeout(config.handshake_timeout, websocket::accept(socket, path))
+        .await
+        .map_err(|_| handshake_timed_out("websocket"))??;
+    let client = Client::new(stream.into(), dest, config, id)?;
+    drop(handshake);
+    serve(client).await
+}
+
+async fn serve(mut client: Client) -> io::Result<()> {
+    let mut budget = Budget::new(client.id(), client.establish_timeout());
+    // 原始目的地未知的转发连接只能从 SNI 得到目的地
+    let sniffed = client.is_unresolved();
+    if sniffed {
+        client = budget.run("sniff", client.retrieve_dest()).await?;
+        if client.is_unresolved() {
+            return Err(io::Error::new(
+                io::ErrorKind::ConnectionRefused,
+                "original destination unavailable and no sni to fall back to, rejected",
+            ));
+        }
+    }
+    client.translate_address();
+    if client.is_echo() {
+        client.route()?;
+        let remote = echo::connect(&client.dest);
+        return client.do_pipe(remote).await;
+    }
+    // WebSocket 隧道的目的地是客户端明确指定的，SNI 只用于替换转发连接的 IP 目的地
+    let sniff = client.dest.port == 443 && !sniffed && !client.has_fixed_dest();
+    if sniff {
+        client = budget.run("sniff", client.retrieve_dest()).await?;
+    }
+    // 本机目的地按嗅探之后的目的地判断，SNI 指向其他主机时不算本机
+    if let Some(policy) = client.local_policy() {
+        match policy {
+            LocalPolicy::Direct => {
+                // 直连也要应用规则的 socket 选项、trace 和建连超时
+                client.route()?;
+                budget.set_limit(client.establish_timeout());
+                let remote = budget
+                    .run("direct connect", client.connect_direct())
+                    .await?;
+                return client.do_pipe(remote).await;
+            }
+            LocalPolicy::Reject => {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    format!("local destination {} rejected", client.dest),
+                ))
+            }
+            LocalPolicy::Upstream => (),
+        }
+    }
+    client.route()?;
+    if sniff && client.sni().is_none() {
+        client.apply_no_sni()?;
+    }
+    let traced = client.is_traced();
+    if traced {
+        debug!(
+            target: trace::TARGET,
+            "{} {} -> {} sni {:?} matched traced rule",
+            client.id(),
+            client.src(),
+            client.dest,
+            client.sni()
+        );
+        client.trace_handshake();
+    }
+    budget.set_limit(client.establish_timeout());
+    let remote = budget
+        .run("upstream connect", client.connect_remote_server())
+        .await;
+    if traced {
+        debug!(
+            target: trace::TARGET,
+            "{} stages {} ({}) total {:?} result {}",
+            client.id(),
+            budget.stages(),
+            client.connect_stages(),
+            budget.elapsed(),
+            match remote {
+                Ok(_) => "ok".to_string(),
+                Err(ref err) => err.to_string(),
+            }
+        );
+    }
+    let remote = remote?;
+    debug!(
+        "{} {} established in {:?}",
+        client.id(),
+        client.dest,
+        budget.elapsed()
+    );
+    client.do_pipe(remote).await?;
+    Ok(())
+}
@@ -0,0 +1,173 @@
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    time::{sleep, Instant, Sleep},
+};
+
+use crate::stream::PipeStream;
+
+// 令牌不足时至少等到能发送这么多字节，避免大量很小的读写
+const MIN_GRANT: u64 = 4096;
+
+// RateLimit 多个连接共享的令牌桶，两个方向的字节都计入
+// 桶的容量为 100ms 的流量，空闲后的突发不会超过这个量
+pub struct RateLimit {
+    // 每秒的字节数
+    rate: u64,
+    burst: u64,
+    state: Mutex<(u64, Instant)>,
+}
+
+impl RateLimit {
+    pub fn new(rate: u64) -> Self {
+        let burst = (rate / 10).max(MIN_GRANT);
+        RateLimit {
+            rate,
+            burst,
+            state: Mutex::new((burst, Instant::now())),
+        }
+    }
+
+    pub fn rate(&self) -> u64 {
+        self.rate
+    }
+
+    // take 取出最多 want 个字节的令牌，令牌不足时返回需要等待的时间
+    pub fn take(&self, want: usize) -> Result<usize, Duration> {
+        let mut state = self.state.lock().unwrap();
+        let (ref mut tokens, ref mut last) = *state;
+        let now = Instant::now();
+        let refill = (now - *last).as_nanos() * self.rate as u128 / 1_000_000_000;
+        if refill > 0 {
+            *tokens = (*tokens + refill.min(self.burst as u128) as u64).min(self.burst);
+            *last = now;
+        }
+        let need = (want as u64).clamp(1, MIN_GRANT);
+        if *tokens >= need {
+            let n = (want as u64).min(*tokens);
+            *tokens -= n;
+            return Ok(n as usize);
+        }
+        let missing = need - *tokens;
+        Err(Duration::from_nanos(
+            (missing as u128 * 1_000_000_000 / self.rate as u128) as u64 + 1,
+        ))
+    }
+
+    // refund 退回没有用掉的令牌
+    pub fn refund(&self, n: usize) {
+        let mut state = self.state.lock().unwrap();
+        state.0 = (state.0 + n as u64).min(self.burst);
+    }
+}
+
+// Shaped 按共享的 RateLimit 限制读写速度
+pub struct Shaped<S> {
+    inner: S,
+    limit: Arc<RateLimit>,
+    read_wait: Option<Pin<Box<Sleep>>>,
+    write_wait: Option<Pin<Box<Sleep>>>,
+}
+
+impl<S> Shaped<S> {
+    pub fn new(inner: S, limit: Arc<RateLimit>) -> Self {
+        Shaped {
+            inner,
+            limit,
+            read_wait: None,
+            write_wait: None,
+        }
+    }
+}
+
+// grant 等待上一次的等待结束后取令牌，令牌不足时设置新的等待
+fn grant(
+    limit: &RateLimit,
+    wait: &mut Option<Pin<Box<Sleep>>>,
+    want: usize,
+    cx: &mut Context<'_>,
+) -> Poll<usize> {
+    loop {
+        if let Some(sleep) = wait.as_mut() {
+            if sleep.as_mut().poll(cx).is_pending() {
+                return Poll::Pending;
+            }
+            *wait = None;
+        }
+        match limit.take(want) {
+            Ok(n) => return Poll::Ready(n),
+            Err(delay) => *wait = Some(Box::pin(sleep(delay))),
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Shaped<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if buf.remaining() == 0 {
+            return Pin::new(&mut this.inner).poll_read(cx, buf);
+        }
+        let n = match grant(&this.limit, &mut this.read_wait, buf.remaining(), cx) {
+            Poll::Ready(n) => n,
+            Poll::Pending => return Poll::Pending,
+        };
+        let mut limited = buf.take(n);
+        let poll = Pin::new(&mut this.inner).poll_read(cx, &mut limited);
+        let filled = limited.filled().len();
+        // take 得到的 ReadBuf 和 buf 共用内存，已经写入的部分由 poll_read 初始化
+        unsafe { buf.assume_init(filled) };
+        buf.advance(filled);
+        this.limit.refund(n - filled);
+        poll
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Shaped<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if buf.is_empty() {
+            return Pin::new(&mut this.inner).poll_write(cx, buf);
+        }
+        let n = match grant(&this.limit, &mut this.write_wait, buf.len(), cx) {
+            Poll::Ready(n) => n,
+            Poll::Pending => return Poll::Pending,
+        };
+        let poll = Pin::new(&mut this.inner).poll_write(cx, &buf[..n]);
+        let written = match poll {
+            Poll::Ready(Ok(written)) => written,
+            _ => 0,
+        };
+        this.limit.refund(n - written);
+        poll
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+impl<S: PipeStream> PipeStream for Shaped<S> {
+    fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        self.inner.set_nodelay(nodelay)
+    }
+}
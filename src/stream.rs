@@ -9,12 +9,18 @@ use std::{
 };
 
 use self::Side::{Left, Right};
-use log::{debug, trace};
+use log::{debug, info, trace};
 use tokio::{
-    io::{AsyncRead, AsyncWrite, ReadBuf},
+    io::{AsyncRead, AsyncWrite, DuplexStream, ReadBuf},
     net::TcpStream,
     time::{sleep, Instant, Sleep},
 };
+use tokio_rustls::server::TlsStream;
+
+use crate::conn_id::ConnId;
+use crate::metrics::{Counter, METRICS};
+use crate::protocols::detect::{detect, Protocol};
+use crate::websocket::WsStream;
 macro_rules! try_poll {
     ($expr:expr) => {
         match $expr {
@@ -28,12 +34,133 @@ macro_rules! try_poll {
 const SHARED_BUF_SIZE: usize = 1024 * 64;
 const PRIVATE_BUF_SIZE: usize = 1024 * 8;
 const HALF_CLOSE_TIMEOUT: Duration = Duration::from_secs(60);
+// 单次读取不超过该值视为交互式的小包
+const SMALL_READ_SIZE: usize = 512;
+// 两个方向连续的小包次数都达到该值后切换为交互模式
+const INTERACTIVE_THRESHOLD: u32 = 4;
+const INTERACTIVE_BUF_SIZE: usize = 1024 * 2;
+// 单次读取不小于该值视为大块数据，缓冲区比它小时读满缓冲区即视为大块数据
+const LARGE_READ_SIZE: usize = 1024 * 16;
+// 任一方向连续大块读取达到该值后切换为批量传输模式
+const BULK_THRESHOLD: u32 = 8;
+// 批量传输模式下连续这么多次非大块读取后退出
+const BULK_EXIT_THRESHOLD: u32 = 8;
+const BULK_BUF_SIZE: usize = 1024 * 256;
+// MAX_BUFFERED 一个方向上 pipe 最多缓冲的字节数
+pub const MAX_BUFFERED: usize = BULK_BUF_SIZE;
 thread_local! {
-    static SHARED_BUFFER:RefCell<[u8;SHARED_BUF_SIZE]> = RefCell::new([0u8;SHARED_BUF_SIZE]);
+    static SHARED_BUFFER:RefCell<[u8;SHARED_BUF_SIZE]> = const { RefCell::new([0u8;SHARED_BUF_SIZE]) };
+}
+
+// InboundStream 客户端一侧的连接，明文 TCP、TLS 或者 WebSocket 隧道
+pub enum InboundStream {
+    Tcp(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+    Ws(Box<WsStream>),
+}
+
+impl InboundStream {
+    // tcp 返回底层的 TcpStream，用于获取地址和 socket option
+    pub fn tcp(&self) -> &TcpStream {
+        match self {
+            InboundStream::Tcp(stream) => stream,
+            InboundStream::Tls(stream) => stream.get_ref().0,
+            InboundStream::Ws(stream) => stream.get_ref(),
+        }
+    }
+
+    // is_tunneled 为 true 时连接不可能来自 iptables 转发
+    pub fn is_tunneled(&self) -> bool {
+        !matches!(self, InboundStream::Tcp(_))
+    }
+}
+
+impl From<TcpStream> for InboundStream {
+    fn from(stream: TcpStream) -> Self {
+        InboundStream::Tcp(stream)
+    }
+}
+
+impl From<TlsStream<TcpStream>> for InboundStream {
+    fn from(stream: TlsStream<TcpStream>) -> Self {
+        InboundStream::Tls(Box::new(stream))
+    }
+}
+
+impl From<WsStream> for InboundStream {
+    fn from(stream: WsStream) -> Self {
+        InboundStream::Ws(Box::new(stream))
+    }
+}
+
+impl AsyncRead for InboundStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            InboundStream::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            InboundStream::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+            InboundStream::Ws(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for InboundStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            InboundStream::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            InboundStream::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+            InboundStream::Ws(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            InboundStream::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            InboundStream::Tls(stream) => Pin::new(stream).poll_flush(cx),
+            InboundStream::Ws(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            InboundStream::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            InboundStream::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+            InboundStream::Ws(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+// PipeStream BiPipe 两端的连接
+// set_nodelay 用于交互模式下关闭 Nagle 算法，非 TCP 的实现可以忽略
+pub trait PipeStream: AsyncRead + AsyncWrite + Unpin {
+    fn set_nodelay(&self, _nodelay: bool) -> io::Result<()> {
+        Ok(())
+    }
 }
 
-pub struct StreamWithBuffer {
-    pub stream: TcpStream,
+impl PipeStream for TcpStream {
+    fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        TcpStream::set_nodelay(self, nodelay)
+    }
+}
+
+impl PipeStream for DuplexStream {}
+
+impl PipeStream for InboundStream {
+    fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        self.tcp().set_nodelay(nodelay)
+    }
+}
+
+pub struct StreamWithBuffer<S> {
+    pub stream: S,
     buf: Option<Box<[u8]>>,
     pos: usize,
     // writeIndex
@@ -41,10 +168,24 @@ pub struct StreamWithBuffer {
     // readIndex
     pub read_eof: bool,
     pub done: bool,
+    // 连续读到小包的次数，读到大包时清零
+    small_reads: u32,
+    // 连续大块读取的次数
+    large_reads: u32,
+    // 连续非大块读取的次数
+    short_reads: u32,
+    // 累计读取的字节数
+    total: u64,
+    // 需要根据读到的第一段数据识别协议
+    detect: bool,
+    detected: Option<Protocol>,
 }
 
-impl StreamWithBuffer {
-    pub fn new(stream: TcpStream) -> Self {
+impl<S> StreamWithBuffer<S>
+where
+    S: PipeStream,
+{
+    pub fn new(stream: S) -> Self {
         StreamWithBuffer {
             stream,
             buf: None,
@@ -52,12 +193,29 @@ impl StreamWithBuffer {
             cap: 0,
             read_eof: false,
             done: false,
+            small_reads: 0,
+            large_reads: 0,
+            short_reads: 0,
+            total: 0,
+            detect: false,
+            detected: None,
         }
     }
     pub fn is_empty(&self) -> bool {
         self.pos == self.cap
     }
 
+    // resize_buffer 切换为指定大小的私有缓冲区，size 为 None 时使用共享缓冲区
+    // 缓冲区中仍有未写出的数据时保持不变
+    fn resize_buffer(&mut self, size: Option<usize>) {
+        if !self.is_empty() {
+            return;
+        }
+        self.buf = size.map(|size| vec![0; size].into_boxed_slice());
+        self.pos = 0;
+        self.cap = 0;
+    }
+
     // Read from self.stream, put the data into buffer
     pub fn poll_read_to_buffer(&mut self, cx: &mut Context) -> Poll<io::Result<usize>> {
         let stream = Pin::new(&mut self.stream);
@@ -80,18 +238,44 @@ impl StreamWithBuffer {
         if n == 0 {
             self.read_eof = true;
         } else {
+            // 写入端阻塞后数据读到私有缓冲区 (交互模式下只有 2KB)，单次读取不可能达到 LARGE_READ_SIZE，
+            // 读满缓冲区说明还有数据在等待，同样算作大块读取
+            let capacity = self.buf.as_ref().map_or(SHARED_BUF_SIZE, |buf| buf.len());
+            let large = n >= LARGE_READ_SIZE.min(capacity);
+            if self.detect && self.total == 0 {
+                self.detected = Some(match self.buf {
+                    Some(ref buf) => detect(&buf[..n]),
+                    None => SHARED_BUFFER.with(|buf| detect(&buf.borrow()[..n])),
+                });
+            }
+            self.total += n as u64;
             self.pos = 0;
-            self.cap = 0;
+            self.cap = n;
+            if n <= SMALL_READ_SIZE {
+                self.small_reads = self.small_reads.saturating_add(1);
+            } else {
+                self.small_reads = 0;
+            }
+            if large {
+                self.large_reads = self.large_reads.saturating_add(1);
+                self.short_reads = 0;
+            } else {
+                self.large_reads = 0;
+                self.short_reads = self.short_reads.saturating_add(1);
+            }
         }
 
         Poll::Ready(Ok(n))
     }
 
-    pub fn poll_write_buffer_to(
+    pub fn poll_write_buffer_to<W>(
         &mut self,
         ctx: &mut Context,
-        write_stream: &mut TcpStream,
-    ) -> Poll<io::Result<usize>> {
+        write_stream: &mut W,
+    ) -> Poll<io::Result<usize>>
+    where
+        W: AsyncWrite + Unpin,
+    {
         let writer = Pin::new(write_stream);
         let result = if let Some(ref buf) = self.buf {
             writer.poll_write(ctx, &buf[self.pos..self.cap])
@@ -118,6 +302,8 @@ impl StreamWithBuffer {
                     let mut buf = vec![0; cmp::max(PRIVATE_BUF_SIZE, available)];
                     buf[..available].copy_from_slice(&shared_buf[self.pos..self.cap]);
                     self.buf = Some(buf.into_boxed_slice());
+                    self.pos = 0;
+                    self.cap = available;
                     Poll::Pending
                 })
             }
@@ -132,74 +318,305 @@ enum Side {
     Right,
 }
 
-pub struct BiPipe {
-    left: StreamWithBuffer,
-    right: StreamWithBuffer,
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlowMode {
+    Normal,
+    // 双向频繁的小包 (ssh/rdp 等)，关闭 Nagle、使用小缓冲区并在每次写入后立即 flush
+    Interactive,
+    // 持续的大块数据 (下载/上传)，数据方向使用大缓冲区减少读写次数
+    // 不使用 splice：协议识别、按协议计数和出口限速都需要经过用户态的数据，TLS/WebSocket 入站也不是裸 socket
+    Bulk,
+}
+
+impl FlowMode {
+    fn gauge(self) -> Option<&'static Counter> {
+        match self {
+            FlowMode::Normal => None,
+            FlowMode::Interactive => Some(&METRICS.pipes_interactive),
+            FlowMode::Bulk => Some(&METRICS.pipes_bulk),
+        }
+    }
+}
+
+pub struct BiPipe<L, R> {
+    id: ConnId,
+    left: StreamWithBuffer<L>,
+    right: StreamWithBuffer<R>,
     half_close_deadline: Option<Pin<Box<Sleep>>>,
+    mode: FlowMode,
+    protocol: Option<Protocol>,
+    // 已经计入 METRICS 的字节数
+    reported_up: u64,
+    reported_down: u64,
 }
 
-pub fn pipe(left: TcpStream, right: TcpStream) -> BiPipe {
+pub fn pipe<L, R>(left: L, right: R) -> BiPipe<L, R>
+where
+    L: PipeStream,
+    R: PipeStream,
+{
+    let mut left = StreamWithBuffer::new(left);
+    left.detect = true;
     BiPipe {
-        left: StreamWithBuffer::new(left),
+        id: ConnId::default(),
+        left,
         right: StreamWithBuffer::new(right),
         half_close_deadline: Default::default(),
+        mode: FlowMode::Normal,
+        protocol: None,
+        reported_up: 0,
+        reported_down: 0,
+    }
+}
+
+fn poll_one_side<A, B>(
+    ctx: &mut Context,
+    reader: &mut StreamWithBuffer<A>,
+    writer: &mut StreamWithBuffer<B>,
+    flush_each_write: bool,
+) -> Poll<io::Result<()>>
+where
+    A: PipeStream,
+    B: PipeStream,
+{
+    loop {
+        if reader.is_empty() && !reader.read_eof {
+            match reader.poll_read_to_buffer(ctx) {
+                Poll::Pending => {
+                    // 暂时没有数据可读，把 writer 内部缓冲的数据 (TLS/WebSocket) 刷出去
+                    try_poll!(Pin::new(&mut writer.stream).poll_flush(ctx));
+                    return Poll::Pending;
+                }
+                Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                Poll::Ready(Ok(_)) => (),
+            }
+        }
+
+        while !reader.is_empty() {
+            try_poll!(reader.poll_write_buffer_to(ctx, &mut writer.stream));
+        }
+        if flush_each_write {
+            try_poll!(Pin::new(&mut writer.stream).poll_flush(ctx));
+        }
+        if reader.read_eof {
+            match Pin::new(&mut writer.stream).poll_shutdown(ctx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(Ok(())) => (),
+                Poll::Ready(Err(err)) => {
+                    debug!("failed to shutdown, maybe connect error. Error=[{}]", err)
+                }
+            }
+            // writer 已经关闭
+            // reader 将在另一个 poll_one_side 作为 writer 被关闭
+            reader.done = true;
+            return Poll::Ready(Ok(()));
+        }
     }
 }
 
-impl BiPipe {
-    fn poll_one_side(&mut self, ctx: &mut Context, side: Side) -> Poll<io::Result<()>> {
+impl<L, R> BiPipe<L, R>
+where
+    L: PipeStream,
+    R: PipeStream,
+{
+    pub fn mode(&self) -> FlowMode {
+        self.mode
+    }
+
+    pub fn with_id(mut self, id: ConnId) -> Self {
+        self.id = id;
+        self
+    }
+
+    // with_protocol 用于客户端数据在建立 pipe 之前已经读出的情况 (例如 TLS 嗅探)
+    pub fn with_protocol(mut self, protocol: Protocol) -> Self {
+        self.set_protocol(protocol);
+        self
+    }
+
+    // with_initial_data 客户端已经读出并在握手时发给上游的数据 (例如 TLS 嗅探读到的 ClientHello)
+    // 按这段数据识别协议，并计入上行字节数
+    pub fn with_initial_data(mut self, data: &[u8]) -> Self {
+        self.set_protocol(detect(data));
+        self.left.total += data.len() as u64;
+        self
+    }
+
+    fn set_protocol(&mut self, protocol: Protocol) {
+        if self.protocol.is_none() {
+            debug!("BiPipe {} detected protocol {}", self.id, protocol);
+            METRICS.protocol(protocol).connections.inc();
+            self.protocol = Some(protocol);
+        }
+    }
+
+    fn poll_side(&mut self, ctx: &mut Context, side: Side) -> Poll<io::Result<()>> {
         let Self {
             ref mut left,
             ref mut right,
+            mode,
             ..
         } = *self;
-        let (reader, writer) = match side {
-            Side::Left => (left, right),
-            Side::Right => (right, left),
+        let flush_each_write = mode == FlowMode::Interactive;
+        match side {
+            Side::Left => poll_one_side(ctx, left, right, flush_each_write),
+            Side::Right => poll_one_side(ctx, right, left, flush_each_write),
+        }
+    }
+
+    // update_mode 根据两个方向最近的读取大小切换模式
+    fn update_mode(&mut self) {
+        let (left, right) = (&self.left, &self.right);
+        let interactive =
+            left.small_reads >= INTERACTIVE_THRESHOLD && right.small_reads >= INTERACTIVE_THRESHOLD;
+        let mode = match self.mode {
+            FlowMode::Normal | FlowMode::Bulk if interactive => FlowMode::Interactive,
+            FlowMode::Normal | FlowMode::Interactive
+                if left.large_reads >= BULK_THRESHOLD || right.large_reads >= BULK_THRESHOLD =>
+            {
+                FlowMode::Bulk
+            }
+            FlowMode::Interactive if left.small_reads == 0 || right.small_reads == 0 => {
+                FlowMode::Normal
+            }
+            FlowMode::Bulk
+                if left.large_reads == 0
+                    && right.large_reads == 0
+                    && (left.short_reads >= BULK_EXIT_THRESHOLD
+                        || right.short_reads >= BULK_EXIT_THRESHOLD) =>
+            {
+                FlowMode::Normal
+            }
+            mode => mode,
         };
-        loop {
-            if reader.is_empty() && !reader.read_eof {
-                try_poll!(reader.poll_read_to_buffer(ctx));
+        if mode == self.mode {
+            return;
+        }
+        debug!(
+            "BiPipe {} switch from {:?} to {:?} mode. left=[small={} large={}] right=[small={} large={}]",
+            self.id,
+            self.mode,
+            mode,
+            left.small_reads,
+            left.large_reads,
+            right.small_reads,
+            right.large_reads
+        );
+        let nodelay = mode == FlowMode::Interactive;
+        for result in [
+            self.left.stream.set_nodelay(nodelay),
+            self.right.stream.set_nodelay(nodelay),
+        ] {
+            if let Err(err) = result {
+                debug!("{} failed to set nodelay. Error=[{}]", self.id, err);
             }
-
-            while !reader.is_empty() {
-                try_poll!(reader.poll_write_buffer_to(ctx, &mut writer.stream));
+        }
+        match mode {
+            FlowMode::Normal => {
+                self.left.resize_buffer(None);
+                self.right.resize_buffer(None);
+                METRICS.switches_to_normal.inc();
             }
-            if reader.read_eof {
-                match Pin::new(&mut writer.stream).poll_shutdown(ctx) {
-                    Poll::Pending => return Poll::Pending,
-                    Poll::Ready(Ok(())) => (),
-                    Poll::Ready(Err(err)) => {
-                        debug!("failed to shutdown, maybe connect error. Error=[{}]", err)
-                    }
+            FlowMode::Interactive => {
+                self.left.resize_buffer(Some(INTERACTIVE_BUF_SIZE));
+                self.right.resize_buffer(Some(INTERACTIVE_BUF_SIZE));
+                METRICS.switches_to_interactive.inc();
+            }
+            FlowMode::Bulk => {
+                // 只给有大块数据的方向分配大缓冲区
+                if self.left.large_reads > 0 {
+                    self.left.resize_buffer(Some(BULK_BUF_SIZE));
+                }
+                if self.right.large_reads > 0 {
+                    self.right.resize_buffer(Some(BULK_BUF_SIZE));
                 }
-                // writer 已经关闭
-                // reader 将在另一个 poll_one_side 作为 writer 被关闭
-                reader.done = true;
-                return Poll::Ready(Ok(()));
+                METRICS.switches_to_bulk.inc();
             }
         }
+        if let Some(gauge) = self.mode.gauge() {
+            gauge.dec();
+        }
+        if let Some(gauge) = mode.gauge() {
+            gauge.inc();
+        }
+        self.mode = mode;
     }
 }
 
-impl Future for BiPipe {
+impl<L, R> BiPipe<L, R> {
+    // totals 返回到目前为止两个方向的字节数 (上行, 下行)
+    pub fn totals(&self) -> (u64, u64) {
+        (self.left.total, self.right.total)
+    }
+
+    pub fn protocol(&self) -> Option<Protocol> {
+        self.protocol
+    }
+
+    // report_traffic 把新增的字节数计入对应协议，协议识别出来之前先不计入
+    fn report_traffic(&mut self) {
+        let Some(protocol) = self.protocol else {
+            return;
+        };
+        let stats = METRICS.protocol(protocol);
+        stats.bytes_up.add(self.left.total - self.reported_up);
+        stats.bytes_down.add(self.right.total - self.reported_down);
+        self.reported_up = self.left.total;
+        self.reported_down = self.right.total;
+    }
+}
+
+impl<L, R> Drop for BiPipe<L, R> {
+    fn drop(&mut self) {
+        if let Some(gauge) = self.mode.gauge() {
+            gauge.dec();
+        }
+        if self.protocol.is_none() {
+            METRICS.unknown.connections.inc();
+            self.protocol = Some(Protocol::Unknown);
+        }
+        self.report_traffic();
+        // 访问日志，每条连接结束时输出一次
+        if self.id != ConnId::default() {
+            info!(
+                "{} closed protocol={} up={} down={}",
+                self.id,
+                self.protocol.unwrap_or(Protocol::Unknown),
+                self.left.total,
+                self.right.total
+            );
+        }
+    }
+}
+
+impl<L, R> Future for BiPipe<L, R>
+where
+    L: PipeStream,
+    R: PipeStream,
+{
     type Output = io::Result<()>;
     // https://stackoverflow.com/questions/28587698/whats-the-difference-between-placing-mut-before-a-variable-name-and-after-the
     fn poll(mut self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<Self::Output> {
         if !self.left.done {
-            if let Poll::Ready(Err(err)) = self.poll_one_side(ctx, Left) {
+            if let Poll::Ready(Err(err)) = self.poll_side(ctx, Left) {
                 return Poll::Ready(Err(err));
             }
         }
 
         if !self.right.done {
-            if let Poll::Ready(Err(err)) = self.poll_one_side(ctx, Right) {
+            if let Poll::Ready(Err(err)) = self.poll_side(ctx, Right) {
                 return Poll::Ready(Err(err));
             }
         }
 
+        self.update_mode();
+        if let Some(protocol) = self.left.detected.take() {
+            self.set_protocol(protocol);
+        }
+        self.report_traffic();
+
         match (self.left.done, self.right.done) {
-            (true, true) => return Poll::Ready(Ok(())),
+            (true, true) => Poll::Ready(Ok(())),
             (false, false) => Poll::Pending,
             _ => match &mut self.half_close_deadline {
                 None => {
@@ -216,7 +633,7 @@ impl Future for BiPipe {
                 }
                 Some(_) => {
                     // 超时后提前返回
-                    debug!("BiPipe half-close conn timeout");
+                    debug!("BiPipe {} half-close conn timeout", self.id);
                     Poll::Ready(Ok(()))
                 }
             },
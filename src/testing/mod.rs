@@ -0,0 +1,298 @@
+// 测试辅助
+// MockUpstream 是一个可以按需制造故障的 SOCKS5 上游，只在 fault-injection feature 下编译
+// SimStream 是按随机种子交错读写、EOF 和错误的内存连接，用于覆盖 stream.rs 的 BiPipe
+// client_hello 构造用于 SNI 嗅探测试的 TLS ClientHello
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    time::{sleep, Sleep},
+};
+
+use crate::stream::PipeStream;
+
+#[cfg(feature = "fault-injection")]
+mod upstream;
+
+#[cfg(feature = "fault-injection")]
+pub use self::upstream::{Fault, MockUpstream};
+
+// client_hello 构造一个最小的 TLS ClientHello 记录，server_name 为空时不带 SNI 扩展
+// SNI 之前放一个其他扩展，覆盖解析器跳过扩展的逻辑
+pub fn client_hello(server_name: Option<&str>) -> Vec<u8> {
+    let mut exts = vec![0x00, 0x17, 0x00, 0x00];
+    if let Some(name) = server_name {
+        let name = name.as_bytes();
+        let list_len = name.len() + 3;
+        exts.extend_from_slice(&[0x00, 0x00]);
+        exts.extend_from_slice(&((list_len + 2) as u16).to_be_bytes());
+        exts.extend_from_slice(&(list_len as u16).to_be_bytes());
+        exts.push(0x00);
+        exts.extend_from_slice(&(name.len() as u16).to_be_bytes());
+        exts.extend_from_slice(name);
+    }
+    let mut body = vec![0x03, 0x03];
+    body.extend_from_slice(&[0u8; 32]);
+    // session id、一个 cipher suite、一个 compression method
+    body.push(0x00);
+    body.extend_from_slice(&[0x00, 0x02, 0x13, 0x01]);
+    body.extend_from_slice(&[0x01, 0x00]);
+    body.extend_from_slice(&(exts.len() as u16).to_be_bytes());
+    body.extend_from_slice(&exts);
+    let mut handshake = vec![0x01];
+    handshake.extend_from_slice(&(body.len() as u32).to_be_bytes()[1..]);
+    handshake.extend_from_slice(&body);
+    let mut record = vec![0x16, 0x03, 0x01];
+    record.extend_from_slice(&(handshake.len() as u16).to_be_bytes());
+    record.extend_from_slice(&handshake);
+    record
+}
+
+// SimRng 可以按种子重放的伪随机数 (xorshift64*)
+#[derive(Debug, Clone)]
+pub struct SimRng(u64);
+
+impl SimRng {
+    pub fn new(seed: u64) -> Self {
+        // 种子为 0 时 xorshift 一直输出 0
+        SimRng(seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    // below 返回 [0, n) 之间的数
+    pub fn below(&mut self, n: usize) -> usize {
+        (self.next_u64() % n as u64) as usize
+    }
+
+    pub fn chance(&mut self, percent: usize) -> bool {
+        self.below(100) < percent
+    }
+
+    pub fn bytes(&mut self, len: usize) -> Vec<u8> {
+        (0..len).map(|_| self.next_u64() as u8).collect()
+    }
+}
+
+// SimScript 对端的行为
+#[derive(Debug, Clone, Default)]
+pub struct SimScript {
+    // 对端发送的数据，发送完之后是 EOF
+    pub data: Vec<u8>,
+    // 发送完数据之后返回 ConnectionReset 而不是 EOF
+    pub read_reset: bool,
+    // 对端收到这么多字节之后，再写入返回 ConnectionReset
+    pub write_reset_at: Option<usize>,
+}
+
+#[derive(Debug, Default)]
+struct FlowState {
+    // 一端被 pipe 读出的字节数
+    read: usize,
+    // 另一端收到的数据
+    received: Vec<u8>,
+    max_in_flight: usize,
+    shutdowns: u32,
+    write_after_shutdown: bool,
+}
+
+// SimFlow 一个方向上的数据，由一端读出、写入另一端
+#[derive(Debug, Default)]
+pub struct SimFlow(Mutex<FlowState>);
+
+impl SimFlow {
+    pub fn received(&self) -> Vec<u8> {
+        self.0.lock().unwrap().received.clone()
+    }
+
+    // max_in_flight pipe 已经读出但还没有写出的最大字节数
+    pub fn max_in_flight(&self) -> usize {
+        self.0.lock().unwrap().max_in_flight
+    }
+
+    // shutdowns 接收端完成 shutdown 的次数
+    pub fn shutdowns(&self) -> u32 {
+        self.0.lock().unwrap().shutdowns
+    }
+
+    pub fn write_after_shutdown(&self) -> bool {
+        self.0.lock().unwrap().write_after_shutdown
+    }
+}
+
+// SimStream 每次读写随机返回 Pending (立即唤醒或者等待几毫秒)、读写随机的长度
+pub struct SimStream {
+    rng: SimRng,
+    script: SimScript,
+    pos: usize,
+    // 单次读写的最大长度，小值用于触发交互模式，大值用于触发批量模式
+    max_chunk: usize,
+    // 从这一端读出的数据和写入这一端的数据
+    reading: Arc<SimFlow>,
+    writing: Arc<SimFlow>,
+    read_stall: Option<Pin<Box<Sleep>>>,
+    write_stall: Option<Pin<Box<Sleep>>>,
+}
+
+// SimPair pipe 两端的连接，up 为 left 到 right 的数据，down 为反方向
+pub struct SimPair {
+    pub left: SimStream,
+    pub right: SimStream,
+    pub up: Arc<SimFlow>,
+    pub down: Arc<SimFlow>,
+}
+
+impl SimPair {
+    pub fn new(seed: u64, left: SimScript, right: SimScript) -> Self {
+        let up = Arc::new(SimFlow::default());
+        let down = Arc::new(SimFlow::default());
+        SimPair {
+            left: SimStream::new(SimRng::new(seed), left, up.clone(), down.clone()),
+            right: SimStream::new(SimRng::new(!seed), right, down.clone(), up.clone()),
+            up,
+            down,
+        }
+    }
+}
+
+impl SimStream {
+    pub fn new(
+        mut rng: SimRng,
+        script: SimScript,
+        reading: Arc<SimFlow>,
+        writing: Arc<SimFlow>,
+    ) -> Self {
+        let max_chunk = [64, 2048, 64 * 1024][rng.below(3)];
+        SimStream {
+            rng,
+            script,
+            pos: 0,
+            max_chunk,
+            reading,
+            writing,
+            read_stall: None,
+            write_stall: None,
+        }
+    }
+
+    fn chunk(&mut self, limit: usize) -> usize {
+        (1 + self.rng.below(self.max_chunk)).min(limit)
+    }
+}
+
+// stall 随机让出一次，之前的等待还没有结束时返回 true
+fn stall(rng: &mut SimRng, wait: &mut Option<Pin<Box<Sleep>>>, cx: &mut Context<'_>) -> bool {
+    if let Some(sleep) = wait.as_mut() {
+        if sleep.as_mut().poll(cx).is_pending() {
+            return true;
+        }
+        *wait = None;
+        return false;
+    }
+    match rng.below(8) {
+        0 => {
+            cx.waker().wake_by_ref();
+            true
+        }
+        1 => {
+            let mut delay = Box::pin(sleep(Duration::from_millis(1 + rng.below(5) as u64)));
+            let _ = delay.as_mut().poll(cx);
+            *wait = Some(delay);
+            true
+        }
+        _ => false,
+    }
+}
+
+impl AsyncRead for SimStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if stall(&mut this.rng, &mut this.read_stall, cx) {
+            return Poll::Pending;
+        }
+        let remaining = this.script.data.len() - this.pos;
+        if remaining == 0 {
+            if this.script.read_reset {
+                return Poll::Ready(Err(io::ErrorKind::ConnectionReset.into()));
+            }
+            return Poll::Ready(Ok(()));
+        }
+        let n = this.chunk(remaining.min(buf.remaining()));
+        buf.put_slice(&this.script.data[this.pos..this.pos + n]);
+        this.pos += n;
+        let mut flow = this.reading.0.lock().unwrap();
+        flow.read += n;
+        flow.max_in_flight = flow.max_in_flight.max(flow.read - flow.received.len());
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for SimStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if stall(&mut this.rng, &mut this.write_stall, cx) {
+            return Poll::Pending;
+        }
+        let mut limit = buf.len();
+        {
+            let mut flow = this.writing.0.lock().unwrap();
+            if flow.shutdowns > 0 {
+                flow.write_after_shutdown = true;
+                return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+            }
+            if let Some(at) = this.script.write_reset_at {
+                if flow.received.len() >= at {
+                    return Poll::Ready(Err(io::ErrorKind::ConnectionReset.into()));
+                }
+                limit = limit.min(at - flow.received.len());
+            }
+        }
+        let n = this.chunk(limit);
+        this.writing
+            .0
+            .lock()
+            .unwrap()
+            .received
+            .extend_from_slice(&buf[..n]);
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if stall(&mut this.rng, &mut this.write_stall, cx) {
+            return Poll::Pending;
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if stall(&mut this.rng, &mut this.write_stall, cx) {
+            return Poll::Pending;
+        }
+        this.writing.0.lock().unwrap().shutdowns += 1;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl PipeStream for SimStream {}
@@ -0,0 +1,204 @@
+// MockUpstream 是一个可以按需制造故障的 SOCKS5 上游，用于覆盖 protocols/socks5.rs 的错误处理
+use std::{
+    io,
+    net::{Ipv6Addr, SocketAddr},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    time::{sleep, timeout},
+};
+
+use crate::client::{Address, Destination};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    // 正常的上游，握手成功后回显数据
+    None,
+    // 收到 greeting 后延迟回复
+    SlowGreeting(Duration),
+    // method 选择回复中的版本号错误
+    BadVersion,
+    // CONNECT 回复只发送一部分就关闭连接
+    TruncatedReply,
+    // 收到 greeting 后直接关闭连接
+    EarlyClose,
+    // CONNECT 回复中 REP 为指定的错误码
+    Reject(u8),
+    // 成功回复，但 BND.ADDR 为 IPv6 地址
+    Ipv6Reply,
+    // 丢弃和 greeting 一起到达的数据，流水线发送的请求会丢失
+    NoPipelining,
+}
+
+#[derive(Default)]
+struct Recorded {
+    requests: Mutex<Vec<String>>,
+    pipelined: AtomicUsize,
+    probes: AtomicUsize,
+}
+
+pub struct MockUpstream {
+    addr: SocketAddr,
+    recorded: Arc<Recorded>,
+    open: Arc<AtomicUsize>,
+}
+
+impl MockUpstream {
+    // spawn 在 127.0.0.1 的随机端口上启动上游，每个连接都按 fault 处理
+    pub async fn spawn(fault: Fault) -> io::Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let recorded = Arc::new(Recorded::default());
+        let open = Arc::new(AtomicUsize::new(0));
+        let records = recorded.clone();
+        let opened = open.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let recorded = records.clone();
+                let opened = opened.clone();
+                opened.fetch_add(1, Ordering::SeqCst);
+                tokio::spawn(async move {
+                    let _ = serve(stream, fault, recorded).await;
+                    opened.fetch_sub(1, Ordering::SeqCst);
+                });
+            }
+        });
+        Ok(MockUpstream {
+            addr,
+            recorded,
+            open,
+        })
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    // open_connections 返回上游还没有关闭的连接数，客户端关闭连接后上游随之关闭
+    pub fn open_connections(&self) -> usize {
+        self.open.load(Ordering::SeqCst)
+    }
+
+    // requests 返回上游收到的 CONNECT 目的地，格式为 host:port，不包括能力探测
+    pub fn requests(&self) -> Vec<String> {
+        self.recorded.requests.lock().unwrap().clone()
+    }
+
+    // pipelined 返回和 greeting 一起到达的 CONNECT 请求数，不包括能力探测
+    pub fn pipelined(&self) -> usize {
+        self.recorded.pipelined.load(Ordering::SeqCst)
+    }
+
+    // probes 返回收到的能力探测 CONNECT (目的端口为 0) 次数
+    pub fn probes(&self) -> usize {
+        self.recorded.probes.load(Ordering::SeqCst)
+    }
+}
+
+async fn read_request(stream: &mut TcpStream) -> io::Result<Destination> {
+    let mut buf = [0u8; 4];
+    stream.read_exact(&mut buf).await?;
+    if buf[..3] != [0x05, 0x01, 0x00] {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "bad request"));
+    }
+    let host: Address = match buf[3] {
+        0x01 => {
+            let mut addr = [0u8; 4];
+            stream.read_exact(&mut addr).await?;
+            addr.into()
+        }
+        0x03 => {
+            let len = stream.read_u8().await? as usize;
+            let mut name = vec![0u8; len];
+            stream.read_exact(&mut name).await?;
+            String::from_utf8(name)
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "bad domain"))?
+                .into()
+        }
+        0x04 => {
+            let mut addr = [0u8; 16];
+            stream.read_exact(&mut addr).await?;
+            addr.into()
+        }
+        _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "bad atyp")),
+    };
+    let port = stream.read_u16().await?;
+    Ok((host, port).into())
+}
+
+// buffered 不等待，返回连接上是否已经有未读的数据
+async fn buffered(stream: &TcpStream) -> bool {
+    let mut byte = [0u8; 1];
+    matches!(
+        timeout(Duration::ZERO, stream.peek(&mut byte)).await,
+        Ok(Ok(1))
+    )
+}
+
+async fn serve(mut stream: TcpStream, fault: Fault, recorded: Arc<Recorded>) -> io::Result<()> {
+    let mut greeting = [0u8; 2];
+    stream.read_exact(&mut greeting).await?;
+    let mut methods = vec![0u8; greeting[1] as usize];
+    stream.read_exact(&mut methods).await?;
+    // 客户端等待 method 选择回复时请求不会先到达
+    let pipelined = buffered(&stream).await;
+    match fault {
+        Fault::EarlyClose => return Ok(()),
+        Fault::SlowGreeting(delay) => sleep(delay).await,
+        Fault::NoPipelining if pipelined => {
+            let mut discard = [0u8; 1024];
+            while stream.try_read(&mut discard).is_ok_and(|n| n > 0) {}
+        }
+        _ => (),
+    }
+    let version = if fault == Fault::BadVersion {
+        0x04
+    } else {
+        0x05
+    };
+    stream.write_all(&[version, 0x00]).await?;
+
+    let dest = read_request(&mut stream).await?;
+    if dest.port == 0 {
+        recorded.probes.fetch_add(1, Ordering::SeqCst);
+    } else {
+        recorded.requests.lock().unwrap().push(dest.to_string());
+        if pipelined {
+            recorded.pipelined.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+    match fault {
+        Fault::TruncatedReply => {
+            stream.write_all(&[0x05, 0x00, 0x00, 0x01, 0x7f]).await?;
+            return Ok(());
+        }
+        Fault::Reject(rep) => {
+            stream
+                .write_all(&[0x05, rep, 0x00, 0x01, 0, 0, 0, 0, 0, 0])
+                .await?;
+            return Ok(());
+        }
+        Fault::Ipv6Reply => {
+            let mut reply = vec![0x05, 0x00, 0x00, 0x04];
+            reply.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+            reply.extend_from_slice(&[0x04, 0x38]);
+            stream.write_all(&reply).await?;
+        }
+        _ => {
+            stream
+                .write_all(&[0x05, 0x00, 0x00, 0x01, 127, 0, 0, 1, 0x04, 0x38])
+                .await?
+        }
+    }
+
+    let (mut reader, mut writer) = stream.split();
+    tokio::io::copy(&mut reader, &mut writer).await?;
+    Ok(())
+}
@@ -0,0 +1,76 @@
+use std::fs::File;
+use std::io::{self, BufReader, ErrorKind};
+use std::path::Path;
+use std::sync::Arc;
+
+use tokio_rustls::rustls::internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys};
+use tokio_rustls::rustls::{
+    AllowAnyAuthenticatedClient, Certificate, NoClientAuth, PrivateKey, RootCertStore, ServerConfig,
+};
+use tokio_rustls::TlsAcceptor;
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, msg)
+}
+
+fn open(path: &Path) -> io::Result<BufReader<File>> {
+    File::open(path)
+        .map(BufReader::new)
+        .map_err(|err| io::Error::new(err.kind(), format!("{}: {}", path.display(), err)))
+}
+
+fn load_certs(path: &Path) -> io::Result<Vec<Certificate>> {
+    let certs = certs(&mut open(path)?)
+        .map_err(|_| invalid_data(format!("{}: invalid certificate", path.display())))?;
+    if certs.is_empty() {
+        return Err(invalid_data(format!(
+            "{}: no certificate found",
+            path.display()
+        )));
+    }
+    Ok(certs)
+}
+
+// load_private_key 依次尝试 PKCS#8 和 RSA 两种 PEM 格式
+fn load_private_key(path: &Path) -> io::Result<PrivateKey> {
+    let pkcs8 = pkcs8_private_keys(&mut open(path)?)
+        .map_err(|_| invalid_data(format!("{}: invalid private key", path.display())))?;
+    if let Some(key) = pkcs8.into_iter().next() {
+        return Ok(key);
+    }
+    let rsa = rsa_private_keys(&mut open(path)?)
+        .map_err(|_| invalid_data(format!("{}: invalid private key", path.display())))?;
+    rsa.into_iter()
+        .next()
+        .ok_or_else(|| invalid_data(format!("{}: no private key found", path.display())))
+}
+
+// build_acceptor 构造入站监听使用的 TLS acceptor
+// client_ca 不为空时要求客户端提供由该 CA 签发的证书
+pub fn build_acceptor(
+    cert: &Path,
+    key: &Path,
+    client_ca: Option<&Path>,
+) -> io::Result<TlsAcceptor> {
+    let verifier = match client_ca {
+        Some(ca) => {
+            let mut roots = RootCertStore::empty();
+            let (valid, _) = roots
+                .add_pem_file(&mut open(ca)?)
+                .map_err(|_| invalid_data(format!("{}: invalid CA certificate", ca.display())))?;
+            if valid == 0 {
+                return Err(invalid_data(format!(
+                    "{}: no CA certificate found",
+                    ca.display()
+                )));
+            }
+            AllowAnyAuthenticatedClient::new(roots)
+        }
+        None => NoClientAuth::new(),
+    };
+    let mut config = ServerConfig::new(verifier);
+    config
+        .set_single_cert(load_certs(cert)?, load_private_key(key)?)
+        .map_err(|err| invalid_data(format!("invalid certificate or key: {}", err)))?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
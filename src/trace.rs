@@ -0,0 +1,216 @@
+use std::{
+    fmt::{self, Write},
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use log::debug;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::conn_id::ConnId;
+
+// TARGET 规则打开 trace 的连接使用的日志 target，不受 --log-level 限制，始终输出 debug 级别
+pub const TARGET: &str = "socket_proxy::trace";
+// 单次读写最多输出的字节数，握手之后的早期数据可能很长
+const MAX_DUMP: usize = 256;
+
+// hex 以十六进制输出数据，超出 MAX_DUMP 的部分省略
+pub fn hex(data: &[u8]) -> String {
+    hex_prefix(&data[..data.len().min(MAX_DUMP)], data.len())
+}
+
+// hex_prefix 输出长度为 len 的数据的前一部分 prefix
+fn hex_prefix(prefix: &[u8], len: usize) -> String {
+    let mut out = String::with_capacity(prefix.len() * 2 + 16);
+    for byte in prefix {
+        let _ = write!(out, "{:02x}", byte);
+    }
+    if len > prefix.len() {
+        let _ = write!(out, "...({} more)", len - prefix.len());
+    }
+    out
+}
+
+// Stages 按执行顺序记录的各阶段耗时
+#[derive(Debug, Clone, Default)]
+pub struct Stages(Vec<(&'static str, Duration)>);
+
+impl Stages {
+    pub fn push(&mut self, stage: &'static str, elapsed: Duration) {
+        self.0.push((stage, elapsed));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl fmt::Display for Stages {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, (stage, elapsed)) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_str(", ")?;
+            }
+            write!(f, "{} {:?}", stage, elapsed)?;
+        }
+        Ok(())
+    }
+}
+
+// Transcript 入站握手收发的字节，握手时还不知道会匹配哪条规则，先记下来，匹配到打开 trace 的规则后再输出
+// 每段只保留 MAX_DUMP 字节，和 Traced 输出的一样多
+#[derive(Debug, Default)]
+pub struct Transcript(Vec<(&'static str, usize, Vec<u8>)>);
+
+impl Transcript {
+    fn record(&mut self, direction: &'static str, data: &[u8]) {
+        let prefix = data[..data.len().min(MAX_DUMP)].to_vec();
+        self.0.push((direction, data.len(), prefix));
+    }
+
+    // dump 按 Traced 的格式输出记下的每一段
+    pub fn dump(&self, id: ConnId, peer: &str) {
+        for (direction, len, prefix) in &self.0 {
+            debug!(
+                target: TARGET,
+                "{} {} {} {} bytes {}",
+                id,
+                direction,
+                peer,
+                len,
+                hex_prefix(prefix, *len)
+            );
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+// Recording 把经过的每一次读写记入 Transcript，transcript 为 None 时只转发
+pub struct Recording<'a, S> {
+    inner: &'a mut S,
+    transcript: Option<Transcript>,
+}
+
+impl<'a, S> Recording<'a, S> {
+    pub fn new(inner: &'a mut S, record: bool) -> Self {
+        Recording {
+            inner,
+            transcript: record.then(Transcript::default),
+        }
+    }
+
+    pub fn into_transcript(self) -> Option<Transcript> {
+        self.transcript
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Recording<'_, S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let before = buf.filled().len();
+        let poll = Pin::new(&mut *this.inner).poll_read(cx, buf);
+        if let (Poll::Ready(Ok(())), Some(transcript)) = (&poll, &mut this.transcript) {
+            transcript.record("<-", &buf.filled()[before..]);
+        }
+        poll
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Recording<'_, S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut *this.inner).poll_write(cx, buf);
+        if let (Poll::Ready(Ok(n)), Some(transcript)) = (&poll, &mut this.transcript) {
+            transcript.record("->", &buf[..*n]);
+        }
+        poll
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+// Traced 记录经过的每一次读写，用于输出握手的原始字节
+pub struct Traced<'a, S> {
+    inner: &'a mut S,
+    id: ConnId,
+    peer: &'static str,
+}
+
+impl<'a, S> Traced<'a, S> {
+    pub fn new(inner: &'a mut S, id: ConnId, peer: &'static str) -> Self {
+        Traced { inner, id, peer }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Traced<'_, S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let before = buf.filled().len();
+        let poll = Pin::new(&mut *this.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = poll {
+            let data = &buf.filled()[before..];
+            debug!(
+                target: TARGET,
+                "{} <- {} {} bytes {}",
+                this.id,
+                this.peer,
+                data.len(),
+                hex(data)
+            );
+        }
+        poll
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Traced<'_, S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut *this.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = poll {
+            debug!(
+                target: TARGET,
+                "{} -> {} {} bytes {}",
+                this.id,
+                this.peer,
+                n,
+                hex(&buf[..n])
+            );
+        }
+        poll
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.get_mut().inner).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,58 @@
+use std::{collections::HashMap, net::SocketAddr, sync::Mutex, time::Duration};
+
+const MIN_BUFFER_SIZE: u64 = 1024 * 64;
+const MAX_BUFFER_SIZE: u64 = 1024 * 1024 * 32;
+
+// BufferTuning 上游连接的 SO_SNDBUF/SO_RCVBUF 设置
+// 配置了链路带宽时按 带宽 x RTT (BDP) 计算缓冲区大小，RTT 取该上游之前连接的平滑值
+// 显式配置的缓冲区大小优先，都没有配置时交给内核自动调整
+#[derive(Debug, Default)]
+pub struct BufferTuning {
+    // bytes/s
+    pub bandwidth: Option<u64>,
+    pub send_buffer: Option<u32>,
+    pub recv_buffer: Option<u32>,
+    rtts: Mutex<HashMap<SocketAddr, Duration>>,
+}
+
+fn bdp(bandwidth: u64, rtt: Duration) -> u32 {
+    let bytes = bandwidth as u128 * rtt.as_micros() / 1_000_000;
+    bytes.clamp(MIN_BUFFER_SIZE as u128, MAX_BUFFER_SIZE as u128) as u32
+}
+
+// mbit_to_bytes 将 Mbit/s 换算成 bytes/s，溢出时返回 None
+pub fn mbit_to_bytes(mbit: u64) -> Option<u64> {
+    mbit.checked_mul(1_000_000).map(|bits| bits / 8)
+}
+
+impl BufferTuning {
+    pub fn new(bandwidth: Option<u64>, send_buffer: Option<u32>, recv_buffer: Option<u32>) -> Self {
+        BufferTuning {
+            bandwidth,
+            send_buffer,
+            recv_buffer,
+            rtts: Default::default(),
+        }
+    }
+
+    pub fn rtt(&self, upstream: SocketAddr) -> Option<Duration> {
+        self.rtts.lock().unwrap().get(&upstream).copied()
+    }
+
+    // record_rtt 按 srtt = 7/8 srtt + 1/8 rtt 平滑
+    pub fn record_rtt(&self, upstream: SocketAddr, rtt: Duration) {
+        let mut rtts = self.rtts.lock().unwrap();
+        let srtt = rtts
+            .get(&upstream)
+            .map_or(rtt, |&srtt| (srtt * 7 + rtt) / 8);
+        rtts.insert(upstream, srtt);
+    }
+
+    // buffer_sizes 返回 (SO_SNDBUF, SO_RCVBUF)，None 表示不修改
+    pub fn buffer_sizes(&self, upstream: SocketAddr) -> (Option<u32>, Option<u32>) {
+        let bdp = self
+            .bandwidth
+            .and_then(|bandwidth| self.rtt(upstream).map(|rtt| bdp(bandwidth, rtt)));
+        (self.send_buffer.or(bdp), self.recv_buffer.or(bdp))
+    }
+}
@@ -0,0 +1,159 @@
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use futures_util::{Sink, Stream};
+use log::debug;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::TcpStream,
+};
+use tokio_tungstenite::{
+    accept_hdr_async,
+    tungstenite::{
+        error::ProtocolError,
+        handshake::server::{ErrorResponse, Request, Response},
+        http::StatusCode,
+        Error as WsError, Message,
+    },
+    WebSocketStream,
+};
+
+use crate::client::Destination;
+
+// 隧道目的地通过该请求头传递，格式为 host:port
+pub const DESTINATION_HEADER: &str = "x-socket-proxy-destination";
+
+fn ws_error(err: WsError) -> io::Error {
+    match err {
+        WsError::Io(err) => err,
+        err => io::Error::other(err),
+    }
+}
+
+fn reject(status: StatusCode) -> ErrorResponse {
+    let mut resp = ErrorResponse::new(None);
+    *resp.status_mut() = status;
+    resp
+}
+
+// accept 完成 HTTP Upgrade 握手
+// 只接受 path 与共享密钥一致、并携带合法目的地请求头的连接
+pub async fn accept(stream: TcpStream, path: &str) -> io::Result<(WsStream, Destination)> {
+    let mut dest = None;
+    // ErrorResponse 的大小由 tungstenite 决定
+    #[allow(clippy::result_large_err)]
+    let callback = |req: &Request, resp: Response| {
+        if req.uri().path() != path {
+            debug!("websocket path mismatch {}", req.uri().path());
+            return Err(reject(StatusCode::NOT_FOUND));
+        }
+        dest = req
+            .headers()
+            .get(DESTINATION_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse().ok());
+        match dest {
+            Some(_) => Ok(resp),
+            None => Err(reject(StatusCode::BAD_REQUEST)),
+        }
+    };
+    let inner = accept_hdr_async(stream, callback).await.map_err(ws_error)?;
+    let dest = dest.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "websocket, missing destination",
+        )
+    })?;
+    Ok((
+        WsStream {
+            inner,
+            pending: Vec::new(),
+            pos: 0,
+        },
+        dest,
+    ))
+}
+
+// WsStream 将 binary message 转换为字节流
+pub struct WsStream {
+    inner: WebSocketStream<TcpStream>,
+    pending: Vec<u8>,
+    pos: usize,
+}
+
+impl WsStream {
+    pub fn get_ref(&self) -> &TcpStream {
+        self.inner.get_ref()
+    }
+}
+
+impl AsyncRead for WsStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if this.pos < this.pending.len() {
+                let n = std::cmp::min(buf.remaining(), this.pending.len() - this.pos);
+                buf.put_slice(&this.pending[this.pos..this.pos + n]);
+                this.pos += n;
+                return Poll::Ready(Ok(()));
+            }
+            let data = match Pin::new(&mut this.inner).poll_next(cx) {
+                Poll::Pending => return Poll::Pending,
+                // 对端关闭视为 EOF
+                Poll::Ready(None)
+                | Poll::Ready(Some(Ok(Message::Close(_))))
+                | Poll::Ready(Some(Err(WsError::ConnectionClosed)))
+                | Poll::Ready(Some(Err(WsError::Protocol(
+                    ProtocolError::ResetWithoutClosingHandshake,
+                )))) => return Poll::Ready(Ok(())),
+                Poll::Ready(Some(Err(err))) => return Poll::Ready(Err(ws_error(err))),
+                Poll::Ready(Some(Ok(Message::Binary(data)))) => data,
+                Poll::Ready(Some(Ok(Message::Text(text)))) => text.into_bytes(),
+                // ping 由 tungstenite 自动回复
+                Poll::Ready(Some(Ok(_))) => continue,
+            };
+            this.pending = data;
+            this.pos = 0;
+        }
+    }
+}
+
+impl AsyncWrite for WsStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut inner = Pin::new(&mut self.get_mut().inner);
+        if let Err(err) = futures_util::ready!(inner.as_mut().poll_ready(cx)) {
+            return Poll::Ready(Err(ws_error(err)));
+        }
+        inner
+            .as_mut()
+            .start_send(Message::Binary(buf.to_vec()))
+            .map_err(ws_error)?;
+        // 尽量立即发出，失败或 pending 时由后续 poll_ready 继续 flush
+        let _ = inner.poll_flush(cx);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner)
+            .poll_flush(cx)
+            .map_err(ws_error)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match Pin::new(&mut self.get_mut().inner).poll_close(cx) {
+            Poll::Ready(Err(WsError::ConnectionClosed)) => Poll::Ready(Ok(())),
+            result => result.map_err(ws_error),
+        }
+    }
+}
@@ -0,0 +1,25 @@
+// wildcard_match 匹配 * (任意个字符) 和 ? (单个字符)
+// 使用回溯到最近一个 * 的方式，最坏情况 O(pattern * name)，不会因为多个 * 指数级回溯
+pub fn wildcard_match(pattern: &[u8], name: &[u8], ignore_case: bool) -> bool {
+    let eq = |p: u8, n: u8| p == b'?' || p == n || (ignore_case && p.eq_ignore_ascii_case(&n));
+    let (mut p, mut n) = (0, 0);
+    // 最近一个 * 的位置，以及它当前匹配到的 name 位置
+    let mut star: Option<(usize, usize)> = None;
+    while n < name.len() {
+        if p < pattern.len() && pattern[p] == b'*' {
+            star = Some((p, n));
+            p += 1;
+        } else if p < pattern.len() && eq(pattern[p], name[n]) {
+            p += 1;
+            n += 1;
+        } else if let Some((star_p, star_n)) = star {
+            // 让 * 多匹配一个字符
+            p = star_p + 1;
+            n = star_n + 1;
+            star = Some((star_p, n));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
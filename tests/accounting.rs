@@ -0,0 +1,176 @@
+use std::{fs, io::Write, net::IpAddr, path::PathBuf, sync::Arc, time::Duration};
+
+use socket_proxy::accounting::{Accounting, FsyncPolicy, Usage};
+
+fn journal_path(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!(
+        "socket_proxy_accounting_{}_{}",
+        name,
+        std::process::id()
+    ));
+    let _ = fs::remove_file(&path);
+    path
+}
+
+// record 记录一条传输了 up/down 字节后结束的连接
+fn record(accounting: &Arc<Accounting>, ip: IpAddr, up: u64, down: u64) {
+    accounting.connection(ip).update(up, down);
+}
+
+#[test]
+fn usage_survives_restart() {
+    let path = journal_path("restart");
+    let client: IpAddr = "192.168.1.10".parse().unwrap();
+    let accounting = Accounting::open(&path, FsyncPolicy::Always).unwrap();
+    record(&accounting, client, 100, 2000);
+    accounting.flush().unwrap();
+    record(&accounting, client, 1, 20);
+    accounting.flush().unwrap();
+    // 没有 flush 的部分在重启后丢失
+    record(&accounting, client, 5, 5);
+    drop(accounting);
+
+    let accounting = Accounting::open(&path, FsyncPolicy::Always).unwrap();
+    assert_eq!(
+        accounting.usage()[&client],
+        Usage {
+            connections: 2,
+            up: 101,
+            down: 2020
+        }
+    );
+    // 打开时已经压缩为每个客户端一行
+    assert_eq!(
+        fs::read_to_string(&path).unwrap(),
+        "192.168.1.10 2 101 2020\n"
+    );
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn torn_last_line_is_dropped() {
+    let path = journal_path("torn");
+    let mut file = fs::File::create(&path).unwrap();
+    file.write_all(b"10.0.0.1 1 10 20\n10.0.0.1 1 10 20\n10.0.0.2 1 3")
+        .unwrap();
+    drop(file);
+
+    let accounting = Accounting::open(&path, FsyncPolicy::Never).unwrap();
+    let usage = accounting.usage();
+    assert_eq!(usage.len(), 1);
+    assert_eq!(usage[&"10.0.0.1".parse::<IpAddr>().unwrap()].up, 20);
+    // 之后追加的增量不会和残缺的行拼在一起
+    record(&accounting, "10.0.0.2".parse().unwrap(), 7, 8);
+    accounting.flush().unwrap();
+    drop(accounting);
+    let accounting = Accounting::open(&path, FsyncPolicy::Never).unwrap();
+    assert_eq!(accounting.usage().len(), 2);
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn torn_line_without_newline_is_dropped_even_if_it_parses() {
+    let path = journal_path("torn_parses");
+    // 最后一行 "10.0.0.2 1 30 400" 被截断成了 "10.0.0.2 1 30 4"
+    fs::write(&path, "10.0.0.1 1 10 20\n10.0.0.2 1 30 4").unwrap();
+    let accounting = Accounting::open(&path, FsyncPolicy::Never).unwrap();
+    let usage = accounting.usage();
+    assert_eq!(usage.len(), 1);
+    assert!(usage.contains_key(&"10.0.0.1".parse::<IpAddr>().unwrap()));
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn open_connections_are_accounted_while_running() {
+    let path = journal_path("live");
+    let client: IpAddr = "10.0.0.3".parse().unwrap();
+    let accounting = Accounting::open(&path, FsyncPolicy::Never).unwrap();
+    let mut connection = accounting.connection(client);
+    connection.update(100, 2000);
+    accounting.flush().unwrap();
+    connection.update(150, 2000);
+    // 连接结束之前字节数已经写入日志，连接数在结束时计入
+    let usage = Usage {
+        connections: 0,
+        up: 150,
+        down: 2000,
+    };
+    assert_eq!(accounting.usage()[&client], usage);
+    drop(connection);
+    assert_eq!(
+        accounting.usage()[&client],
+        Usage {
+            connections: 1,
+            ..usage
+        }
+    );
+    accounting.flush().unwrap();
+    drop(accounting);
+    let accounting = Accounting::open(&path, FsyncPolicy::Never).unwrap();
+    assert_eq!(
+        accounting.usage()[&client],
+        Usage {
+            connections: 1,
+            ..usage
+        }
+    );
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn failed_compaction_keeps_the_journal_usable() {
+    let path = journal_path("compact_fail");
+    let client: IpAddr = "192.168.100.100".parse().unwrap();
+    let accounting = Accounting::open(&path, FsyncPolicy::Never).unwrap();
+    // 临时文件的位置被目录占用，压缩失败
+    let mut tmp_path = path.clone().into_os_string();
+    tmp_path.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_path);
+    fs::create_dir(&tmp_path).unwrap();
+    let mut flushes = 0;
+    let err = loop {
+        record(&accounting, client, 1_000_000_000, 1_000_000_000);
+        flushes += 1;
+        if let Err(err) = accounting.flush() {
+            break err;
+        }
+        assert!(flushes < 10_000, "compaction never ran");
+    };
+    assert!(!err.to_string().is_empty());
+    fs::remove_dir(&tmp_path).unwrap();
+    // 之后的增量仍然写入日志
+    record(&accounting, client, 1, 1);
+    accounting.flush().unwrap();
+    drop(accounting);
+    let accounting = Accounting::open(&path, FsyncPolicy::Never).unwrap();
+    assert_eq!(accounting.usage()[&client].connections, flushes + 1);
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn journal_is_compacted() {
+    let path = journal_path("compact");
+    let client: IpAddr = "::1".parse().unwrap();
+    let accounting = Accounting::open(&path, FsyncPolicy::Never).unwrap();
+    for _ in 0..10_000 {
+        record(&accounting, client, 1, 1);
+        accounting.flush().unwrap();
+    }
+    assert!(fs::metadata(&path).unwrap().len() < 64 * 1024);
+    drop(accounting);
+    let accounting = Accounting::open(&path, FsyncPolicy::Never).unwrap();
+    assert_eq!(accounting.usage()[&client].connections, 10_000);
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn fsync_policy_parses() {
+    assert_eq!("always".parse(), Ok(FsyncPolicy::Always));
+    assert_eq!("never".parse(), Ok(FsyncPolicy::Never));
+    assert_eq!(
+        "interval:60".parse(),
+        Ok(FsyncPolicy::Interval(Duration::from_secs(60)))
+    );
+    assert!("interval:soon".parse::<FsyncPolicy>().is_err());
+    assert!("sometimes".parse::<FsyncPolicy>().is_err());
+}
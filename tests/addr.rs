@@ -0,0 +1,93 @@
+use std::net::{IpAddr, SocketAddr, SocketAddrV6};
+
+use socket_proxy::{
+    addr::{canonical_ip, canonical_socket_addr, is_loopback, same_socket_addr},
+    client::Destination,
+    local::LocalAddrs,
+    loop_guard::EgressRegistry,
+    rules::{Cidr, RuleConfig, Rules},
+};
+
+fn ip(s: &str) -> IpAddr {
+    s.parse().unwrap()
+}
+
+fn sock(s: &str) -> SocketAddr {
+    s.parse().unwrap()
+}
+
+#[test]
+fn v4_mapped_addresses_become_ipv4() {
+    assert_eq!(canonical_ip(ip("::ffff:10.1.2.3")), ip("10.1.2.3"));
+    assert_eq!(
+        canonical_socket_addr(sock("[::ffff:10.1.2.3]:443")),
+        sock("10.1.2.3:443")
+    );
+    assert!(same_socket_addr(
+        sock("[::ffff:192.168.1.1]:80"),
+        sock("192.168.1.1:80")
+    ));
+    assert!(!same_socket_addr(
+        sock("[::ffff:192.168.1.1]:80"),
+        sock("192.168.1.1:81")
+    ));
+}
+
+#[test]
+fn ipv6_addresses_are_kept() {
+    assert_eq!(canonical_ip(ip("2001:db8::1")), ip("2001:db8::1"));
+    // IPv4-compatible (::a.b.c.d) 已废弃，不做转换
+    assert_eq!(canonical_ip(ip("::10.1.2.3")), ip("::10.1.2.3"));
+    let with_flow = SocketAddr::V6(SocketAddrV6::new("2001:db8::1".parse().unwrap(), 443, 7, 0));
+    assert_eq!(canonical_socket_addr(with_flow), sock("[2001:db8::1]:443"));
+    assert!(same_socket_addr(with_flow, sock("[2001:db8::1]:443")));
+    let scoped = SocketAddr::V6(SocketAddrV6::new("fe80::1".parse().unwrap(), 22, 0, 2));
+    assert_ne!(canonical_socket_addr(scoped), sock("[fe80::1]:22"));
+}
+
+#[test]
+fn loopback_in_every_form() {
+    assert!(is_loopback(ip("127.0.0.1")));
+    assert!(is_loopback(ip("127.8.9.10")));
+    assert!(is_loopback(ip("::1")));
+    assert!(is_loopback(ip("::ffff:127.0.0.1")));
+    assert!(!is_loopback(ip("::ffff:10.0.0.1")));
+    assert!(!is_loopback(ip("2001:db8::1")));
+}
+
+#[test]
+fn cidr_matches_mapped_addresses() {
+    let cidr: Cidr = "10.0.0.0/8".parse().unwrap();
+    assert!(cidr.contains(ip("10.2.3.4")));
+    assert!(cidr.contains(ip("::ffff:10.2.3.4")));
+    assert!(!cidr.contains(ip("::ffff:11.2.3.4")));
+    let v6: Cidr = "2001:db8::/32".parse().unwrap();
+    assert!(v6.contains(ip("2001:db8:1::1")));
+    assert!(!v6.contains(ip("10.2.3.4")));
+}
+
+#[test]
+fn rules_match_mapped_destinations() {
+    let rules = Rules::from_config(&[RuleConfig {
+        ips: vec!["192.168.0.0/16".into()],
+        mark: Some(1),
+        ..Default::default()
+    }])
+    .unwrap();
+    let dest: Destination = sock("[::ffff:192.168.3.4]:22").into();
+    assert_eq!(rules.find(&dest).and_then(|rule| rule.mark), Some(1));
+}
+
+#[test]
+fn egress_registry_compares_canonical_addresses() {
+    let registry = std::sync::Arc::new(EgressRegistry::default());
+    let local = LocalAddrs::default();
+    let guard = registry.register(sock("10.0.0.5:40000"));
+    assert!(registry.contains(&sock("[::ffff:10.0.0.5]:40000"), &local));
+    assert!(registry
+        .check(&sock("[::ffff:10.0.0.5]:40000"), &local)
+        .is_err());
+    assert!(registry.check(&sock("10.0.0.5:40001"), &local).is_ok());
+    drop(guard);
+    assert!(registry.is_empty());
+}
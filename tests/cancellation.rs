@@ -0,0 +1,128 @@
+// 在每个 await 点取消握手，检查上游连接被关闭、计数器和出站登记都恢复
+
+use std::{
+    future::{poll_fn, Future},
+    pin::Pin,
+    sync::Arc,
+    task::Poll,
+    time::Duration,
+};
+
+use socket_proxy::{
+    client::{Client, Destination},
+    config::Config,
+    conn_id::ConnId,
+    metrics::METRICS,
+    protocols::handshake,
+    testing::{Fault, MockUpstream},
+};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    time::{sleep, timeout},
+};
+
+// 每个测试最多尝试的 poll 次数，每次 poll 之间等待 2ms，远大于 SlowGreeting 的延迟
+const MAX_POLLS: usize = 500;
+
+// 最多 poll n 次，每次之间留出时间让 IO 就绪，返回 None 表示 future 还没有完成
+async fn poll_times<F>(fut: &mut Pin<Box<F>>, n: usize) -> Option<F::Output>
+where
+    F: Future + ?Sized,
+{
+    for _ in 0..n {
+        if let Poll::Ready(output) = poll_fn(|cx| Poll::Ready(fut.as_mut().poll(cx))).await {
+            return Some(output);
+        }
+        sleep(Duration::from_millis(2)).await;
+    }
+    None
+}
+
+async fn wait_closed(upstream: &MockUpstream) {
+    timeout(Duration::from_secs(5), async {
+        while upstream.open_connections() != 0 {
+            sleep(Duration::from_millis(1)).await;
+        }
+    })
+    .await
+    .expect("upstream connection was not closed after cancellation");
+}
+
+fn domain() -> Destination {
+    ("example.com", 443).into()
+}
+
+// inbound 返回代理一侧的入站连接和客户端一侧的连接
+async fn inbound() -> (TcpStream, TcpStream) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let client = TcpStream::connect(listener.local_addr().unwrap())
+        .await
+        .unwrap();
+    let (server, _) = listener.accept().await.unwrap();
+    (server, client)
+}
+
+#[tokio::test]
+async fn cancelled_upstream_handshake_closes_connection() {
+    let upstream = MockUpstream::spawn(Fault::SlowGreeting(Duration::from_millis(5)))
+        .await
+        .unwrap();
+    let mut completed = false;
+    for n in 0..=MAX_POLLS {
+        let mut stream = TcpStream::connect(upstream.addr()).await.unwrap();
+        let dest = domain();
+        let mut fut = Box::pin(handshake(&mut stream, &dest, Some(b"hello"), None));
+        let done = poll_times(&mut fut, n).await;
+        drop(fut);
+        drop(stream);
+        wait_closed(&upstream).await;
+        if let Some(result) = done {
+            result.unwrap();
+            completed = true;
+            break;
+        }
+    }
+    assert!(completed, "handshake not done after {} polls", MAX_POLLS);
+}
+
+#[tokio::test]
+async fn cancelled_connect_releases_counters_and_egress() {
+    let upstream = MockUpstream::spawn(Fault::SlowGreeting(Duration::from_millis(5)))
+        .await
+        .unwrap();
+    let config = Arc::new(Config::new(upstream.addr()));
+    let mut completed = false;
+    for n in 0..=MAX_POLLS {
+        let (left, _peer) = inbound().await;
+        let mut client =
+            Client::new(left.into(), domain(), config.clone(), ConnId::next()).unwrap();
+        let mut fut = Box::pin(client.connect_remote_server());
+        let done = poll_times(&mut fut, n).await;
+        drop(fut);
+        assert_eq!(
+            METRICS.upstream_connects.get(),
+            0,
+            "cancelled after {} polls",
+            n
+        );
+        match done {
+            None => {
+                drop(client);
+                assert!(config.egress.is_empty(), "cancelled after {} polls", n);
+                wait_closed(&upstream).await;
+            }
+            Some(result) => {
+                let remote = result.unwrap();
+                // 成功后出站登记随 Client 存在，直到连接关闭
+                assert_eq!(config.egress.len(), 1);
+                drop(remote);
+                drop(client);
+                assert!(config.egress.is_empty());
+                wait_closed(&upstream).await;
+                completed = true;
+                break;
+            }
+        }
+    }
+    assert!(completed, "connect not done after {} polls", MAX_POLLS);
+}
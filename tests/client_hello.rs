@@ -0,0 +1,35 @@
+use socket_proxy::{testing::client_hello, tls::parse_client_hello};
+
+fn server_name(data: &[u8]) -> Result<Option<String>, &'static str> {
+    parse_client_hello(data).map(|hello| hello.server_name.map(String::from))
+}
+
+#[test]
+fn finds_sni_after_other_extensions() {
+    // client_hello 在 SNI 之前放了一个空的扩展，解析器需要跳过整个扩展而不是进入它的数据
+    assert_eq!(
+        server_name(&client_hello(Some("example.com"))),
+        Ok(Some("example.com".into()))
+    );
+    assert_eq!(
+        server_name(&client_hello(Some("a.very.long.subdomain.example.org"))),
+        Ok(Some("a.very.long.subdomain.example.org".into()))
+    );
+}
+
+#[test]
+fn hello_without_sni() {
+    assert_eq!(server_name(&client_hello(None)), Ok(None));
+}
+
+#[test]
+fn rejects_malformed_hellos() {
+    let hello = client_hello(Some("example.com"));
+    // 截断在 SNI 扩展中间
+    assert!(server_name(&hello[..hello.len() - 4]).is_err());
+    let mut alert = hello.clone();
+    alert[0] = 0x15;
+    assert!(server_name(&alert).is_err());
+    assert!(server_name(b"GET / HTTP/1.1\r\n\r\n").is_err());
+    assert!(server_name(&[]).is_err());
+}
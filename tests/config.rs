@@ -0,0 +1,57 @@
+use socket_proxy::config::{ConfigFile, Permits};
+use tokio::sync::Semaphore;
+
+#[test]
+fn config_file_round_trips() {
+    let source = r#"
+        [[rules]]
+        domains = ["example.com"]
+        ports = [443]
+        mark = 7
+
+        [profiles.work]
+        rules = [{ ips = ["10.0.0.0/8"], dscp = 46 }]
+
+        [[listeners]]
+        listen = "127.0.0.1:1080"
+        role = "socks"
+        tls = true
+
+        [[outbounds]]
+        name = "primary"
+        socks5 = "10.0.0.1:1080"
+    "#;
+    let file: ConfigFile = toml::from_str(source).unwrap();
+    let emitted = toml::to_string(&file).unwrap();
+    // 空的字段不输出
+    assert!(!emitted.contains("include"));
+    assert!(!emitted.contains("domain_regex"));
+    let reparsed: ConfigFile = toml::from_str(&emitted).unwrap();
+    assert_eq!(toml::to_string(&reparsed).unwrap(), emitted);
+    assert_eq!(reparsed.rules[0].domains, ["example.com"]);
+    assert_eq!(reparsed.profiles["work"].rules[0].dscp, Some(46));
+    assert!(reparsed.listeners[0].tls);
+    assert_eq!(reparsed.outbounds[0].name(), "primary");
+    assert_eq!(
+        reparsed.outbounds[0].socks5,
+        Some("10.0.0.1:1080".parse().unwrap())
+    );
+}
+
+#[test]
+fn permits_are_bounded() {
+    assert_eq!("16".parse::<Permits>().unwrap().get(), 16);
+    assert_eq!(
+        Permits::new(Semaphore::MAX_PERMITS).unwrap().get(),
+        Semaphore::MAX_PERMITS
+    );
+    let semaphore = Permits::new(2).unwrap().semaphore();
+    assert_eq!(semaphore.available_permits(), 2);
+    // 0 个许可会让所有连接永远等待
+    assert!("0".parse::<Permits>().unwrap_err().contains("0"));
+    // 超过上限时 Semaphore::new 会 panic
+    let err = Permits::new(Semaphore::MAX_PERMITS + 1).unwrap_err();
+    assert!(err.contains("too large"), "{}", err);
+    assert!("-1".parse::<Permits>().is_err());
+    assert!("many".parse::<Permits>().is_err());
+}
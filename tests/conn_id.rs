@@ -0,0 +1,60 @@
+use std::{collections::HashSet, future::pending, io, thread, time::Duration};
+
+use socket_proxy::{
+    budget::Budget,
+    conn_id::ConnId,
+    metrics::{Exemplar, METRICS},
+};
+
+#[test]
+fn ids_are_unique_across_threads() {
+    let handles: Vec<_> = (0..4)
+        .map(|_| thread::spawn(|| (0..1000).map(|_| ConnId::next()).collect::<Vec<_>>()))
+        .collect();
+    let mut seen = HashSet::new();
+    for handle in handles {
+        let ids = handle.join().unwrap();
+        // 同一个线程内递增
+        assert!(ids.windows(2).all(|pair| pair[0] < pair[1]));
+        for id in ids {
+            assert_ne!(id, ConnId::default());
+            assert!(seen.insert(id), "duplicate {}", id);
+        }
+    }
+    assert_eq!(seen.len(), 4000);
+}
+
+#[test]
+fn ids_display_with_hash() {
+    assert_eq!(ConnId::from(42).to_string(), "#42");
+    assert_eq!(ConnId::from(42).get(), 42);
+    assert_eq!(ConnId::default().get(), 0);
+}
+
+#[test]
+fn exemplar_keeps_last_id() {
+    let exemplar = Exemplar::new();
+    assert_eq!(exemplar.get(), None);
+    assert_eq!(exemplar.to_string(), "");
+    exemplar.record(ConnId::from(7));
+    exemplar.record(ConnId::from(9));
+    assert_eq!(exemplar.get(), Some(ConnId::from(9)));
+    assert_eq!(exemplar.to_string(), "(#9)");
+}
+
+#[tokio::test]
+async fn establish_timeout_records_exemplar() {
+    let id = ConnId::next();
+    let mut budget = Budget::new(id, Some(Duration::from_millis(10)));
+    let result = budget
+        .run("sniff", pending::<io::Result<()>>())
+        .await
+        .unwrap_err();
+    assert_eq!(result.kind(), io::ErrorKind::TimedOut);
+    assert_eq!(METRICS.establish_timeouts_last.get(), Some(id));
+    assert!(METRICS.to_string().contains(&format!(
+        "establish_timeouts={}({})",
+        METRICS.establish_timeouts.get(),
+        id
+    )));
+}
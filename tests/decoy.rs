@@ -0,0 +1,81 @@
+use std::{sync::Arc, time::Duration};
+
+use socket_proxy::{
+    config::Config,
+    decoy::{ProbeResponse, MAX_DELAY},
+    listener::Role,
+    server::ProxyServer,
+};
+use tokio::{
+    io::{duplex, AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    time::{timeout, Instant},
+};
+
+#[test]
+fn parses_modes() {
+    assert_eq!("close".parse(), Ok(ProbeResponse::Close));
+    assert_eq!("http".parse(), Ok(ProbeResponse::Http));
+    assert_eq!(
+        "delay:30".parse(),
+        Ok(ProbeResponse::Delay(Duration::from_secs(30)))
+    );
+    assert_eq!("delay:60".parse(), Ok(ProbeResponse::Delay(MAX_DELAY)));
+    // 等待期间占用连接数，不能无限长
+    assert!("delay:61".parse::<ProbeResponse>().is_err());
+    assert!("delay:".parse::<ProbeResponse>().is_err());
+    assert!("banner".parse::<ProbeResponse>().is_err());
+}
+
+#[tokio::test]
+async fn http_answers_with_404() {
+    let (mut client, mut server) = duplex(4096);
+    client.write_all(b"GET / HTTP/1.0\r\n\r\n").await.unwrap();
+    let respond = tokio::spawn(async move { ProbeResponse::Http.respond(&mut server).await });
+    let mut response = String::new();
+    client.read_to_string(&mut response).await.unwrap();
+    drop(client);
+    respond.await.unwrap();
+    assert!(response.starts_with("HTTP/1.1 404 Not Found\r\nServer: nginx\r\n"));
+    assert!(response.ends_with("</html>\r\n"));
+}
+
+#[tokio::test(start_paused = true)]
+async fn delay_holds_connection_silently() {
+    let (mut client, mut server) = duplex(4096);
+    let start = Instant::now();
+    let respond = tokio::spawn(async move {
+        ProbeResponse::Delay(Duration::from_secs(10))
+            .respond(&mut server)
+            .await
+    });
+    client.write_all(b"\x16\x03\x01").await.unwrap();
+    let mut buf = Vec::new();
+    // 服务端结束后 duplex 的另一端才读到 EOF
+    respond.await.unwrap();
+    client.read_to_end(&mut buf).await.unwrap();
+    assert!(buf.is_empty());
+    assert!(start.elapsed() >= Duration::from_secs(10));
+}
+
+#[tokio::test]
+async fn stalled_socks_handshake_gets_probe_response() {
+    let mut config = Config::new("127.0.0.1:1".parse().unwrap());
+    config.handshake_timeout = Duration::from_millis(200);
+    config.probe_response = ProbeResponse::Http;
+    let server = ProxyServer::new(Arc::new(config), None);
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let stats = server.register_listener("decoy-test".into(), Role::Socks);
+    tokio::spawn(server.serve_listener(Arc::new(listener), stats, None));
+
+    // 发送 greeting 的一部分之后停住
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream.write_all(&[0x05, 0x02]).await.unwrap();
+    let mut response = Vec::new();
+    timeout(Duration::from_secs(5), stream.read_to_end(&mut response))
+        .await
+        .expect("handshake timeout should answer the connection")
+        .unwrap();
+    assert!(response.starts_with(b"HTTP/1.1 404 Not Found\r\n"));
+}
@@ -0,0 +1,56 @@
+use std::time::Duration;
+
+use socket_proxy::deny::DenyList;
+use tokio::{
+    net::{TcpListener, TcpStream},
+    time::timeout,
+};
+
+fn deny(cidrs: &[&str]) -> DenyList {
+    DenyList::new(cidrs.iter().map(|cidr| cidr.parse().unwrap()).collect())
+}
+
+// accepted 返回在 filter 挂载后，客户端连接能否被 accept
+async fn accepted(bind: &str, deny: &DenyList) -> bool {
+    let listener = TcpListener::bind(bind).await.unwrap();
+    deny.attach(&listener).unwrap();
+    let addr = listener.local_addr().unwrap();
+    // 被丢弃的 SYN 会重传，connect 不会很快返回
+    let _client = tokio::spawn(async move { TcpStream::connect(addr).await });
+    timeout(Duration::from_millis(300), listener.accept())
+        .await
+        .is_ok()
+}
+
+#[test]
+fn contains_matches_networks() {
+    let list = deny(&["10.0.0.0/8", "2001:db8::/32"]);
+    assert!(list.contains("10.1.2.3".parse().unwrap()));
+    assert!(list.contains("::ffff:10.1.2.3".parse().unwrap()));
+    assert!(list.contains("2001:db8::5".parse().unwrap()));
+    assert!(!list.contains("192.168.1.1".parse().unwrap()));
+}
+
+#[tokio::test]
+async fn filter_drops_denied_ipv4_sources() {
+    assert!(!accepted("127.0.0.1:0", &deny(&["127.0.0.0/8"])).await);
+    assert!(!accepted("127.0.0.1:0", &deny(&["10.0.0.0/8", "127.0.0.1"])).await);
+    assert!(accepted("127.0.0.1:0", &deny(&["10.0.0.0/8", "2001:db8::/32"])).await);
+    assert!(accepted("127.0.0.1:0", &deny(&[])).await);
+}
+
+#[tokio::test]
+async fn filter_drops_denied_ipv6_sources() {
+    assert!(!accepted("[::1]:0", &deny(&["::1/128"])).await);
+    assert!(!accepted("[::1]:0", &deny(&["::/0"])).await);
+    assert!(accepted("[::1]:0", &deny(&["::2/128", "127.0.0.0/8"])).await);
+    assert!(accepted("[::1]:0", &deny(&["2001:db8::/32", "fe80::/10"])).await);
+}
+
+#[test]
+fn too_many_networks_are_rejected() {
+    let cidrs: Vec<_> = (0..1100)
+        .map(|i| format!("10.{}.{}.0/24", i / 256, i % 256).parse().unwrap())
+        .collect();
+    assert!(DenyList::new(cidrs).program().is_err());
+}
@@ -0,0 +1,70 @@
+use std::time::Duration;
+
+use socket_proxy::{
+    metrics::METRICS,
+    protocols::detect::{detect, Protocol},
+    stream::pipe,
+};
+use tokio::{
+    io::{duplex, AsyncReadExt, AsyncWriteExt},
+    time::timeout,
+};
+
+#[test]
+fn detects_protocols_from_first_bytes() {
+    assert_eq!(detect(&[0x16, 0x03, 0x01, 0x02, 0x00]), Protocol::Tls);
+    assert_eq!(detect(&[0x16, 0x03]), Protocol::Tls);
+    assert_eq!(detect(b"SSH-2.0-OpenSSH_9.6\r\n"), Protocol::Ssh);
+    assert_eq!(detect(b"GET / HTTP/1.1\r\n"), Protocol::Http);
+    assert_eq!(
+        detect(b"CONNECT example.com:443 HTTP/1.1\r\n"),
+        Protocol::Http
+    );
+    assert_eq!(detect(b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n"), Protocol::Http);
+}
+
+#[test]
+fn short_or_unknown_data_is_unknown() {
+    assert_eq!(detect(&[]), Protocol::Unknown);
+    assert_eq!(detect(&[0x16]), Protocol::Unknown);
+    // TLS 记录但不是 SSLv3/TLS 1.x
+    assert_eq!(detect(&[0x16, 0x02, 0x00]), Protocol::Unknown);
+    assert_eq!(detect(b"SSH"), Protocol::Unknown);
+    assert_eq!(detect(b"GET"), Protocol::Unknown);
+    // 方法名区分大小写，并且后面必须有空格
+    assert_eq!(detect(b"get / HTTP/1.1\r\n"), Protocol::Unknown);
+    assert_eq!(detect(b"GETX / HTTP/1.1\r\n"), Protocol::Unknown);
+    assert_eq!(detect(&[0x05, 0x01, 0x00]), Protocol::Unknown);
+}
+
+#[test]
+fn protocol_names() {
+    let names: Vec<_> = Protocol::ALL.iter().map(|p| p.to_string()).collect();
+    assert_eq!(names, ["tls", "http", "ssh", "unknown"]);
+}
+
+#[tokio::test]
+async fn initial_data_counts_towards_protocol_bytes() {
+    let ssh = METRICS.protocol(Protocol::Ssh);
+    let (connections, bytes_up) = (ssh.connections.get(), ssh.bytes_up.get());
+    let (mut client, left) = duplex(1024);
+    let (right, mut server) = duplex(1024);
+    // 握手时已经发给上游的数据
+    let initial = b"SSH-2.0-test\r\n";
+    let mut pipe = pipe(left, right).with_initial_data(initial);
+    assert_eq!(pipe.protocol(), Some(Protocol::Ssh));
+    client.write_all(b"more").await.unwrap();
+    client.shutdown().await.unwrap();
+    server.shutdown().await.unwrap();
+    timeout(Duration::from_secs(5), &mut pipe)
+        .await
+        .unwrap()
+        .unwrap();
+    let mut buf = Vec::new();
+    server.read_to_end(&mut buf).await.unwrap();
+    assert_eq!(buf, b"more");
+    assert_eq!(pipe.totals(), (initial.len() as u64 + 4, 0));
+    drop(pipe);
+    assert_eq!(ssh.connections.get() - connections, 1);
+    assert_eq!(ssh.bytes_up.get() - bytes_up, initial.len() as u64 + 4);
+}
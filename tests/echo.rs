@@ -0,0 +1,68 @@
+use std::{net::SocketAddr, sync::Arc};
+
+use socket_proxy::{
+    config::Config,
+    echo::{ECHO_HOST, ECHO_PORT},
+    listener::Role,
+    server::ProxyServer,
+    testing::{Fault, MockUpstream},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+async fn spawn_proxy(upstream: &MockUpstream, echo_sources: &[&str]) -> SocketAddr {
+    let mut config = Config::new(upstream.addr());
+    config.echo_sources = echo_sources.iter().map(|s| s.parse().unwrap()).collect();
+    let server = ProxyServer::new(Arc::new(config), None);
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let stats = server.register_listener("echo-test".into(), Role::Socks);
+    tokio::spawn(server.serve_listener(Arc::new(listener), stats, None));
+    addr
+}
+
+// echo 通过代理 CONNECT 回显目的地，返回对方发回的数据
+async fn echo(proxy: SocketAddr) -> Vec<u8> {
+    let mut stream = TcpStream::connect(proxy).await.unwrap();
+    stream.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+    let mut request = vec![0x05, 0x01, 0x00, 0x03, ECHO_HOST.len() as u8];
+    request.extend_from_slice(ECHO_HOST.as_bytes());
+    request.extend_from_slice(&ECHO_PORT.to_be_bytes());
+    stream.write_all(&request).await.unwrap();
+    let mut reply = [0u8; 12];
+    stream.read_exact(&mut reply).await.unwrap();
+    stream.write_all(b"hello").await.unwrap();
+    let mut buf = [0u8; 5];
+    stream.read_exact(&mut buf).await.unwrap();
+    buf.to_vec()
+}
+
+#[tokio::test]
+async fn echo_is_answered_for_allowed_sources() {
+    let upstream = MockUpstream::spawn(Fault::None).await.unwrap();
+    let proxy = spawn_proxy(&upstream, &["127.0.0.0/8"]).await;
+    assert_eq!(echo(proxy).await, b"hello");
+    assert!(upstream.requests().is_empty());
+}
+
+#[tokio::test]
+async fn echo_is_disabled_by_default() {
+    // MockUpstream 同样回显数据，只能从上游收到的请求区分
+    let upstream = MockUpstream::spawn(Fault::None).await.unwrap();
+    let proxy = spawn_proxy(&upstream, &[]).await;
+    assert_eq!(echo(proxy).await, b"hello");
+    assert_eq!(
+        upstream.requests(),
+        [format!("{}:{}", ECHO_HOST, ECHO_PORT)]
+    );
+}
+
+#[tokio::test]
+async fn echo_requires_matching_source() {
+    let upstream = MockUpstream::spawn(Fault::None).await.unwrap();
+    let proxy = spawn_proxy(&upstream, &["10.0.0.0/8", "::1/128"]).await;
+    echo(proxy).await;
+    assert_eq!(upstream.requests().len(), 1);
+}
@@ -0,0 +1,28 @@
+use socket_proxy::firewall::FirewallExclusion;
+
+#[test]
+fn builds_iptables_arguments() {
+    let firewall = FirewallExclusion::new("OUTPUT", "10.0.0.1:1080".parse().unwrap());
+    assert_eq!(
+        firewall.args("-I").join(" "),
+        "-w -t nat -I OUTPUT 1 -p tcp -d 10.0.0.1 --dport 1080 \
+         -m comment --comment socket_proxy upstream -j RETURN"
+    );
+    let firewall = FirewallExclusion::new("PROXY", "[2001:db8::1]:1080".parse().unwrap());
+    assert_eq!(
+        firewall.args("-D").join(" "),
+        "-w -t nat -D PROXY -p tcp -d 2001:db8::1 --dport 1080 \
+         -m comment --comment socket_proxy upstream -j RETURN"
+    );
+}
+
+#[tokio::test]
+async fn only_inserted_rules_are_removed() {
+    // 链不存在 (或者没有 iptables) 时插入失败，之后删除不再调用 iptables
+    let mut firewall = FirewallExclusion::new(
+        "SOCKET_PROXY_MISSING_CHAIN",
+        "10.0.0.1:1080".parse().unwrap(),
+    );
+    assert!(firewall.install().await.is_err());
+    firewall.remove().await.unwrap();
+}
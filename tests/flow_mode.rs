@@ -0,0 +1,132 @@
+use std::time::Duration;
+
+use socket_proxy::stream::{pipe, BiPipe, FlowMode};
+use tokio::{
+    io::{duplex, AsyncReadExt, AsyncWriteExt, DuplexStream},
+    time::timeout,
+};
+
+type Pipe = BiPipe<DuplexStream, DuplexStream>;
+
+// drive 让 pipe 处理已经到达的数据
+async fn drive(pipe: &mut Pipe) {
+    assert!(timeout(Duration::from_millis(5), &mut *pipe).await.is_err());
+}
+
+// exchange 客户端和服务端各发送一个消息，经过 pipe 之后由对方读出
+async fn exchange(
+    pipe: &mut Pipe,
+    client: &mut DuplexStream,
+    server: &mut DuplexStream,
+    request: &[u8],
+    response: &[u8],
+) {
+    client.write_all(request).await.unwrap();
+    drive(pipe).await;
+    let mut buf = vec![0u8; request.len()];
+    server.read_exact(&mut buf).await.unwrap();
+    assert_eq!(buf, request);
+    server.write_all(response).await.unwrap();
+    drive(pipe).await;
+    let mut buf = vec![0u8; response.len()];
+    client.read_exact(&mut buf).await.unwrap();
+    assert_eq!(buf, response);
+}
+
+#[tokio::test]
+async fn small_exchanges_switch_to_interactive() {
+    let (mut client, left) = duplex(64 * 1024);
+    let (right, mut server) = duplex(64 * 1024);
+    let mut pipe = pipe(left, right);
+    for _ in 0..3 {
+        exchange(&mut pipe, &mut client, &mut server, b"ls\n", b"file\n").await;
+    }
+    assert_eq!(pipe.mode(), FlowMode::Normal);
+    exchange(&mut pipe, &mut client, &mut server, b"ls\n", b"file\n").await;
+    assert_eq!(pipe.mode(), FlowMode::Interactive);
+
+    // 一个方向出现大包后退出交互模式
+    let output = vec![b'x'; 4096];
+    exchange(&mut pipe, &mut client, &mut server, b"cat\n", &output).await;
+    assert_eq!(pipe.mode(), FlowMode::Normal);
+}
+
+#[tokio::test]
+async fn one_sided_small_writes_stay_normal() {
+    let (mut client, left) = duplex(64 * 1024);
+    let (right, mut server) = duplex(64 * 1024);
+    let mut pipe = pipe(left, right);
+    // 只有一个方向是小包 (例如上传时的 ACK 式应答) 不是交互式的
+    let response = vec![b'y'; 4096];
+    for _ in 0..8 {
+        exchange(&mut pipe, &mut client, &mut server, b"k", &response).await;
+    }
+    assert_eq!(pipe.mode(), FlowMode::Normal);
+}
+
+// pump 从 client 发送 total 字节，server 每次读出 chunk 字节，读的过程中不断驱动 pipe
+async fn pump(
+    pipe: &mut Pipe,
+    client: &mut DuplexStream,
+    server: &mut DuplexStream,
+    total: usize,
+    chunk: usize,
+) {
+    let data = vec![0x5a; total];
+    let writer = async {
+        client.write_all(&data).await.unwrap();
+    };
+    let reader = async {
+        let mut received = 0;
+        let mut buf = vec![0u8; chunk];
+        while received < total {
+            let n = server.read(&mut buf).await.unwrap();
+            assert!(n > 0);
+            received += n;
+            // 慢速的接收端，让 pipe 的写入阻塞
+            tokio::task::yield_now().await;
+        }
+    };
+    let driver = async {
+        loop {
+            let _ = (&mut *pipe).await;
+        }
+    };
+    tokio::select! {
+        _ = async { tokio::join!(writer, reader) } => (),
+        _ = driver => unreachable!(),
+    }
+}
+
+#[tokio::test]
+async fn sustained_transfer_switches_to_bulk() {
+    let (mut client, left) = duplex(1 << 20);
+    let (right, mut server) = duplex(1 << 20);
+    let mut pipe = pipe(left, right);
+    pump(&mut pipe, &mut client, &mut server, 4 << 20, 64 * 1024).await;
+    assert_eq!(pipe.mode(), FlowMode::Bulk);
+}
+
+#[tokio::test]
+async fn backpressured_transfer_switches_to_bulk() {
+    let (mut client, left) = duplex(1 << 20);
+    // 接收方向的缓冲很小，pipe 的写入经常阻塞，数据转移到私有缓冲区
+    let (right, mut server) = duplex(4096);
+    let mut pipe = pipe(left, right);
+    pump(&mut pipe, &mut client, &mut server, 2 << 20, 4096).await;
+    assert_eq!(pipe.mode(), FlowMode::Bulk);
+}
+
+#[tokio::test]
+async fn interactive_session_switches_to_bulk_under_backpressure() {
+    let (mut client, left) = duplex(1 << 20);
+    let (right, mut server) = duplex(4096);
+    let mut pipe = pipe(left, right);
+    for _ in 0..4 {
+        exchange(&mut pipe, &mut client, &mut server, b"ls\n", b"file\n").await;
+    }
+    assert_eq!(pipe.mode(), FlowMode::Interactive);
+    // 交互会话中开始上传大文件
+    pump(&mut pipe, &mut client, &mut server, 1 << 20, 4096).await;
+    assert_eq!(pipe.mode(), FlowMode::Bulk);
+}
@@ -0,0 +1,113 @@
+use std::time::{Duration, UNIX_EPOCH};
+
+use socket_proxy::{
+    conn_id::ConnId,
+    hooks::{Event, EventKind, HookTarget, Hooks},
+};
+use tokio::{
+    io::{AsyncBufReadExt, BufReader},
+    net::UnixListener,
+    time::timeout,
+};
+
+fn event(kind: EventKind) -> Event {
+    Event {
+        kind,
+        time: UNIX_EPOCH + Duration::from_millis(1_700_000_000_250),
+        id: ConnId::from(7),
+        src: "192.0.2.1:5000".parse().unwrap(),
+        dest: "example.com:443".into(),
+        sni: Some("exa\"mple.com".into()),
+        protocol: Some("tls"),
+        up: 10,
+        down: 20,
+        duration: Some(Duration::from_millis(1500)),
+    }
+}
+
+#[test]
+fn parses_targets() {
+    assert_eq!(
+        "unix:/run/hook.sock".parse(),
+        Ok(HookTarget::Unix("/run/hook.sock".into()))
+    );
+    assert_eq!(
+        "logger -t proxy".parse(),
+        Ok(HookTarget::Command("logger -t proxy".into()))
+    );
+    assert!("unix:".parse::<HookTarget>().is_err());
+    assert!(" ".parse::<HookTarget>().is_err());
+}
+
+#[test]
+fn encodes_json() {
+    let open = event(EventKind::Open).to_json();
+    // 时间是事件发生的时间，不是编码的时间
+    assert!(open.starts_with("{\"event\":\"open\",\"time\":1700000000.250,"));
+    assert!(open.ends_with(
+        ",\"id\":7,\"src\":\"192.0.2.1:5000\",\"dest\":\"example.com:443\",\"sni\":\"exa\\\"mple.com\"}"
+    ));
+    let close = event(EventKind::Close).to_json();
+    assert!(close.ends_with(
+        ",\"sni\":\"exa\\\"mple.com\",\"protocol\":\"tls\",\"up\":10,\"down\":20,\"duration_ms\":1500}"
+    ));
+}
+
+#[tokio::test]
+async fn delivers_to_unix_socket() {
+    let path = std::env::temp_dir().join(format!("socket_proxy_hook_{}.sock", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let listener = UnixListener::bind(&path).unwrap();
+    let hooks = Hooks::spawn(HookTarget::Unix(path.clone()), 0);
+    assert!(hooks.open(event(EventKind::Open)));
+    hooks.close(event(EventKind::Close));
+    let (stream, _) = listener.accept().await.unwrap();
+    let mut lines = BufReader::new(stream).lines();
+    for kind in ["open", "close"] {
+        let line = timeout(Duration::from_secs(5), lines.next_line())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert!(line.starts_with(&format!("{{\"event\":\"{}\"", kind)));
+    }
+    let _ = std::fs::remove_file(&path);
+}
+
+#[tokio::test]
+async fn rate_limit_never_drops_close_of_admitted_open() {
+    let path = std::env::temp_dir().join(format!(
+        "socket_proxy_hook_rate_{}.sock",
+        std::process::id()
+    ));
+    let _ = std::fs::remove_file(&path);
+    let listener = UnixListener::bind(&path).unwrap();
+    let hooks = Hooks::spawn(HookTarget::Unix(path.clone()), 1);
+    let mut first = event(EventKind::Open);
+    first.id = ConnId::from(1);
+    let mut second = event(EventKind::Open);
+    second.id = ConnId::from(2);
+    assert!(hooks.open(first.clone()));
+    // 同一秒内第二个连接超出速率，它的 close 也不投递
+    assert!(!hooks.open(second));
+    first.kind = EventKind::Close;
+    hooks.close(first);
+    let (stream, _) = listener.accept().await.unwrap();
+    let mut lines = BufReader::new(stream).lines();
+    for kind in ["open", "close"] {
+        let line = timeout(Duration::from_secs(5), lines.next_line())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert!(line.starts_with(&format!("{{\"event\":\"{}\"", kind)));
+        assert!(line.contains(",\"id\":1,"), "{}", line);
+    }
+    drop(hooks);
+    let rest = timeout(Duration::from_secs(5), lines.next_line())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(rest, None);
+    let _ = std::fs::remove_file(&path);
+}
@@ -0,0 +1,130 @@
+// 入站监听在 IPv6 地址上时的 SOCKS5 握手和转发
+
+use std::{
+    net::{Ipv6Addr, SocketAddr},
+    sync::Arc,
+};
+
+use socket_proxy::{
+    client::{Address, Client},
+    config::Config,
+    conn_id::ConnId,
+    listener::Role,
+    testing::{Fault, MockUpstream},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+// socks_connect 以客户端身份完成握手，返回 CONNECT 回复
+async fn socks_connect(stream: &mut TcpStream, request: &[u8]) -> Vec<u8> {
+    stream.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+    let mut method = [0u8; 2];
+    stream.read_exact(&mut method).await.unwrap();
+    assert_eq!(method, [0x05, 0x00]);
+    stream.write_all(request).await.unwrap();
+    let mut head = [0u8; 4];
+    stream.read_exact(&mut head).await.unwrap();
+    let len = match head[3] {
+        0x01 => 4,
+        0x04 => 16,
+        atyp => panic!("unexpected atyp {}", atyp),
+    };
+    let mut rest = vec![0u8; len + 2];
+    stream.read_exact(&mut rest).await.unwrap();
+    [&head[..], &rest[..]].concat()
+}
+
+fn ipv6_request(ip: Ipv6Addr, port: u16) -> Vec<u8> {
+    let mut request = vec![0x05, 0x01, 0x00, 0x04];
+    request.extend_from_slice(&ip.octets());
+    request.extend_from_slice(&port.to_be_bytes());
+    request
+}
+
+#[tokio::test]
+async fn ipv6_client_gets_ipv6_bound_address() {
+    let upstream = MockUpstream::spawn(Fault::None).await.unwrap();
+    let config = Arc::new(Config::new(upstream.addr()));
+    let listener = TcpListener::bind("[::1]:0").await.unwrap();
+    let listen_addr = listener.local_addr().unwrap();
+
+    let client = tokio::spawn(async move {
+        let mut stream = TcpStream::connect(listen_addr).await.unwrap();
+        let dest: Ipv6Addr = "2001:db8::1".parse().unwrap();
+        let reply = socks_connect(&mut stream, &ipv6_request(dest, 443)).await;
+        let mut expected = vec![0x05, 0x00, 0x00, 0x04];
+        expected.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+        expected.extend_from_slice(&listen_addr.port().to_be_bytes());
+        assert_eq!(reply, expected);
+        stream.write_all(b"ping").await.unwrap();
+        let mut echo = [0u8; 4];
+        stream.read_exact(&mut echo).await.unwrap();
+        assert_eq!(&echo, b"ping");
+    });
+
+    let (socket, peer) = listener.accept().await.unwrap();
+    assert!(peer.is_ipv6());
+    let mut client_conn = Client::from_socket(socket.into(), config, ConnId::next(), Role::Auto)
+        .await
+        .unwrap();
+    assert_eq!(client_conn.dest.to_string(), "[2001:db8::1]:443");
+    client_conn.route().unwrap();
+    let remote = client_conn.connect_remote_server().await.unwrap();
+    let pipe = tokio::spawn(client_conn.do_pipe(remote));
+    client.await.unwrap();
+    pipe.abort();
+    assert_eq!(upstream.requests(), vec!["[2001:db8::1]:443"]);
+}
+
+#[tokio::test]
+async fn ipv6_literal_domain_is_an_ip() {
+    let config = Arc::new(Config::new("127.0.0.1:1".parse().unwrap()));
+    let listener = TcpListener::bind("[::1]:0").await.unwrap();
+    let listen_addr = listener.local_addr().unwrap();
+    let client = tokio::spawn(async move {
+        let mut stream = TcpStream::connect(listen_addr).await.unwrap();
+        let host = b"[2001:db8::2]";
+        let mut request = vec![0x05, 0x01, 0x00, 0x03, host.len() as u8];
+        request.extend_from_slice(host);
+        request.extend_from_slice(&22u16.to_be_bytes());
+        socks_connect(&mut stream, &request).await
+    });
+    let (socket, _) = listener.accept().await.unwrap();
+    let client_conn = Client::from_socket(socket.into(), config, ConnId::next(), Role::Auto)
+        .await
+        .unwrap();
+    assert!(matches!(client_conn.dest.host, Address::Ip(ip) if ip.is_ipv6()));
+    assert_eq!(client_conn.dest.to_string(), "[2001:db8::2]:22");
+    client.await.unwrap();
+}
+
+#[tokio::test]
+async fn v4_mapped_client_gets_ipv4_bound_address() {
+    let upstream = MockUpstream::spawn(Fault::None).await.unwrap();
+    let config = Arc::new(Config::new(upstream.addr()));
+    // 双栈监听，IPv4 客户端的地址以 ::ffff:127.0.0.1 出现
+    let listener = match TcpListener::bind("[::]:0").await {
+        Ok(listener) => listener,
+        Err(_) => return,
+    };
+    let port = listener.local_addr().unwrap().port();
+
+    let client = tokio::spawn(async move {
+        let addr = SocketAddr::from(([127, 0, 0, 1], port));
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let request = [0x05, 0x01, 0x00, 0x01, 192, 0, 2, 1, 0, 80];
+        socks_connect(&mut stream, &request).await
+    });
+
+    let (socket, _) = listener.accept().await.unwrap();
+    let client_conn = Client::from_socket(socket.into(), config, ConnId::next(), Role::Auto)
+        .await
+        .unwrap();
+    assert_eq!(client_conn.dest.to_string(), "192.0.2.1:80");
+    let reply = client.await.unwrap();
+    let mut expected = vec![0x05, 0x00, 0x00, 0x01, 127, 0, 0, 1];
+    expected.extend_from_slice(&port.to_be_bytes());
+    assert_eq!(reply, expected);
+}
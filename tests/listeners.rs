@@ -0,0 +1,148 @@
+use std::{sync::Arc, time::Duration};
+
+use socket_proxy::{
+    client::Client,
+    config::{Config, ConfigFile},
+    conn_id::ConnId,
+    linux::set_defer_accept,
+    listener::Role,
+    metrics::fullest_accept_queue,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpSocket, TcpStream},
+    time::timeout,
+};
+
+#[tokio::test]
+async fn socks_listener_skips_original_destination() {
+    let mut config = Config::new("127.0.0.1:1".parse().unwrap());
+    // 不会回退到 SNI，也不会查询原始目的地
+    config.original_dst_fallback = "reject".parse().unwrap();
+    let config = Arc::new(config);
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let client = tokio::spawn(async move {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+        let mut method = [0u8; 2];
+        stream.read_exact(&mut method).await.unwrap();
+        stream
+            .write_all(&[0x05, 0x01, 0x00, 0x01, 192, 0, 2, 1, 0, 80])
+            .await
+            .unwrap();
+        let mut reply = [0u8; 10];
+        stream.read_exact(&mut reply).await.unwrap();
+        reply
+    });
+    let (socket, _) = listener.accept().await.unwrap();
+    let client_conn = Client::from_socket(socket.into(), config, ConnId::next(), Role::Socks)
+        .await
+        .unwrap();
+    assert_eq!(client_conn.dest.to_string(), "192.0.2.1:80");
+    assert_eq!(client.await.unwrap()[1], 0x00);
+}
+
+#[tokio::test]
+async fn redirect_listener_rejects_socks_clients() {
+    let mut config = Config::new("127.0.0.1:1".parse().unwrap());
+    config.original_dst_fallback = "reject".parse().unwrap();
+    let config = Arc::new(config);
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let client = tokio::spawn(async move {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+        let mut buf = Vec::new();
+        timeout(Duration::from_secs(5), stream.read_to_end(&mut buf))
+            .await
+            .unwrap()
+            .ok();
+        buf
+    });
+    let (socket, _) = listener.accept().await.unwrap();
+    let result = Client::from_socket(socket.into(), config, ConnId::next(), Role::Redirect).await;
+    assert!(result.is_err());
+    assert!(client.await.unwrap().is_empty());
+}
+
+#[test]
+fn parses_listeners() {
+    let file: ConfigFile = toml::from_str(
+        r#"
+        [[listeners]]
+        listen = "127.0.0.1:1080"
+        role = "socks"
+
+        [[listeners]]
+        name = "lan"
+        listen = "[::]:12345"
+        role = "redirect"
+
+        [[listeners]]
+        listen = "127.0.0.1:1081"
+        role = "redirect"
+        defer_accept = 0
+        "#,
+    )
+    .unwrap();
+    assert_eq!(file.listeners.len(), 3);
+    assert_eq!(file.listeners[0].name(), "127.0.0.1:1080");
+    assert_eq!(file.listeners[0].role, Role::Socks);
+    assert_eq!(file.listeners[1].name(), "lan");
+    assert_eq!(file.listeners[1].role, Role::Redirect);
+    assert!(!file.listeners[1].tls);
+    // 只有 redirect 端口默认打开 TCP_DEFER_ACCEPT
+    assert_eq!(file.listeners[0].defer_accept(), 0);
+    assert_eq!(file.listeners[1].defer_accept(), 1);
+    assert_eq!(file.listeners[2].defer_accept(), 0);
+    assert!(toml::from_str::<ConfigFile>(
+        "[[listeners]]\nlisten = \"127.0.0.1:1\"\nrole = \"tproxy\""
+    )
+    .is_err());
+}
+
+#[tokio::test]
+async fn deferred_accept_has_data_ready() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    set_defer_accept(&listener, 5).unwrap();
+    let addr = listener.local_addr().unwrap();
+    let client = tokio::spawn(async move {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        stream.write_all(b"GET / HTTP/1.1\r\n").await.unwrap();
+        stream
+    });
+    let (socket, _) = timeout(Duration::from_secs(3), listener.accept())
+        .await
+        .unwrap()
+        .unwrap();
+    // 连接交给 accept 时客户端的数据已经到达
+    let mut buf = [0u8; 3];
+    let n = timeout(Duration::from_millis(50), socket.peek(&mut buf))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(n, 3);
+    assert_eq!(&buf, b"GET");
+    drop(client.await.unwrap());
+}
+
+#[tokio::test]
+async fn watch_samples_the_fullest_listener() {
+    let idle = Arc::new(TcpListener::bind("127.0.0.1:0").await.unwrap());
+    // 例如没有及时 accept 的 WebSocket 监听端口
+    let socket = TcpSocket::new_v4().unwrap();
+    socket.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+    let busy = Arc::new(socket.listen(8).unwrap());
+    let mut queued = Vec::new();
+    for _ in 0..3 {
+        queued.push(
+            TcpStream::connect(busy.local_addr().unwrap())
+                .await
+                .unwrap(),
+        );
+    }
+    assert_eq!(fullest_accept_queue(std::slice::from_ref(&idle)).0, 0);
+    assert_eq!(fullest_accept_queue(&[idle, busy]), (3, 8));
+}
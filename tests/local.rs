@@ -0,0 +1,125 @@
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use socket_proxy::{
+    config::Config,
+    listener::Role,
+    local::{LocalAddrs, LocalPolicy},
+    server::ProxyServer,
+    testing::{client_hello, Fault, MockUpstream},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    time::timeout,
+};
+
+async fn spawn_proxy(upstream: &MockUpstream, policy: Option<LocalPolicy>) -> SocketAddr {
+    let mut config = Config::new(upstream.addr());
+    if let Some(policy) = policy {
+        config.local_policy = policy;
+    }
+    let server = ProxyServer::new(Arc::new(config), None);
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let stats = server.register_listener("local-test".into(), Role::Socks);
+    tokio::spawn(server.serve_listener(Arc::new(listener), stats, None));
+    addr
+}
+
+// exchange 通过代理连接 dest 并发送 payload，返回读到的数据，连接被关闭时返回已读到的部分
+async fn exchange(proxy: SocketAddr, dest: SocketAddr, payload: &[u8]) -> Vec<u8> {
+    let mut stream = TcpStream::connect(proxy).await.unwrap();
+    stream.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+    let mut request = vec![0x05, 0x01, 0x00, 0x01];
+    match dest {
+        SocketAddr::V4(v4) => request.extend_from_slice(&v4.ip().octets()),
+        SocketAddr::V6(_) => unreachable!(),
+    }
+    request.extend_from_slice(&dest.port().to_be_bytes());
+    stream.write_all(&request).await.unwrap();
+    let mut reply = [0u8; 12];
+    stream.read_exact(&mut reply).await.unwrap();
+    stream.write_all(payload).await.unwrap();
+    let mut received = vec![0u8; payload.len()];
+    let mut n = 0;
+    while n < received.len() {
+        match timeout(Duration::from_secs(5), stream.read(&mut received[n..]))
+            .await
+            .unwrap()
+        {
+            Ok(0) | Err(_) => break,
+            Ok(read) => n += read,
+        }
+    }
+    received.truncate(n);
+    received
+}
+
+#[test]
+fn recognizes_local_destinations() {
+    let local = LocalAddrs::default();
+    for dest in [
+        "127.0.0.1:80",
+        "127.1.2.3:80",
+        "[::1]:80",
+        "0.0.0.0:80",
+        "[::ffff:127.0.0.1]:80",
+        "localhost:80",
+        "LOCALHOST.:80",
+        "app.localhost:80",
+    ] {
+        assert!(local.is_local(&dest.parse().unwrap()), "{}", dest);
+    }
+    for dest in ["192.0.2.1:80", "example.com:80", "localhost.example.com:80"] {
+        assert!(!local.is_local(&dest.parse().unwrap()), "{}", dest);
+    }
+}
+
+#[tokio::test]
+async fn local_destinations_go_upstream_by_default() {
+    let upstream = MockUpstream::spawn(Fault::None).await.unwrap();
+    let proxy = spawn_proxy(&upstream, None).await;
+    let dest: SocketAddr = "127.0.0.1:8080".parse().unwrap();
+    assert_eq!(exchange(proxy, dest, b"ping").await, b"ping");
+    assert_eq!(upstream.requests(), ["127.0.0.1:8080"]);
+}
+
+#[tokio::test]
+async fn reject_policy_closes_local_destinations() {
+    let upstream = MockUpstream::spawn(Fault::None).await.unwrap();
+    let proxy = spawn_proxy(&upstream, Some(LocalPolicy::Reject)).await;
+    let dest: SocketAddr = "127.0.0.1:8080".parse().unwrap();
+    assert_eq!(exchange(proxy, dest, b"ping").await, b"");
+    assert!(upstream.requests().is_empty());
+}
+
+#[tokio::test]
+async fn direct_policy_connects_without_upstream() {
+    let upstream = MockUpstream::spawn(Fault::None).await.unwrap();
+    let proxy = spawn_proxy(&upstream, Some(LocalPolicy::Direct)).await;
+    let local = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let dest = local.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (mut socket, _) = local.accept().await.unwrap();
+        let mut buf = [0u8; 4];
+        socket.read_exact(&mut buf).await.unwrap();
+        socket.write_all(&buf).await.unwrap();
+    });
+    assert_eq!(exchange(proxy, dest, b"ping").await, b"ping");
+    assert!(upstream.requests().is_empty());
+}
+
+#[tokio::test]
+async fn local_check_uses_sniffed_destination() {
+    let upstream = MockUpstream::spawn(Fault::None).await.unwrap();
+    let proxy = spawn_proxy(&upstream, Some(LocalPolicy::Reject)).await;
+    let dest: SocketAddr = "127.0.0.1:443".parse().unwrap();
+    let hello = client_hello(Some("example.com"));
+    assert_eq!(exchange(proxy, dest, &hello).await, hello);
+    assert_eq!(upstream.requests(), ["example.com:443"]);
+
+    // 没有 SNI 时目的地仍然是本机
+    let hello = client_hello(None);
+    assert_eq!(exchange(proxy, dest, &hello).await, b"");
+    assert_eq!(upstream.requests(), ["example.com:443"]);
+}
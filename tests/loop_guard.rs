@@ -0,0 +1,136 @@
+use std::{future::Future, net::SocketAddr, pin::Pin, sync::Arc, time::Duration};
+
+use socket_proxy::{
+    client::Client, config::Config, conn_id::ConnId, listener::Role, local::LocalAddrs,
+    loop_guard::EgressRegistry, server::ProxyServer,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpSocket, TcpStream},
+    time::timeout,
+};
+
+fn sock(s: &str) -> SocketAddr {
+    s.parse().unwrap()
+}
+
+#[test]
+fn registered_port_matches_before_connect() {
+    let registry = Arc::new(EgressRegistry::default());
+    let local: LocalAddrs = ["10.0.0.5", "2001:db8::1"]
+        .into_iter()
+        .map(|ip| ip.parse().unwrap())
+        .collect();
+    let check = |peer: &str| registry.check(&sock(peer), &local);
+    // bind 之后、connect 之前只知道端口
+    let mut guard = registry.register(sock("0.0.0.0:40000"));
+    assert!(check("10.0.0.5:40000").is_err());
+    assert!(check("[::ffff:10.0.0.5]:40000").is_err());
+    assert!(check("127.0.0.1:40000").is_err());
+    assert!(check("10.0.0.5:40001").is_ok());
+    // 其他主机的客户端源端口碰巧相同时不是环路
+    assert!(check("192.0.2.1:40000").is_ok());
+    // connect 之后只匹配实际的本地地址
+    guard.update(sock("10.0.0.5:40000"));
+    assert_eq!(registry.len(), 1);
+    assert!(check("10.0.0.5:40000").is_err());
+    assert!(check("127.0.0.1:40000").is_ok());
+    assert!(check("192.0.2.1:40000").is_ok());
+    drop(guard);
+    assert!(registry.is_empty());
+
+    let _guard = registry.register(sock("[::]:40002"));
+    assert!(check("[2001:db8::1]:40002").is_err());
+    assert!(check("[2001:db8::2]:40002").is_ok());
+}
+
+#[tokio::test]
+async fn connection_to_itself_is_rejected() {
+    // 上游地址就是自己的监听端口，相当于 REDIRECT 规则没有排除上游的情况
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let config = Arc::new(Config::new(addr));
+    let server = ProxyServer::new(config.clone(), None);
+    let stats = server.register_listener("loop-test".into(), Role::Socks);
+    tokio::spawn(server.serve_listener(Arc::new(listener), stats.clone(), None));
+
+    let connect = || async {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+        stream
+            .write_all(&[0x05, 0x01, 0x00, 0x01, 192, 0, 2, 1, 0, 80])
+            .await
+            .unwrap();
+        let mut buf = Vec::new();
+        timeout(Duration::from_secs(5), stream.read_to_end(&mut buf))
+            .await
+            .expect("looped connection should be closed")
+            .ok();
+    };
+    // 第一个连接触发上游能力探测，探测连接同样经过监听端口，等它结束
+    connect().await;
+    timeout(Duration::from_secs(5), async {
+        while config.capabilities.get(addr).is_none() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .unwrap();
+    let accepted = stats.accepted.get();
+    const CLIENTS: u64 = 20;
+    for _ in 0..CLIENTS {
+        connect().await;
+    }
+    // 每个客户端只产生一个环回的连接，在握手之前就被拒绝
+    assert_eq!(stats.accepted.get() - accepted, CLIENTS * 2);
+    timeout(Duration::from_secs(5), async {
+        while !config.egress.is_empty() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .unwrap();
+}
+
+#[tokio::test]
+async fn egress_is_registered_while_connecting() {
+    // accept 队列满的监听端口丢弃新的 SYN，connect 停在进行中
+    let socket = TcpSocket::new_v4().unwrap();
+    socket.bind(sock("127.0.0.1:0")).unwrap();
+    let upstream = socket.listen(0).unwrap();
+    let upstream_addr = upstream.local_addr().unwrap();
+    let mut queued = Vec::new();
+    for _ in 0..16 {
+        match timeout(Duration::from_millis(50), TcpStream::connect(upstream_addr)).await {
+            Ok(stream) => queued.push(stream.unwrap()),
+            Err(_) => break,
+        }
+    }
+    let config = Arc::new(Config::new(upstream_addr));
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    // 经过上游和直连都要在 connect 之前登记
+    for direct in [false, true] {
+        let _peer = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (left, _) = listener.accept().await.unwrap();
+        let mut client = Client::new(
+            left.into(),
+            upstream_addr.into(),
+            config.clone(),
+            ConnId::next(),
+        )
+        .unwrap();
+        let mut connecting = if direct {
+            Box::pin(client.connect_direct()) as Pin<Box<dyn Future<Output = _>>>
+        } else {
+            Box::pin(client.connect_remote_server())
+        };
+        assert!(timeout(Duration::from_millis(50), &mut connecting)
+            .await
+            .is_err());
+        assert_eq!(config.egress.len(), 1, "direct {}", direct);
+        drop(connecting);
+        assert!(config.egress.is_empty());
+    }
+}
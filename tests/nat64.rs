@@ -0,0 +1,122 @@
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use socket_proxy::{
+    client::Destination,
+    nat64::{translate_destination, Nat64},
+};
+
+fn v6(s: &str) -> Ipv6Addr {
+    s.parse().unwrap()
+}
+
+// RFC 6052 2.4 节的示例，IPv4 地址为 192.0.2.33
+const EXAMPLES: [(&str, &str); 6] = [
+    ("2001:db8::/32", "2001:db8:c000:221::"),
+    ("2001:db8:100::/40", "2001:db8:1c0:2:21::"),
+    ("2001:db8:122::/48", "2001:db8:122:c000:2:2100::"),
+    ("2001:db8:122:300::/56", "2001:db8:122:3c0:0:221::"),
+    ("2001:db8:122:344::/64", "2001:db8:122:344:c0:2:2100:0"),
+    ("2001:db8:122:344::/96", "2001:db8:122:344::c000:221"),
+];
+
+#[test]
+fn embeds_rfc6052_examples() {
+    let v4 = Ipv4Addr::new(192, 0, 2, 33);
+    for (prefix, expected) in EXAMPLES {
+        let nat64: Nat64 = prefix.parse().unwrap();
+        assert_eq!(nat64.embed(v4), v6(expected), "{}", prefix);
+        assert_eq!(nat64.extract(v6(expected)), Some(v4), "{}", prefix);
+    }
+}
+
+#[test]
+fn round_trips_every_prefix_length() {
+    for (prefix, _) in EXAMPLES {
+        let nat64: Nat64 = prefix.parse().unwrap();
+        for v4 in [
+            Ipv4Addr::new(0, 0, 0, 0),
+            Ipv4Addr::new(1, 2, 3, 4),
+            Ipv4Addr::new(255, 255, 255, 255),
+            Ipv4Addr::new(10, 128, 0, 7),
+        ] {
+            let embedded = nat64.embed(v4);
+            // bits 64-71 (u-octet) 必须为 0，IPv4 地址跨过它
+            assert_eq!(embedded.octets()[8], 0, "{} {}", prefix, v4);
+            assert_eq!(nat64.extract(embedded), Some(v4), "{} {}", prefix, v4);
+        }
+    }
+}
+
+#[test]
+fn u_octet_is_skipped() {
+    // /40 时 IPv4 地址的第 4 字节、/48 时第 3、4 字节、/56 时后 3 字节落在 u-octet 之后
+    let nat64: Nat64 = "2001:db8:100::/40".parse().unwrap();
+    let embedded = nat64.embed(Ipv4Addr::new(1, 2, 3, 4));
+    assert_eq!(embedded.octets()[5..10], [1, 2, 3, 0, 4]);
+    let nat64: Nat64 = "2001:db8:122::/48".parse().unwrap();
+    let embedded = nat64.embed(Ipv4Addr::new(1, 2, 3, 4));
+    assert_eq!(embedded.octets()[6..11], [1, 2, 0, 3, 4]);
+    let nat64: Nat64 = "2001:db8:122:300::/56".parse().unwrap();
+    let embedded = nat64.embed(Ipv4Addr::new(1, 2, 3, 4));
+    assert_eq!(embedded.octets()[7..12], [1, 0, 2, 3, 4]);
+}
+
+#[test]
+fn parses_prefixes() {
+    for invalid in [
+        "2001:db8::",
+        "2001:db8::/33",
+        "2001:db8::/128",
+        "10.0.0.0/32",
+    ] {
+        assert!(invalid.parse::<Nat64>().is_err(), "{}", invalid);
+    }
+    // 前缀长度之外的位被清零
+    let nat64: Nat64 = "64:ff9b::ffff:ffff/96".parse().unwrap();
+    assert_eq!(
+        nat64.embed(Ipv4Addr::new(192, 0, 2, 1)),
+        v6("64:ff9b::c000:201")
+    );
+}
+
+#[test]
+fn extract_requires_matching_prefix() {
+    let nat64: Nat64 = "64:ff9b::/96".parse().unwrap();
+    assert_eq!(
+        nat64.extract(v6("64:ff9b::c000:201")),
+        Some(Ipv4Addr::new(192, 0, 2, 1))
+    );
+    assert_eq!(nat64.extract(v6("64:ff9c::c000:201")), None);
+    assert_eq!(nat64.extract(v6("2001:db8::c000:201")), None);
+}
+
+#[test]
+fn translates_destinations() {
+    let translate = |dest: &str, nat64: Option<&Nat64>| {
+        let mut dest: Destination = dest.parse().unwrap();
+        let changed = translate_destination(&mut dest, nat64);
+        (dest.to_string(), changed)
+    };
+    let mut nat64: Nat64 = "64:ff9b::/96".parse().unwrap();
+    assert_eq!(
+        translate("[64:ff9b::c000:201]:443", Some(&nat64)),
+        ("192.0.2.1:443".into(), true)
+    );
+    assert_eq!(
+        translate("[::ffff:192.0.2.1]:443", None),
+        ("192.0.2.1:443".into(), true)
+    );
+    assert_eq!(
+        translate("[2001:db8::1]:443", Some(&nat64)),
+        ("[2001:db8::1]:443".into(), false)
+    );
+    assert_eq!(
+        translate("example.com:443", Some(&nat64)),
+        ("example.com:443".into(), false)
+    );
+    nat64.synthesize = true;
+    assert_eq!(
+        translate("192.0.2.1:443", Some(&nat64)),
+        ("[64:ff9b::c000:201]:443".into(), true)
+    );
+}
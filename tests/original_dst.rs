@@ -0,0 +1,76 @@
+use std::sync::Arc;
+
+use socket_proxy::{
+    client::Client, config::Config, conn_id::ConnId, listener::Role,
+    original_dst::OriginalDstFallback, pinning::DnsPinning, testing::client_hello,
+};
+use tokio::{
+    io::AsyncWriteExt,
+    net::{TcpListener, TcpStream},
+};
+
+fn fallback(v6: bool, sni: bool) -> OriginalDstFallback {
+    OriginalDstFallback { v6, sni }
+}
+
+#[test]
+fn parses_fallback_steps() {
+    let parse = |s: &str| s.parse::<OriginalDstFallback>();
+    assert_eq!(OriginalDstFallback::default(), fallback(true, true));
+    assert_eq!(parse("v6,sni,reject"), Ok(fallback(true, true)));
+    assert_eq!(parse("v6, sni"), Ok(fallback(true, true)));
+    assert_eq!(parse("v6"), Ok(fallback(true, false)));
+    assert_eq!(parse("sni,reject"), Ok(fallback(false, true)));
+    assert_eq!(parse("reject"), Ok(fallback(false, false)));
+    assert!(parse("sni,v6").is_err());
+    assert!(parse("v6,v6").is_err());
+    assert!(parse("reject,sni").is_err());
+    assert!(parse("v4").is_err());
+    assert!(parse("").is_err());
+}
+
+// accepted 返回代理一侧的连接和客户端一侧的连接
+async fn accepted() -> (TcpStream, TcpStream) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let client = TcpStream::connect(listener.local_addr().unwrap())
+        .await
+        .unwrap();
+    let (server, _) = listener.accept().await.unwrap();
+    (server, client)
+}
+
+#[tokio::test]
+async fn lookup_fails_for_direct_connections() {
+    // 没有经过 REDIRECT 的连接没有原始目的地
+    let (server, _client) = accepted().await;
+    assert!(fallback(false, false).lookup(&server).is_err());
+    assert!(fallback(true, false).lookup(&server).is_err());
+}
+
+#[tokio::test]
+async fn sni_fallback_does_not_pin_the_listener_address() {
+    for pinning in [DnsPinning::Hint, DnsPinning::Verify] {
+        let mut config = Config::new("127.0.0.1:1".parse().unwrap());
+        config.original_dst_fallback = "sni".parse().unwrap();
+        config.dns_pinning = Some(pinning);
+        let (server, mut client) = accepted().await;
+        client
+            .write_all(&client_hello(Some("example.com")))
+            .await
+            .unwrap();
+        let conn = Client::from_socket(
+            server.into(),
+            Arc::new(config),
+            ConnId::next(),
+            Role::Redirect,
+        )
+        .await
+        .unwrap();
+        assert!(conn.is_unresolved());
+        let conn = conn.retrieve_dest().await.unwrap();
+        assert!(!conn.is_unresolved());
+        assert_eq!(conn.dest.to_string(), "example.com:443");
+        // 监听地址不是客户端连接的 IP，不能当作 pinned 地址
+        assert_eq!(conn.pinned(), None, "{:?}", pinning);
+    }
+}
@@ -0,0 +1,257 @@
+use std::{sync::Arc, time::Duration};
+
+use socket_proxy::{
+    client::{Client, Destination},
+    config::{Config, Permits},
+    conn_id::ConnId,
+    metrics::METRICS,
+    outbound::{HappyPathCache, Outbound, OutboundConfig, OutboundLimits, WhenFull},
+    rules::{NoSniPolicy, RuleConfig, Rules},
+    testing::{Fault, MockUpstream},
+};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::Semaphore,
+};
+
+async fn client(config: &Arc<Config>, dest: Destination) -> Client {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let _peer = TcpStream::connect(listener.local_addr().unwrap())
+        .await
+        .unwrap();
+    let (left, _) = listener.accept().await.unwrap();
+    Client::new(left.into(), dest, config.clone(), ConnId::next()).unwrap()
+}
+
+async fn connect(config: &Arc<Config>, dest: Destination) -> std::io::Result<TcpStream> {
+    client(config, dest).await.connect_remote_server().await
+}
+
+#[test]
+fn happy_path_order() {
+    let cache = HappyPathCache::default();
+    assert_eq!(cache.order("example.com:443", 3), [0, 1, 2]);
+    cache.record("example.com:443", 2);
+    assert_eq!(cache.order("example.com:443", 3), [2, 0, 1]);
+    assert_eq!(cache.order("other.com:443", 3), [0, 1, 2]);
+    // 首选出口成功时不需要记录
+    cache.record("other.com:443", 0);
+    assert_eq!(cache.len(), 1);
+    // 其他出口失败不影响记录
+    cache.forget("example.com:443", 1);
+    assert_eq!(cache.order("example.com:443", 3), [2, 0, 1]);
+    cache.forget("example.com:443", 2);
+    assert!(cache.is_empty());
+}
+
+#[test]
+fn outbound_requires_one_kind() {
+    let config = |socks5: Option<&str>, direct| OutboundConfig {
+        socks5: socks5.map(|addr| addr.parse().unwrap()),
+        direct,
+        ..Default::default()
+    };
+    assert!(config(Some("127.0.0.1:1080"), false).to_outbound().is_ok());
+    assert!(config(None, true).to_outbound().is_ok());
+    assert!(config(None, false).to_outbound().is_err());
+    assert!(config(Some("127.0.0.1:1080"), true).to_outbound().is_err());
+    assert!(config(Some("127.0.0.1:0"), false).to_outbound().is_err());
+}
+
+#[test]
+fn outbound_limits() {
+    let config: OutboundConfig = toml::from_str(
+        r#"
+        socks5 = "127.0.0.1:1080"
+        max_connections = 2
+        max_bandwidth_mbit = 8
+        when_full = "queue"
+        "#,
+    )
+    .unwrap();
+    assert_eq!(
+        config.to_outbound().unwrap().limits,
+        OutboundLimits {
+            max_connections: Some(Permits::new(2).unwrap()),
+            max_bandwidth: Some(1_000_000),
+            when_full: WhenFull::Queue,
+        }
+    );
+    let invalid = |toml: &str| {
+        let config: OutboundConfig = toml::from_str(toml).unwrap();
+        config.to_outbound().is_err()
+    };
+    assert!(invalid("direct = true\nmax_connections = 0"));
+    assert!(invalid("direct = true\nmax_bandwidth_mbit = 0"));
+    assert!(invalid("direct = true\nwhen_full = \"failover\""));
+    // 超过 Semaphore 的上限或者换算成字节数时溢出
+    assert!(invalid(&format!(
+        "direct = true\nmax_connections = {}",
+        Semaphore::MAX_PERMITS + 1
+    )));
+    assert!(invalid(&format!(
+        "direct = true\nmax_bandwidth_mbit = {}",
+        u64::MAX / 1_000
+    )));
+}
+
+fn limited(addr: std::net::SocketAddr, when_full: WhenFull) -> Outbound {
+    Outbound {
+        limits: OutboundLimits {
+            max_connections: Some(Permits::new(1).unwrap()),
+            when_full,
+            ..Default::default()
+        },
+        ..Outbound::socks5(addr)
+    }
+}
+
+#[tokio::test]
+async fn full_outbound_fails_over() {
+    let primary = MockUpstream::spawn(Fault::None).await.unwrap();
+    let secondary = MockUpstream::spawn(Fault::None).await.unwrap();
+    let mut config = Config::new(primary.addr());
+    config.outbounds[0] = limited(primary.addr(), WhenFull::Failover);
+    config.outbounds.push(Outbound::socks5(secondary.addr()));
+    let config = Arc::new(config);
+
+    // 名额随 Client 一起释放
+    let mut first = client(&config, ("a.example.com", 443).into()).await;
+    first.connect_remote_server().await.unwrap();
+    let full = METRICS.outbound_full.get();
+    connect(&config, ("b.example.com", 443).into())
+        .await
+        .unwrap();
+    assert_eq!(primary.requests(), ["a.example.com:443"]);
+    assert_eq!(secondary.requests(), ["b.example.com:443"]);
+    assert!(METRICS.outbound_full.get() > full);
+    // 达到上限不算失败
+    assert_eq!(
+        config
+            .outbound_health
+            .get(&config.outbounds[0].name)
+            .failures,
+        0
+    );
+
+    drop(first);
+    connect(&config, ("c.example.com", 443).into())
+        .await
+        .unwrap();
+    assert_eq!(
+        primary.requests(),
+        ["a.example.com:443", "c.example.com:443"]
+    );
+}
+
+#[tokio::test]
+async fn full_outbound_queues() {
+    let upstream = MockUpstream::spawn(Fault::None).await.unwrap();
+    let mut config = Config::new(upstream.addr());
+    config.outbounds[0] = limited(upstream.addr(), WhenFull::Queue);
+    let config = Arc::new(config);
+
+    let mut first = client(&config, ("a.example.com", 443).into()).await;
+    first.connect_remote_server().await.unwrap();
+    let queued = tokio::spawn({
+        let config = config.clone();
+        async move { connect(&config, ("b.example.com", 443).into()).await }
+    });
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert!(!queued.is_finished());
+    assert_eq!(upstream.requests().len(), 1);
+
+    drop(first);
+    queued.await.unwrap().unwrap();
+    assert_eq!(
+        upstream.requests(),
+        ["a.example.com:443", "b.example.com:443"]
+    );
+}
+
+#[tokio::test]
+async fn failover_is_remembered_per_destination() {
+    let primary = MockUpstream::spawn(Fault::Reject(0x01)).await.unwrap();
+    let secondary = MockUpstream::spawn(Fault::None).await.unwrap();
+    let mut config = Config::new(primary.addr());
+    config.outbounds.push(Outbound::socks5(secondary.addr()));
+    let config = Arc::new(config);
+
+    connect(&config, ("example.com", 443).into()).await.unwrap();
+    assert_eq!(primary.requests(), ["example.com:443"]);
+    assert_eq!(secondary.requests(), ["example.com:443"]);
+
+    // 第二次直接使用上次成功的出口
+    connect(&config, ("example.com", 443).into()).await.unwrap();
+    assert_eq!(primary.requests().len(), 1);
+    assert_eq!(secondary.requests().len(), 2);
+
+    // 其他目的地仍然先尝试首选出口
+    connect(&config, ("example.org", 443).into()).await.unwrap();
+    assert_eq!(primary.requests(), ["example.com:443", "example.org:443"]);
+}
+
+#[tokio::test]
+async fn all_outbounds_failing_returns_last_error() {
+    let primary = MockUpstream::spawn(Fault::EarlyClose).await.unwrap();
+    let secondary = MockUpstream::spawn(Fault::Reject(0x05)).await.unwrap();
+    let mut config = Config::new(primary.addr());
+    config.outbounds.push(Outbound::socks5(secondary.addr()));
+    let config = Arc::new(config);
+    assert!(connect(&config, ("example.com", 443).into()).await.is_err());
+    assert!(config.happy_path.is_empty());
+}
+
+#[tokio::test]
+async fn no_sni_policies() {
+    let primary = MockUpstream::spawn(Fault::None).await.unwrap();
+    let blind = MockUpstream::spawn(Fault::None).await.unwrap();
+    let mut config = Config::new(primary.addr());
+    config.outbounds.push(Outbound {
+        name: "blind".into(),
+        ..Outbound::socks5(blind.addr())
+    });
+    config.rules = Rules::from_config(&[
+        RuleConfig {
+            ips: vec!["192.0.2.0/24".into()],
+            no_sni: Some("reject".into()),
+            ..Default::default()
+        },
+        RuleConfig {
+            ips: vec!["198.51.100.0/24".into()],
+            no_sni: Some("outbound:blind".into()),
+            ..Default::default()
+        },
+    ])
+    .unwrap();
+    let config = Arc::new(config);
+
+    let mut rejected = client(&config, "192.0.2.1:443".parse().unwrap()).await;
+    rejected.route().unwrap();
+    assert!(rejected.apply_no_sni().is_err());
+
+    let mut routed = client(&config, "198.51.100.1:443".parse().unwrap()).await;
+    routed.route().unwrap();
+    routed.apply_no_sni().unwrap();
+    routed.connect_remote_server().await.unwrap();
+    assert_eq!(blind.requests(), ["198.51.100.1:443"]);
+
+    // 没有匹配规则时使用 --no-sni，默认按 IP 转发
+    let mut forwarded = client(&config, "203.0.113.1:443".parse().unwrap()).await;
+    forwarded.route().unwrap();
+    forwarded.apply_no_sni().unwrap();
+    forwarded.connect_remote_server().await.unwrap();
+    assert_eq!(primary.requests(), ["203.0.113.1:443"]);
+}
+
+#[test]
+fn parses_no_sni_policies() {
+    assert_eq!("ip".parse(), Ok(NoSniPolicy::Ip));
+    assert_eq!("reject".parse(), Ok(NoSniPolicy::Reject));
+    assert_eq!(
+        "outbound:backup".parse(),
+        Ok(NoSniPolicy::Outbound("backup".into()))
+    );
+    assert!("outbound:".parse::<NoSniPolicy>().is_err());
+    assert!("drop".parse::<NoSniPolicy>().is_err());
+}
@@ -0,0 +1,161 @@
+use std::{
+    net::{IpAddr, Ipv4Addr},
+    sync::Arc,
+};
+
+use socket_proxy::{
+    client::Client,
+    config::Config,
+    conn_id::ConnId,
+    listener::Role,
+    protocols::peer::{
+        recv_metadata, send_metadata, PeerHello, PeerMetadata, FEATURES, FEATURE_PINNED_ADDR,
+        METHOD_PEER, PROTOCOL_VERSION,
+    },
+};
+use tokio::{
+    io::{duplex, AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+fn metadata(hello: Option<PeerHello>) -> PeerMetadata {
+    PeerMetadata {
+        destination: Some("example.com:443".into()),
+        request_id: Some(7),
+        hello,
+        ..Default::default()
+    }
+}
+
+#[tokio::test]
+async fn peers_negotiate_version_and_features() {
+    let (mut client, mut server) = duplex(1024);
+    let sent = metadata(Some(PeerHello {
+        version: PROTOCOL_VERSION + 1,
+        features: FEATURES | 1 << 31,
+    }));
+    let (hello, received) = tokio::join!(send_metadata(&mut client, &sent), async {
+        recv_metadata(&mut server).await
+    });
+    assert_eq!(received.unwrap(), sent);
+    // 较新的对方降级到本地的版本，未知的功能位不启用
+    assert_eq!(hello.unwrap(), PeerHello::LOCAL);
+}
+
+#[tokio::test]
+async fn legacy_server_is_version_one() {
+    let (mut client, mut server) = duplex(1024);
+    let sent = metadata(Some(PeerHello::LOCAL));
+    let legacy = async {
+        let mut header = [0u8; 3];
+        server.read_exact(&mut header).await.unwrap();
+        let len = u16::from_be_bytes([header[1], header[2]]) as usize;
+        let mut tlvs = vec![0u8; len];
+        server.read_exact(&mut tlvs).await.unwrap();
+        server.write_all(&[0x01, 0x00]).await.unwrap();
+    };
+    let (hello, ()) = tokio::join!(send_metadata(&mut client, &sent), legacy);
+    let hello = hello.unwrap();
+    assert_eq!(hello.version, 1);
+    assert!(hello.supports(FEATURE_PINNED_ADDR));
+}
+
+#[tokio::test]
+async fn legacy_client_gets_legacy_reply() {
+    let (mut client, mut server) = duplex(1024);
+    let (_, received) = tokio::join!(
+        client.write_all(&[0x01, 0x00, 0x00]),
+        recv_metadata(&mut server)
+    );
+    assert_eq!(received.unwrap(), PeerMetadata::default());
+    drop(server);
+    let mut reply = Vec::new();
+    client.read_to_end(&mut reply).await.unwrap();
+    assert_eq!(reply, [0x01, 0x00]);
+}
+
+#[tokio::test]
+async fn bad_magic_is_rejected() {
+    let (mut client, mut server) = duplex(1024);
+    let request = [
+        0x01, 0x00, 0x0d, 0x06, 0x00, 0x0a, b'N', b'O', b'P', b'E', 0, 2, 0, 0, 0, 1,
+    ];
+    let (_, received) = tokio::join!(client.write_all(&request), recv_metadata(&mut server));
+    assert!(received.is_err());
+    drop(server);
+    let mut reply = Vec::new();
+    client.read_to_end(&mut reply).await.unwrap();
+    assert_eq!(reply, [0x01, 0x01]);
+}
+
+// peer_client 客户端同时提供 METHOD_PEER 和无认证，在元数据中给出 pinned 地址，之后发送 request
+// 返回服务端选择的方法和服务端的 Client
+async fn peer_client(trusted_peers: &[&str], pinned: IpAddr, request: Vec<u8>) -> (u8, Client) {
+    let mut config = Config::new("127.0.0.1:1".parse().unwrap());
+    config.original_dst_fallback = "reject".parse().unwrap();
+    config.trusted_peers = trusted_peers.iter().map(|s| s.parse().unwrap()).collect();
+    let config = Arc::new(config);
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let client = tokio::spawn(async move {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(&[0x05, 0x02, METHOD_PEER, 0x00])
+            .await
+            .unwrap();
+        let mut method = [0u8; 2];
+        stream.read_exact(&mut method).await.unwrap();
+        if method[1] == METHOD_PEER {
+            let meta = PeerMetadata {
+                pinned: Some(pinned),
+                ..metadata(Some(PeerHello::LOCAL))
+            };
+            send_metadata(&mut stream, &meta).await.unwrap();
+        }
+        stream.write_all(&request).await.unwrap();
+        let mut reply = [0u8; 10];
+        stream.read_exact(&mut reply).await.unwrap();
+        method[1]
+    });
+    let (socket, _) = listener.accept().await.unwrap();
+    let conn = Client::from_socket(socket.into(), config, ConnId::next(), Role::Socks)
+        .await
+        .unwrap();
+    (client.await.unwrap(), conn)
+}
+
+// peer_handshake 返回服务端选择的方法和 Client 使用的 pinned 地址
+async fn peer_handshake(trusted_peers: &[&str]) -> (u8, Option<IpAddr>) {
+    let request = vec![0x05, 0x01, 0x00, 0x01, 192, 0, 2, 1, 0, 80];
+    let (method, conn) = peer_client(trusted_peers, PINNED, request).await;
+    (method, conn.pinned())
+}
+
+const PINNED: IpAddr = IpAddr::V4(Ipv4Addr::new(203, 0, 113, 7));
+
+#[tokio::test]
+async fn peer_method_requires_trusted_source() {
+    assert_eq!(peer_handshake(&[]).await, (0x00, None));
+    assert_eq!(peer_handshake(&["10.0.0.0/8"]).await, (0x00, None));
+    assert_eq!(
+        peer_handshake(&["127.0.0.0/8"]).await,
+        (METHOD_PEER, Some(PINNED))
+    );
+}
+
+#[tokio::test]
+async fn direct_connect_uses_pinned_address() {
+    let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = target.local_addr().unwrap().port();
+    // 域名无法解析，只能连接下游给出的地址
+    let domain = b"pinned.invalid";
+    let mut request = vec![0x05, 0x01, 0x00, 0x03, domain.len() as u8];
+    request.extend_from_slice(domain);
+    request.extend_from_slice(&port.to_be_bytes());
+    let (method, mut conn) =
+        peer_client(&["127.0.0.0/8"], Ipv4Addr::LOCALHOST.into(), request).await;
+    assert_eq!(method, METHOD_PEER);
+    let stream = conn.connect_direct().await.unwrap();
+    let (accepted, _) = target.accept().await.unwrap();
+    assert_eq!(accepted.peer_addr().unwrap(), stream.local_addr().unwrap());
+}
@@ -0,0 +1,172 @@
+use std::{
+    future::poll_fn,
+    io,
+    task::{Context, Poll},
+};
+
+use socket_proxy::{
+    metrics::METRICS,
+    stream::{pipe, StreamWithBuffer, MAX_BUFFERED},
+    testing::{SimPair, SimRng, SimScript, SimStream},
+};
+use tokio::sync::Mutex;
+
+const SEEDS: u64 = 200;
+
+// 运行 BiPipe 的测试依次执行，模式切换的计数器是全局的，并行时会计入其他测试的切换
+static PIPES: Mutex<()> = Mutex::const_new(());
+
+// switches 当前各种模式切换的累计次数 (normal, interactive, bulk)
+fn switches() -> [u64; 3] {
+    [
+        METRICS.switches_to_normal.get(),
+        METRICS.switches_to_interactive.get(),
+        METRICS.switches_to_bulk.get(),
+    ]
+}
+
+// payload 随机长度的数据，包括空数据和超过缓冲区大小的数据
+fn payload(rng: &mut SimRng) -> Vec<u8> {
+    let len = match rng.below(4) {
+        0 => 0,
+        1 => rng.below(512),
+        2 => rng.below(64 * 1024),
+        _ => rng.below(600 * 1024),
+    };
+    rng.bytes(len)
+}
+
+#[tokio::test(start_paused = true)]
+async fn clean_runs_deliver_everything() {
+    let _serial = PIPES.lock().await;
+    let before = switches();
+    for seed in 0..SEEDS {
+        let mut rng = SimRng::new(seed);
+        let left = SimScript {
+            data: payload(&mut rng),
+            ..Default::default()
+        };
+        let right = SimScript {
+            data: payload(&mut rng),
+            ..Default::default()
+        };
+        let sim = SimPair::new(seed, left.clone(), right.clone());
+        let mut bipipe = pipe(sim.left, sim.right);
+        let result = (&mut bipipe).await;
+        assert!(result.is_ok(), "seed {}: {:?}", seed, result);
+        assert_eq!(
+            bipipe.totals(),
+            (left.data.len() as u64, right.data.len() as u64),
+            "seed {}",
+            seed
+        );
+        for (flow, sent) in [(&sim.up, &left.data), (&sim.down, &right.data)] {
+            assert!(flow.received() == *sent, "seed {}: data lost", seed);
+            assert_eq!(flow.shutdowns(), 1, "seed {}", seed);
+            assert!(flow.max_in_flight() <= MAX_BUFFERED, "seed {}", seed);
+        }
+    }
+    // 随机的读写长度需要覆盖所有的模式切换
+    let after = switches();
+    for (mode, (before, after)) in ["normal", "interactive", "bulk"]
+        .into_iter()
+        .zip(before.into_iter().zip(after))
+    {
+        assert!(after > before, "no switch to {}", mode);
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn faults_never_corrupt_or_double_shutdown() {
+    let _serial = PIPES.lock().await;
+    for seed in 0..SEEDS {
+        let mut rng = SimRng::new(seed);
+        let script = |rng: &mut SimRng| {
+            let data = payload(rng);
+            SimScript {
+                read_reset: rng.chance(30),
+                write_reset_at: rng.chance(30).then(|| rng.below(data.len() + 1)),
+                data,
+            }
+        };
+        let left = script(&mut rng);
+        let right = script(&mut rng);
+        let sim = SimPair::new(seed, left.clone(), right.clone());
+        let result = pipe(sim.left, sim.right).await;
+        for (flow, sent) in [(&sim.up, &left.data), (&sim.down, &right.data)] {
+            let received = flow.received();
+            assert!(sent.starts_with(&received), "seed {}: corrupted", seed);
+            assert!(flow.shutdowns() <= 1, "seed {}", seed);
+            assert!(!flow.write_after_shutdown(), "seed {}", seed);
+            assert!(flow.max_in_flight() <= MAX_BUFFERED, "seed {}", seed);
+            // 正常结束时不能丢数据
+            if result.is_ok() {
+                assert_eq!(received.len(), sent.len(), "seed {}: data lost", seed);
+            }
+        }
+        let reset = left.read_reset
+            || right.read_reset
+            || left.write_reset_at.is_some_and(|at| at < right.data.len())
+            || right.write_reset_at.is_some_and(|at| at < left.data.len());
+        assert_eq!(result.is_err(), reset, "seed {}: {:?}", seed, result);
+    }
+}
+
+// 写入端阻塞时，共享缓冲区中的数据转移到私有缓冲区，之后读取其他数据不会覆盖它
+#[tokio::test(start_paused = true)]
+async fn stream_with_buffer_survives_stalled_writes() {
+    for seed in 0..SEEDS {
+        let mut rng = SimRng::new(seed);
+        let left = SimScript {
+            data: payload(&mut rng),
+            ..Default::default()
+        };
+        let right = SimScript {
+            data: payload(&mut rng),
+            ..Default::default()
+        };
+        let sim = SimPair::new(seed, left.clone(), right.clone());
+        let mut up = StreamWithBuffer::new(sim.left);
+        let mut down = StreamWithBuffer::new(sim.right);
+        // 两个方向交替使用同一个线程的共享缓冲区
+        poll_fn(|cx| {
+            let _ = copy_some(cx, &mut up, &mut down);
+            let _ = copy_some(cx, &mut down, &mut up);
+            if up.read_eof && up.is_empty() && down.read_eof && down.is_empty() {
+                Poll::Ready(())
+            } else {
+                Poll::Pending
+            }
+        })
+        .await;
+        assert!(sim.up.received() == left.data, "seed {}", seed);
+        assert!(sim.down.received() == right.data, "seed {}", seed);
+    }
+}
+
+// copy_some 和 BiPipe 的单个方向一样，尽可能把 reader 的数据写入 writer
+fn copy_some(
+    cx: &mut Context<'_>,
+    reader: &mut StreamWithBuffer<SimStream>,
+    writer: &mut StreamWithBuffer<SimStream>,
+) -> Poll<io::Result<()>> {
+    loop {
+        if reader.is_empty() {
+            if reader.read_eof {
+                return Poll::Ready(Ok(()));
+            }
+            match reader.poll_read_to_buffer(cx) {
+                Poll::Ready(Ok(_)) => (),
+                Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        while !reader.is_empty() {
+            match reader.poll_write_buffer_to(cx, &mut writer.stream) {
+                Poll::Ready(Ok(_)) => (),
+                Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
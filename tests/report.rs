@@ -0,0 +1,63 @@
+use std::{io, net::SocketAddr, sync::Arc, time::Duration};
+
+use socket_proxy::{
+    config::Config,
+    metrics::METRICS,
+    report::ShutdownReport,
+    server::{ProxyServer, Snapshot},
+};
+
+fn snapshot() -> Snapshot {
+    Snapshot {
+        uptime: Duration::from_millis(61_500),
+        accepted: 12,
+        connections: 2,
+        handshakes: 0,
+        upstream_connects: 0,
+        bytes_up: 1000,
+        bytes_down: 50_000,
+        protocols: Vec::new(),
+        errors: vec![("timeout", 1), ("refused", 3)],
+        listeners: Vec::new(),
+        outbounds: Vec::new(),
+    }
+}
+
+#[test]
+fn report_encodes_as_json() {
+    let report = ShutdownReport {
+        signal: "SIGTERM".into(),
+        snapshot: snapshot(),
+        force_closed: 2,
+    };
+    assert_eq!(
+        report.to_json(),
+        r#"{"signal":"SIGTERM","uptime_ms":61500,"connections":12,"bytes_up":1000,"bytes_down":50000,"errors":{"timeout":1,"refused":3},"force_closed":2}"#
+    );
+    assert_eq!(
+        report.to_string(),
+        "signal=SIGTERM uptime=61s connections=12 bytes_up=1000 bytes_down=50000 \
+         errors_timeout=1 errors_refused=3 force_closed=2"
+    );
+}
+
+#[tokio::test]
+async fn drain_without_connections_returns_immediately() {
+    let upstream: SocketAddr = "127.0.0.1:1080".parse().unwrap();
+    let server = ProxyServer::new(Arc::new(Config::new(upstream)), None);
+    // 其他 ProxyServer 的连接不算在内
+    let _other = METRICS.connections.track();
+    let drained =
+        tokio::time::timeout(Duration::from_secs(1), server.drain(Duration::from_secs(5)));
+    assert_eq!(drained.await.unwrap(), 0);
+
+    METRICS
+        .errors
+        .record(&io::Error::new(io::ErrorKind::TimedOut, "budget"));
+    METRICS
+        .errors
+        .record(&io::Error::new(io::ErrorKind::UnexpectedEof, "eof"));
+    let errors = server.snapshot().errors;
+    assert!(errors.contains(&("timeout", 1)));
+    assert!(errors.contains(&("reset", 1)));
+}
@@ -0,0 +1,252 @@
+use socket_proxy::{
+    client::Destination,
+    rules::{Rule, RuleConfig, Rules},
+    wildcard::wildcard_match,
+};
+
+fn marked(config: RuleConfig) -> Rules {
+    Rules::from_config(&[RuleConfig {
+        mark: Some(1),
+        ..config
+    }])
+    .unwrap()
+}
+
+fn hits(rules: &Rules, host: &str) -> bool {
+    let dest: Destination = (host, 443).into();
+    rules.find(&dest).is_some()
+}
+
+#[test]
+fn wildcard_patterns() {
+    assert!(wildcard_match(b"*.example.com", b"a.example.com", false));
+    assert!(wildcard_match(b"*.example.com", b"a.b.example.com", false));
+    assert!(!wildcard_match(b"*.example.com", b"example.com", false));
+    assert!(wildcard_match(
+        b"img?.example.com",
+        b"img1.example.com",
+        false
+    ));
+    assert!(!wildcard_match(
+        b"img?.example.com",
+        b"img12.example.com",
+        false
+    ));
+    assert!(wildcard_match(b"*cdn*", b"static-cdn-01.net", false));
+    assert!(wildcard_match(b"*", b"", false));
+    assert!(!wildcard_match(b"A.com", b"a.com", false));
+    assert!(wildcard_match(b"A.com", b"a.com", true));
+    // 大量 * 不会指数级回溯
+    let pattern = "*a".repeat(20);
+    assert!(!wildcard_match(
+        pattern.as_bytes(),
+        "a".repeat(19).as_bytes(),
+        false
+    ));
+}
+
+// naive_match 直接按定义递归匹配，只用于和 wildcard_match 比较
+fn naive_match(pattern: &[u8], name: &[u8]) -> bool {
+    match (pattern.first(), name.first()) {
+        (None, _) => name.is_empty(),
+        (Some(b'*'), _) => {
+            naive_match(&pattern[1..], name)
+                || (!name.is_empty() && naive_match(pattern, &name[1..]))
+        }
+        (Some(&p), Some(&n)) => (p == b'?' || p == n) && naive_match(&pattern[1..], &name[1..]),
+        (Some(_), None) => false,
+    }
+}
+
+// strings 由 alphabet 组成的长度不超过 max_len 的所有字符串
+fn strings(alphabet: &[u8], max_len: usize) -> Vec<Vec<u8>> {
+    let mut all = vec![Vec::new()];
+    let mut last = vec![Vec::new()];
+    for _ in 0..max_len {
+        last = last
+            .iter()
+            .flat_map(|s: &Vec<u8>| {
+                alphabet.iter().map(move |&c| {
+                    let mut s = s.clone();
+                    s.push(c);
+                    s
+                })
+            })
+            .collect();
+        all.extend(last.iter().cloned());
+    }
+    all
+}
+
+#[test]
+fn wildcard_matches_like_the_definition() {
+    let names = strings(b"ab.", 5);
+    for pattern in strings(b"a.*?", 4) {
+        for name in &names {
+            assert_eq!(
+                wildcard_match(&pattern, name, false),
+                naive_match(&pattern, name),
+                "{:?} {:?}",
+                String::from_utf8_lossy(&pattern),
+                String::from_utf8_lossy(name)
+            );
+        }
+    }
+}
+
+#[test]
+fn wildcard_rules_follow_rule_order_and_ports() {
+    let rules = Rules::from_config(&[
+        RuleConfig {
+            domains: vec!["api-??.example.com".into()],
+            ports: vec![443],
+            mark: Some(1),
+            ..Default::default()
+        },
+        RuleConfig {
+            domains: vec!["example.com".into()],
+            mark: Some(2),
+            ..Default::default()
+        },
+        RuleConfig {
+            domains: vec!["*".into()],
+            mark: Some(3),
+            ..Default::default()
+        },
+    ])
+    .unwrap();
+    assert_eq!(mark_of(&rules, "api-01.example.com:443"), Some(1));
+    assert_eq!(mark_of(&rules, "API-01.Example.com.:443"), Some(1));
+    assert_eq!(mark_of(&rules, "api-01.example.com:80"), Some(2));
+    assert_eq!(mark_of(&rules, "api-001.example.com:443"), Some(2));
+    assert_eq!(mark_of(&rules, "other.net:80"), Some(3));
+    // 通配符只匹配域名
+    assert_eq!(mark_of(&rules, "192.0.2.1:80"), None);
+}
+
+#[test]
+fn suffix_and_wildcard_domains() {
+    let rules = marked(RuleConfig {
+        domains: vec!["example.org".into(), "*.cdn.example.com".into()],
+        ..Default::default()
+    });
+    assert!(hits(&rules, "example.org"));
+    assert!(hits(&rules, "www.example.org"));
+    assert!(hits(&rules, "img.cdn.example.com"));
+    assert!(hits(&rules, "IMG.CDN.example.com."));
+    assert!(!hits(&rules, "cdn.example.com"));
+    assert!(!hits(&rules, "www.example.com"));
+}
+
+#[test]
+fn regex_domains() {
+    let rules = marked(RuleConfig {
+        domain_regex: vec![r"^(www|api)\d*\.example\.net$".into()],
+        ..Default::default()
+    });
+    assert!(hits(&rules, "www.example.net"));
+    assert!(hits(&rules, "API2.example.net"));
+    assert!(!hits(&rules, "cdn.example.net"));
+    // 目的地是 IP 时不匹配域名规则
+    let dest: Destination = "10.0.0.1:443".parse().unwrap();
+    assert!(rules.find(&dest).is_none());
+}
+
+#[test]
+fn invalid_regex_is_rejected() {
+    let err = Rules::from_config(&[RuleConfig {
+        domain_regex: vec!["(unclosed".into()],
+        ..Default::default()
+    }])
+    .unwrap_err();
+    assert!(err.to_string().contains("domain_regex"), "{}", err);
+}
+
+fn mark_of(rules: &Rules, dest: &str) -> Option<u32> {
+    let dest: Destination = dest.parse().unwrap();
+    rules.find(&dest).and_then(|rule| rule.mark)
+}
+
+#[test]
+fn first_matching_rule_wins() {
+    let rules = Rules::from_config(&[
+        RuleConfig {
+            domains: vec!["example.com".into()],
+            ports: vec![22],
+            mark: Some(1),
+            ..Default::default()
+        },
+        RuleConfig {
+            domain_regex: vec![r"^www\.".into()],
+            mark: Some(2),
+            ..Default::default()
+        },
+        RuleConfig {
+            domains: vec!["www.example.com".into()],
+            ips: vec!["10.0.0.0/8".into()],
+            mark: Some(3),
+            ..Default::default()
+        },
+        RuleConfig {
+            ips: vec!["10.1.0.0/16".into(), "2001:db8::/32".into()],
+            mark: Some(4),
+            ..Default::default()
+        },
+        RuleConfig {
+            ports: vec![443],
+            mark: Some(5),
+            ..Default::default()
+        },
+    ])
+    .unwrap();
+    assert_eq!(mark_of(&rules, "www.example.com:22"), Some(1));
+    assert_eq!(mark_of(&rules, "www.example.com:80"), Some(2));
+    assert_eq!(mark_of(&rules, "api.example.com:80"), None);
+    assert_eq!(mark_of(&rules, "api.example.com:443"), Some(5));
+    assert_eq!(mark_of(&rules, "10.1.2.3:80"), Some(3));
+    assert_eq!(mark_of(&rules, "[::ffff:10.1.2.3]:80"), Some(3));
+    assert_eq!(mark_of(&rules, "[2001:db8::1]:80"), Some(4));
+    assert_eq!(mark_of(&rules, "192.168.0.1:443"), Some(5));
+    assert_eq!(mark_of(&rules, "192.168.0.1:80"), None);
+}
+
+#[test]
+fn large_rule_sets_match_like_a_linear_scan() {
+    let mut configs: Vec<RuleConfig> = (0..20_000)
+        .map(|i| RuleConfig {
+            domains: vec![format!("site{}.example", i)],
+            ports: if i % 3 == 0 { vec![443] } else { vec![] },
+            mark: Some(i),
+            ..Default::default()
+        })
+        .collect();
+    configs.push(RuleConfig {
+        domains: (0..20_000).map(|i| format!("list{}.example", i)).collect(),
+        ips: (0..=255).map(|i| format!("172.16.{}.0/24", i)).collect(),
+        mark: Some(u32::MAX),
+        ..Default::default()
+    });
+    let rules = Rules::from_config(&configs).unwrap();
+    assert_eq!(rules.len(), 20_001);
+    let linear: Vec<_> = configs
+        .iter()
+        .map(|config| Rule::from_config(config).unwrap())
+        .collect();
+    for dest in [
+        "site9.example:443",
+        "site9.example:80",
+        "www.site10.example:80",
+        "list19999.example:80",
+        "a.b.list5.example:22",
+        "other.example:80",
+        "172.16.200.9:80",
+        "172.17.0.1:80",
+    ] {
+        let parsed: Destination = dest.parse().unwrap();
+        let expected = linear
+            .iter()
+            .find(|rule| rule.matches(&parsed))
+            .and_then(|rule| rule.mark);
+        assert_eq!(mark_of(&rules, dest), expected, "{}", dest);
+    }
+}
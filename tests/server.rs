@@ -0,0 +1,169 @@
+use std::{sync::Arc, time::Duration};
+
+use socket_proxy::{
+    accounting::{Accounting, FsyncPolicy, Usage},
+    client::Client,
+    config::Config,
+    conn_id::ConnId,
+    hooks::{HookTarget, Hooks},
+    listener::{ListenerStats, Role},
+    outbound::{Outbound, OutboundKind},
+    server::ProxyServer,
+    testing::{Fault, MockUpstream},
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream, UnixListener},
+    time::timeout,
+};
+
+async fn connect(config: &Arc<Config>) -> std::io::Result<TcpStream> {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let _peer = TcpStream::connect(listener.local_addr().unwrap())
+        .await
+        .unwrap();
+    let (left, _) = listener.accept().await.unwrap();
+    Client::new(
+        left.into(),
+        ("example.com", 443).into(),
+        config.clone(),
+        ConnId::next(),
+    )
+    .unwrap()
+    .connect_remote_server()
+    .await
+}
+
+#[tokio::test]
+async fn snapshot_reports_outbound_health() {
+    let primary = MockUpstream::spawn(Fault::Reject(0x01)).await.unwrap();
+    let secondary = MockUpstream::spawn(Fault::None).await.unwrap();
+    let mut config = Config::new(primary.addr());
+    config.outbounds.push(Outbound::socks5(secondary.addr()));
+    let server = ProxyServer::new(Arc::new(config), None);
+    let stats = server.register_listener("snapshot-test".into(), Role::Socks);
+    stats.accepted.add(3);
+
+    connect(server.config()).await.unwrap();
+    let snapshot = server.snapshot();
+    let [failed, healthy] = &snapshot.outbounds[..] else {
+        panic!("expected two outbounds, got {:?}", snapshot.outbounds);
+    };
+    assert_eq!(failed.kind, OutboundKind::Socks5(primary.addr()));
+    assert_eq!(failed.health.failures, 1);
+    assert_eq!(failed.health.consecutive_failures, 1);
+    assert!(failed.health.last_error.is_some());
+    assert_eq!(healthy.health.connects, 1);
+    assert_eq!(healthy.health.failures, 0);
+    // 握手成功后测得 RTT
+    assert!(healthy.rtt.is_some());
+    let listener = snapshot
+        .listeners
+        .iter()
+        .find(|listener| listener.name == "snapshot-test")
+        .unwrap();
+    assert_eq!((listener.role, listener.accepted), (Role::Socks, 3));
+
+    // 之后直接使用成功的出口，首选出口的连续失败次数不变
+    connect(server.config()).await.unwrap();
+    let snapshot = server.snapshot();
+    assert_eq!(snapshot.outbounds[0].health.consecutive_failures, 1);
+    assert_eq!(snapshot.outbounds[1].health.connects, 2);
+}
+
+#[test]
+fn snapshot_lists_only_own_listeners() {
+    let config = Arc::new(Config::new("127.0.0.1:1".parse().unwrap()));
+    let first = ProxyServer::new(config.clone(), None);
+    let second = ProxyServer::new(config, None);
+    let stats = first.register_listener("own-listener".into(), Role::Socks);
+    let names = |server: &ProxyServer| -> Vec<String> {
+        server
+            .snapshot()
+            .listeners
+            .into_iter()
+            .map(|listener| listener.name)
+            .collect()
+    };
+    assert_eq!(names(&first), ["own-listener"]);
+    assert!(names(&second).is_empty());
+
+    // 连接结束前统计仍然存在，之后从指标和 snapshot 中移除
+    let active = stats.track();
+    drop(stats);
+    assert_eq!(names(&first), ["own-listener"]);
+    drop(active);
+    assert!(names(&first).is_empty());
+    assert!(ListenerStats::all()
+        .iter()
+        .all(|listener| listener.name != "own-listener"));
+}
+
+#[tokio::test]
+async fn drain_aborts_open_connections_and_finishes_them() {
+    let upstream = MockUpstream::spawn(Fault::None).await.unwrap();
+    let dir = std::env::temp_dir();
+    let journal = dir.join(format!("socket_proxy_drain_{}", std::process::id()));
+    let hook_path = dir.join(format!("socket_proxy_drain_{}.sock", std::process::id()));
+    let _ = std::fs::remove_file(&journal);
+    let _ = std::fs::remove_file(&hook_path);
+    let hook_listener = UnixListener::bind(&hook_path).unwrap();
+    let mut config = Config::new(upstream.addr());
+    config.accounting = Some(Accounting::open(&journal, FsyncPolicy::Never).unwrap());
+    config.hooks = Some(Hooks::spawn(HookTarget::Unix(hook_path.clone()), 0));
+    let config = Arc::new(config);
+    let server = ProxyServer::new(config.clone(), None);
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let stats = server.register_listener("drain-test".into(), Role::Socks);
+    let accepting = tokio::spawn(server.clone().serve_listener(
+        Arc::new(listener),
+        stats.clone(),
+        None,
+    ));
+
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+    stream
+        .write_all(&[0x05, 0x01, 0x00, 0x03, 11])
+        .await
+        .unwrap();
+    stream.write_all(b"example.com\x01\xbb").await.unwrap();
+    let mut reply = [0u8; 12];
+    stream.read_exact(&mut reply).await.unwrap();
+    stream.write_all(b"hello").await.unwrap();
+    let mut echoed = [0u8; 5];
+    stream.read_exact(&mut echoed).await.unwrap();
+
+    accepting.abort();
+    assert_eq!(server.drain(Duration::from_millis(100)).await, 1);
+    // 中止的连接已经关闭，guard 都已经执行
+    let read = timeout(Duration::from_secs(5), stream.read(&mut echoed))
+        .await
+        .unwrap();
+    assert!(matches!(read, Ok(0) | Err(_)), "{:?}", read);
+    assert_eq!(stats.active.get(), 0);
+    let usage = config.accounting.as_ref().unwrap().usage();
+    assert_eq!(
+        usage[&"127.0.0.1".parse().unwrap()],
+        Usage {
+            connections: 1,
+            up: 5,
+            down: 5
+        }
+    );
+
+    config.hooks.as_ref().unwrap().flush().await;
+    let (hook_stream, _) = hook_listener.accept().await.unwrap();
+    let mut lines = BufReader::new(hook_stream).lines();
+    for kind in ["open", "close"] {
+        let line = lines.next_line().await.unwrap().unwrap();
+        assert!(
+            line.starts_with(&format!("{{\"event\":\"{}\"", kind)),
+            "{}",
+            line
+        );
+    }
+    let _ = std::fs::remove_file(&journal);
+    let _ = std::fs::remove_file(&hook_path);
+}
@@ -0,0 +1,63 @@
+use std::{sync::Arc, time::Duration};
+
+use socket_proxy::shaper::{RateLimit, Shaped};
+use tokio::{
+    io::{duplex, AsyncReadExt, AsyncWriteExt},
+    time::Instant,
+};
+
+#[tokio::test(start_paused = true)]
+async fn rate_limit_refills_over_time() {
+    // 8000 字节每秒，桶的容量为 4096 字节
+    let limit = RateLimit::new(8000);
+    assert_eq!(limit.take(10_000), Ok(4096));
+    let wait = limit.take(100).unwrap_err();
+    assert!(wait > Duration::from_millis(12) && wait < Duration::from_millis(13));
+    tokio::time::advance(Duration::from_millis(500)).await;
+    // 令牌不足 4096 字节时等待，除非请求的更少
+    assert!(limit.take(10_000).is_err());
+    assert_eq!(limit.take(4000), Ok(4000));
+    limit.refund(1000);
+    assert_eq!(limit.take(1000), Ok(1000));
+}
+
+#[tokio::test(start_paused = true)]
+async fn shaped_streams_share_the_limit() {
+    let limit = Arc::new(RateLimit::new(100_000));
+    let start = Instant::now();
+    let mut tasks = Vec::new();
+    for _ in 0..2 {
+        let (near, mut far) = duplex(1 << 16);
+        let mut shaped = Shaped::new(near, limit.clone());
+        tasks.push(tokio::spawn(async move {
+            shaped.write_all(&[0u8; 50_000]).await.unwrap();
+            shaped.shutdown().await.unwrap();
+        }));
+        tasks.push(tokio::spawn(async move {
+            let mut data = Vec::new();
+            far.read_to_end(&mut data).await.unwrap();
+            assert_eq!(data.len(), 50_000);
+        }));
+    }
+    for task in tasks {
+        task.await.unwrap();
+    }
+    // 合计 100000 字节，减去初始的 10000 字节突发
+    let elapsed = start.elapsed();
+    assert!(elapsed >= Duration::from_millis(890), "{:?}", elapsed);
+    assert!(elapsed < Duration::from_millis(1000), "{:?}", elapsed);
+}
+
+#[tokio::test(start_paused = true)]
+async fn shaped_reads_are_limited() {
+    let limit = Arc::new(RateLimit::new(40_960));
+    let (near, mut far) = duplex(1 << 16);
+    let mut shaped = Shaped::new(near, limit);
+    far.write_all(&[1u8; 20_480]).await.unwrap();
+    let start = Instant::now();
+    let mut data = vec![0u8; 20_480];
+    shaped.read_exact(&mut data).await.unwrap();
+    let elapsed = start.elapsed();
+    assert!(elapsed >= Duration::from_millis(390), "{:?}", elapsed);
+    assert!(elapsed < Duration::from_millis(500), "{:?}", elapsed);
+}
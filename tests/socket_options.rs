@@ -0,0 +1,149 @@
+use std::{
+    mem,
+    os::unix::io::{AsRawFd, RawFd},
+    sync::Arc,
+};
+
+use socket_proxy::{
+    client::Client,
+    config::Config,
+    conn_id::ConnId,
+    linux::set_dscp,
+    rules::{RuleConfig, Rules},
+};
+use tokio::net::{TcpListener, TcpStream};
+
+fn get_int_option(fd: RawFd, level: libc::c_int, name: libc::c_int) -> libc::c_int {
+    let mut value: libc::c_int = 0;
+    let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let res = unsafe {
+        libc::getsockopt(
+            fd,
+            level,
+            name,
+            &mut value as *mut _ as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    assert_eq!(res, 0, "{}", std::io::Error::last_os_error());
+    value
+}
+
+async fn connected_pair(bind: &str) -> (TcpStream, TcpStream) {
+    let listener = TcpListener::bind(bind).await.unwrap();
+    let client = TcpStream::connect(listener.local_addr().unwrap())
+        .await
+        .unwrap();
+    let (server, _) = listener.accept().await.unwrap();
+    (client, server)
+}
+
+#[test]
+fn rule_options_are_validated() {
+    let rule = |mark, dscp| {
+        Rules::from_config(&[RuleConfig {
+            ports: vec![443],
+            mark,
+            dscp,
+            ..Default::default()
+        }])
+    };
+    assert!(rule(Some(0x100), Some(0)).is_ok());
+    assert!(rule(None, Some(63)).is_ok());
+    assert!(rule(None, Some(64)).is_err());
+    assert!(Rules::from_config(&[RuleConfig {
+        ports: vec![0],
+        mark: Some(1),
+        ..Default::default()
+    }])
+    .is_err());
+    assert!(Rules::from_config(&[RuleConfig {
+        ips: vec!["10.0.0.0/33".into()],
+        mark: Some(1),
+        ..Default::default()
+    }])
+    .is_err());
+}
+
+#[tokio::test]
+async fn dscp_is_set_on_ipv4_sockets() {
+    let (socket, _peer) = connected_pair("127.0.0.1:0").await;
+    set_dscp(&socket, false, 46).unwrap();
+    assert_eq!(
+        get_int_option(socket.as_raw_fd(), libc::IPPROTO_IP, libc::IP_TOS) & 0xfc,
+        46 << 2
+    );
+}
+
+#[tokio::test]
+async fn dscp_is_set_on_ipv6_sockets() {
+    let listener = match TcpListener::bind("[::1]:0").await {
+        Ok(listener) => listener,
+        // 没有 IPv6 的环境
+        Err(_) => return,
+    };
+    let socket = TcpStream::connect(listener.local_addr().unwrap())
+        .await
+        .unwrap();
+    set_dscp(&socket, true, 10).unwrap();
+    assert_eq!(
+        get_int_option(socket.as_raw_fd(), libc::IPPROTO_IPV6, libc::IPV6_TCLASS),
+        10 << 2
+    );
+}
+
+#[tokio::test]
+async fn matched_rule_sets_dscp_on_client_socket() {
+    let mut config = Config::new("127.0.0.1:1".parse().unwrap());
+    config.rules = Rules::from_config(&[RuleConfig {
+        domains: vec!["example.com".into()],
+        dscp: Some(8),
+        ..Default::default()
+    }])
+    .unwrap();
+    let config = Arc::new(config);
+    for (dest, expected) in [("www.example.com:443", 8 << 2), ("example.org:443", 0)] {
+        let (_peer, socket) = connected_pair("127.0.0.1:0").await;
+        let fd = socket.as_raw_fd();
+        let mut client = Client::new(
+            socket.into(),
+            dest.parse().unwrap(),
+            config.clone(),
+            ConnId::next(),
+        )
+        .unwrap();
+        client.route().unwrap();
+        assert_eq!(
+            get_int_option(fd, libc::IPPROTO_IP, libc::IP_TOS) & 0xfc,
+            expected,
+            "{}",
+            dest
+        );
+    }
+}
+
+#[tokio::test]
+async fn direct_connect_applies_rule_options() {
+    let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let mut config = Config::new("127.0.0.1:1".parse().unwrap());
+    config.rules = Rules::from_config(&[RuleConfig {
+        ips: vec!["127.0.0.0/8".into()],
+        dscp: Some(8),
+        ..Default::default()
+    }])
+    .unwrap();
+    let (_peer, socket) = connected_pair("127.0.0.1:0").await;
+    let mut client = Client::new(
+        socket.into(),
+        target.local_addr().unwrap().into(),
+        Arc::new(config),
+        ConnId::next(),
+    )
+    .unwrap();
+    client.route().unwrap();
+    let stream = client.connect_direct().await.unwrap();
+    assert_eq!(
+        get_int_option(stream.as_raw_fd(), libc::IPPROTO_IP, libc::IP_TOS) & 0xfc,
+        8 << 2
+    );
+}
@@ -0,0 +1,68 @@
+use std::{io::BufReader, path::Path, sync::Arc, time::Duration};
+
+use socket_proxy::{
+    config::Config, listener::Role, server::ProxyServer, tls::server::build_acceptor,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    time::timeout,
+};
+use tokio_rustls::{
+    rustls::{ClientConfig, RootCertStore},
+    webpki::DNSNameRef,
+    TlsConnector,
+};
+
+const DATA: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/data");
+
+async fn spawn_tls_server(handshake_timeout: Duration) -> std::net::SocketAddr {
+    let acceptor = build_acceptor(
+        &Path::new(DATA).join("server.pem"),
+        &Path::new(DATA).join("server.key"),
+        None,
+    )
+    .unwrap();
+    let mut config = Config::new("127.0.0.1:1".parse().unwrap());
+    config.handshake_timeout = handshake_timeout;
+    let server = ProxyServer::new(Arc::new(config), None);
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let stats = server.register_listener("tls-test".into(), Role::Socks);
+    tokio::spawn(server.serve_listener(Arc::new(listener), stats, Some(acceptor)));
+    addr
+}
+
+fn connector() -> TlsConnector {
+    let mut roots = RootCertStore::empty();
+    let ca = std::fs::File::open(Path::new(DATA).join("ca.pem")).unwrap();
+    roots.add_pem_file(&mut BufReader::new(ca)).unwrap();
+    let mut config = ClientConfig::new();
+    config.root_store = roots;
+    TlsConnector::from(Arc::new(config))
+}
+
+#[tokio::test]
+async fn socks_over_tls() {
+    let addr = spawn_tls_server(Duration::from_secs(5)).await;
+    let tcp = TcpStream::connect(addr).await.unwrap();
+    let name = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+    let mut stream = connector().connect(name, tcp).await.unwrap();
+    stream.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+    let mut method = [0u8; 2];
+    stream.read_exact(&mut method).await.unwrap();
+    assert_eq!(method, [0x05, 0x00]);
+}
+
+#[tokio::test]
+async fn stalled_tls_handshake_is_closed() {
+    let addr = spawn_tls_server(Duration::from_millis(200)).await;
+    // 只建立 TCP 连接，不发送 ClientHello
+    let mut tcp = TcpStream::connect(addr).await.unwrap();
+    let mut buf = [0u8; 1];
+    let n = timeout(Duration::from_secs(5), tcp.read(&mut buf))
+        .await
+        .expect("handshake timeout should close the connection")
+        .unwrap_or(0);
+    assert_eq!(n, 0);
+}
@@ -0,0 +1,98 @@
+use std::{io, time::Duration};
+
+use socket_proxy::{
+    budget::Budget,
+    client::Destination,
+    conn_id::ConnId,
+    rules::{RuleConfig, Rules},
+    trace::{hex, Recording, Stages, Traced},
+};
+use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+#[test]
+fn hex_dump_is_truncated() {
+    assert_eq!(hex(&[0x05, 0x01, 0x00]), "050100");
+    let long = hex(&[0xab; 300]);
+    assert!(long.starts_with("abab"));
+    assert!(long.ends_with("...(44 more)"));
+    assert_eq!(long.len(), 512 + "...(44 more)".len());
+}
+
+#[test]
+fn trace_is_set_per_rule() {
+    let rules = Rules::from_config(&[
+        RuleConfig {
+            domains: vec!["broken.example.com".into()],
+            trace: true,
+            ..Default::default()
+        },
+        RuleConfig {
+            domains: vec!["example.com".into()],
+            ..Default::default()
+        },
+    ])
+    .unwrap();
+    let find = |host: &str| rules.find(&Destination::from((host, 443))).unwrap();
+    assert!(find("broken.example.com").trace);
+    assert!(!find("www.example.com").trace);
+}
+
+#[tokio::test]
+async fn traced_stream_passes_data_through() {
+    let (mut near, mut far) = duplex(64);
+    let mut traced = Traced::new(&mut near, ConnId::next(), "upstream");
+    traced.write_all(b"ping").await.unwrap();
+    let mut buf = [0u8; 4];
+    far.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"ping");
+    far.write_all(b"pong").await.unwrap();
+    traced.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"pong");
+}
+
+#[tokio::test(start_paused = true)]
+async fn budget_records_stage_timings() {
+    let mut budget = Budget::new(ConnId::next(), Some(Duration::from_secs(1)));
+    budget
+        .run("sniff", async {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            Ok::<_, io::Error>(())
+        })
+        .await
+        .unwrap();
+    let timed_out = budget
+        .run("upstream connect", async {
+            tokio::time::sleep(Duration::from_secs(5)).await;
+            Ok::<_, io::Error>(())
+        })
+        .await;
+    assert_eq!(timed_out.unwrap_err().kind(), io::ErrorKind::TimedOut);
+    // 超时的阶段同样记录
+    assert_eq!(budget.stages(), "sniff 20ms, upstream connect 980ms");
+}
+
+#[tokio::test]
+async fn recording_keeps_handshake_only_when_enabled() {
+    for record in [true, false] {
+        let (mut near, mut far) = duplex(64);
+        let mut recording = Recording::new(&mut near, record);
+        far.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+        let mut greeting = [0u8; 3];
+        recording.read_exact(&mut greeting).await.unwrap();
+        recording.write_all(&[0x05, 0x00]).await.unwrap();
+        let mut reply = [0u8; 2];
+        far.read_exact(&mut reply).await.unwrap();
+        assert_eq!((greeting, reply), ([0x05, 0x01, 0x00], [0x05, 0x00]));
+        let transcript = recording.into_transcript();
+        assert_eq!(transcript.is_some_and(|t| !t.is_empty()), record);
+    }
+}
+
+#[test]
+fn stages_are_listed_in_order() {
+    let mut stages = Stages::default();
+    assert!(stages.is_empty());
+    stages.push("dns resolve", Duration::from_millis(3));
+    stages.push("tcp connect", Duration::from_millis(20));
+    assert_eq!(stages.to_string(), "dns resolve 3ms, tcp connect 20ms");
+}
@@ -0,0 +1,62 @@
+use std::time::Duration;
+
+use socket_proxy::tuning::{mbit_to_bytes, BufferTuning};
+
+#[test]
+fn converts_mbit_without_overflow() {
+    assert_eq!(mbit_to_bytes(0), Some(0));
+    assert_eq!(mbit_to_bytes(100), Some(12_500_000));
+    assert_eq!(
+        mbit_to_bytes(u64::MAX / 1_000_000),
+        Some(u64::MAX / 1_000_000 * 125_000)
+    );
+    assert_eq!(mbit_to_bytes(u64::MAX / 1_000_000 + 1), None);
+    assert_eq!(mbit_to_bytes(u64::MAX), None);
+}
+
+#[test]
+fn rtt_is_smoothed_per_upstream() {
+    let tuning = BufferTuning::default();
+    let a = "192.0.2.1:1080".parse().unwrap();
+    let b = "192.0.2.2:1080".parse().unwrap();
+    assert_eq!(tuning.rtt(a), None);
+    tuning.record_rtt(a, Duration::from_millis(80));
+    assert_eq!(tuning.rtt(a), Some(Duration::from_millis(80)));
+    tuning.record_rtt(a, Duration::from_millis(160));
+    assert_eq!(tuning.rtt(a), Some(Duration::from_millis(90)));
+    assert_eq!(tuning.rtt(b), None);
+}
+
+#[test]
+fn buffers_follow_bandwidth_delay_product() {
+    let upstream = "192.0.2.1:1080".parse().unwrap();
+    // 100 Mbit/s x 40ms = 500 KB
+    let tuning = BufferTuning::new(mbit_to_bytes(100), None, None);
+    assert_eq!(tuning.buffer_sizes(upstream), (None, None));
+    tuning.record_rtt(upstream, Duration::from_millis(40));
+    assert_eq!(
+        tuning.buffer_sizes(upstream),
+        (Some(500_000), Some(500_000))
+    );
+
+    // 显式配置优先
+    let tuning = BufferTuning::new(mbit_to_bytes(100), Some(4096), None);
+    tuning.record_rtt(upstream, Duration::from_millis(40));
+    assert_eq!(tuning.buffer_sizes(upstream), (Some(4096), Some(500_000)));
+
+    // 没有配置带宽时只使用显式配置
+    let tuning = BufferTuning::new(None, None, Some(8192));
+    tuning.record_rtt(upstream, Duration::from_millis(40));
+    assert_eq!(tuning.buffer_sizes(upstream), (None, Some(8192)));
+}
+
+#[test]
+fn buffers_are_clamped() {
+    let upstream = "192.0.2.1:1080".parse().unwrap();
+    let tuning = BufferTuning::new(mbit_to_bytes(1), None, None);
+    tuning.record_rtt(upstream, Duration::from_millis(1));
+    assert_eq!(tuning.buffer_sizes(upstream).0, Some(64 * 1024));
+    let tuning = BufferTuning::new(Some(u64::MAX), None, None);
+    tuning.record_rtt(upstream, Duration::from_secs(3600));
+    assert_eq!(tuning.buffer_sizes(upstream).0, Some(32 * 1024 * 1024));
+}
@@ -0,0 +1,241 @@
+use std::{io::ErrorKind, net::SocketAddr, sync::Arc, time::Duration};
+
+use socket_proxy::{
+    client::{Client, Destination},
+    config::Config,
+    conn_id::ConnId,
+    protocols::{
+        capabilities::{probe, CapabilityCache},
+        handshake, handshake_pipelined,
+    },
+    rules::{RuleConfig, Rules},
+    testing::{Fault, MockUpstream},
+};
+use tokio::{
+    io::AsyncReadExt,
+    net::{TcpListener, TcpStream},
+    time::{sleep, timeout},
+};
+
+async fn connect(fault: Fault) -> (MockUpstream, TcpStream) {
+    let upstream = MockUpstream::spawn(fault).await.unwrap();
+    let stream = TcpStream::connect(upstream.addr()).await.unwrap();
+    (upstream, stream)
+}
+
+fn domain() -> Destination {
+    ("example.com", 443).into()
+}
+
+#[tokio::test]
+async fn domain_request_flushes_early_data() {
+    let (upstream, mut stream) = connect(Fault::None).await;
+    handshake(&mut stream, &domain(), Some(b"hello"), None)
+        .await
+        .unwrap();
+    let mut buf = [0u8; 5];
+    stream.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"hello");
+    assert_eq!(upstream.requests(), vec!["example.com:443"]);
+}
+
+#[tokio::test]
+async fn pipelined_handshake_flushes_early_data() {
+    let (upstream, mut stream) = connect(Fault::None).await;
+    let method = handshake_pipelined(&mut stream, &domain(), Some(b"hello"))
+        .await
+        .unwrap();
+    assert_eq!(method, 0x00);
+    let mut buf = [0u8; 5];
+    stream.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"hello");
+    assert_eq!(upstream.requests(), vec!["example.com:443"]);
+}
+
+#[tokio::test]
+async fn probe_reports_capabilities() {
+    let upstream = MockUpstream::spawn(Fault::None).await.unwrap();
+    let caps = probe(upstream.addr()).await.unwrap();
+    assert!(caps.no_auth);
+    assert!(!caps.user_pass);
+    // MockUpstream 只接受 CONNECT，UDP ASSOCIATE 会被直接关闭
+    assert!(!caps.udp);
+    assert!(caps.pipelining);
+    assert_eq!(caps.peer, None);
+    assert_eq!(upstream.probes(), 1);
+    assert!(upstream.requests().is_empty());
+}
+
+#[tokio::test(start_paused = true)]
+async fn probe_detects_dropped_pipelined_requests() {
+    let upstream = MockUpstream::spawn(Fault::NoPipelining).await.unwrap();
+    let caps = probe(upstream.addr()).await.unwrap();
+    assert!(caps.no_auth);
+    assert!(!caps.pipelining);
+    assert_eq!(upstream.probes(), 0);
+}
+
+async fn wait_until(mut done: impl FnMut() -> bool) {
+    timeout(Duration::from_secs(10), async {
+        while !done() {
+            sleep(Duration::from_millis(5)).await;
+        }
+    })
+    .await
+    .unwrap();
+}
+
+// connect_through 等待上游能力探测完成后通过 Client 建立一个连接
+async fn connect_through(upstream: &MockUpstream) {
+    let config = Arc::new(Config::new(upstream.addr()));
+    config.capabilities.detect(upstream.addr());
+    wait_until(|| config.capabilities.get(upstream.addr()).is_some()).await;
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let _peer = TcpStream::connect(listener.local_addr().unwrap())
+        .await
+        .unwrap();
+    let (left, _) = listener.accept().await.unwrap();
+    let mut client = Client::new(left.into(), domain(), config, ConnId::next()).unwrap();
+    client.connect_remote_server().await.unwrap();
+    assert_eq!(upstream.requests(), vec!["example.com:443"]);
+}
+
+#[tokio::test]
+async fn client_pipelines_when_upstream_supports_it() {
+    let upstream = MockUpstream::spawn(Fault::None).await.unwrap();
+    connect_through(&upstream).await;
+    assert_eq!(upstream.pipelined(), 1);
+}
+
+// 流水线探测要等到超时，暂停时间以免测试等待
+#[tokio::test(start_paused = true)]
+async fn client_waits_for_method_reply_otherwise() {
+    let upstream = MockUpstream::spawn(Fault::NoPipelining).await.unwrap();
+    connect_through(&upstream).await;
+    assert_eq!(upstream.pipelined(), 0);
+}
+
+#[tokio::test]
+async fn capabilities_are_probed_again_after_ttl() {
+    let upstream = MockUpstream::spawn(Fault::None).await.unwrap();
+    let addr = upstream.addr();
+    let cache = Arc::new(CapabilityCache::default());
+    cache.detect(addr);
+    wait_until(|| cache.get(addr).is_some()).await;
+    cache.detect(addr);
+    sleep(Duration::from_millis(100)).await;
+    assert_eq!(upstream.probes(), 1);
+
+    let cache = Arc::new(CapabilityCache::with_ttl(Duration::ZERO));
+    cache.detect(addr);
+    wait_until(|| cache.get(addr).is_some()).await;
+    assert_eq!(upstream.probes(), 2);
+    cache.detect(addr);
+    // 重新探测期间继续使用旧结果
+    assert!(cache.get(addr).is_some_and(|caps| caps.pipelining));
+    wait_until(|| upstream.probes() == 3).await;
+}
+
+#[tokio::test]
+async fn ip_requests_are_well_formed() {
+    for addr in ["10.0.0.1:80", "[2001:db8::1]:8080"] {
+        let (upstream, mut stream) = connect(Fault::None).await;
+        let dest: Destination = addr.parse::<SocketAddr>().unwrap().into();
+        handshake(&mut stream, &dest, None::<&[u8]>, None)
+            .await
+            .unwrap();
+        assert_eq!(upstream.requests(), vec![addr]);
+    }
+}
+
+#[tokio::test]
+async fn ipv6_bound_address_is_consumed() {
+    let (_upstream, mut stream) = connect(Fault::Ipv6Reply).await;
+    handshake(&mut stream, &domain(), Some(b"ping"), None)
+        .await
+        .unwrap();
+    let mut buf = [0u8; 4];
+    stream.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"ping");
+}
+
+#[tokio::test(start_paused = true)]
+async fn slow_greeting_times_out() {
+    let (_upstream, mut stream) = connect(Fault::SlowGreeting(Duration::from_secs(60))).await;
+    let err = handshake(&mut stream, &domain(), None::<&[u8]>, None)
+        .await
+        .unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::TimedOut);
+}
+
+#[tokio::test]
+async fn bad_version_is_rejected() {
+    let (_upstream, mut stream) = connect(Fault::BadVersion).await;
+    let err = handshake(&mut stream, &domain(), None::<&[u8]>, None)
+        .await
+        .unwrap_err();
+    assert!(err.to_string().contains("version"), "{}", err);
+}
+
+#[tokio::test]
+async fn truncated_reply_is_eof() {
+    let (_upstream, mut stream) = connect(Fault::TruncatedReply).await;
+    let err = handshake(&mut stream, &domain(), None::<&[u8]>, None)
+        .await
+        .unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+}
+
+#[tokio::test]
+async fn early_close_is_eof() {
+    let (_upstream, mut stream) = connect(Fault::EarlyClose).await;
+    let err = handshake(&mut stream, &domain(), None::<&[u8]>, None)
+        .await
+        .unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+}
+
+#[tokio::test]
+async fn rejected_request_reports_reply_code() {
+    let (_upstream, mut stream) = connect(Fault::Reject(0x05)).await;
+    let err = handshake(&mut stream, &domain(), None::<&[u8]>, None)
+        .await
+        .unwrap_err();
+    assert!(err.to_string().contains("0x05"), "{}", err);
+}
+
+#[tokio::test]
+async fn traced_connect_records_each_stage() {
+    let upstream = MockUpstream::spawn(Fault::None).await.unwrap();
+    let mut config = Config::new(upstream.addr());
+    config.rules = Rules::from_config(&[RuleConfig {
+        domains: vec!["traced.example.com".into()],
+        trace: true,
+        ..Default::default()
+    }])
+    .unwrap();
+    let config = Arc::new(config);
+    for (host, traced) in [("traced.example.com", true), ("example.com", false)] {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let _peer = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (left, _) = listener.accept().await.unwrap();
+        let mut client = Client::new(
+            left.into(),
+            (host, 443).into(),
+            config.clone(),
+            ConnId::next(),
+        )
+        .unwrap();
+        client.route().unwrap();
+        client.connect_remote_server().await.unwrap();
+        let stages = client.connect_stages().to_string();
+        if traced {
+            assert!(stages.starts_with("tcp connect "), "{}", stages);
+            assert!(stages.contains(", socks handshake "), "{}", stages);
+        } else {
+            assert!(stages.is_empty(), "{}", stages);
+        }
+    }
+}
@@ -0,0 +1,116 @@
+use std::{net::SocketAddr, sync::Arc};
+
+use futures_util::{SinkExt, StreamExt};
+use socket_proxy::{
+    client::Destination,
+    config::Config,
+    listener::Role,
+    server::ProxyServer,
+    testing::{client_hello, Fault, MockUpstream},
+    websocket::{self, DESTINATION_HEADER},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+use tokio_tungstenite::{
+    client_async,
+    tungstenite::{http::Request, Message},
+};
+
+fn request(addr: SocketAddr, path: &str, dest: Option<&str>) -> Request<()> {
+    let mut builder = Request::builder().uri(format!("ws://{}{}", addr, path));
+    if let Some(dest) = dest {
+        builder = builder.header(DESTINATION_HEADER, dest);
+    }
+    builder.body(()).unwrap()
+}
+
+#[test]
+fn parses_destinations() {
+    let parse = |s: &str| s.parse::<Destination>().map(|dest| dest.to_string());
+    assert_eq!(parse("example.com:443"), Ok("example.com:443".into()));
+    assert_eq!(parse("192.0.2.1:80"), Ok("192.0.2.1:80".into()));
+    assert_eq!(parse("[2001:db8::1]:8080"), Ok("[2001:db8::1]:8080".into()));
+    assert_eq!(parse("example.com"), Err("missing port"));
+    assert_eq!(parse("example.com:65536"), Err("invalid port"));
+    assert_eq!(parse("example.com:http"), Err("invalid port"));
+    assert_eq!(parse(":443"), Err("invalid host"));
+    assert_eq!(parse("2001:db8::1:443"), Err("invalid host"));
+    assert_eq!(
+        parse(&format!("{}:443", "a".repeat(256))),
+        Err("invalid host")
+    );
+}
+
+#[tokio::test]
+async fn accepts_tunnel_with_destination() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = tokio::spawn(async move {
+        let (socket, _) = listener.accept().await.unwrap();
+        let (mut stream, dest) = websocket::accept(socket, "/tunnel").await.unwrap();
+        let mut buf = [0u8; 4];
+        stream.read_exact(&mut buf).await.unwrap();
+        stream.write_all(&buf).await.unwrap();
+        stream.flush().await.unwrap();
+        dest.to_string()
+    });
+    let socket = TcpStream::connect(addr).await.unwrap();
+    let (mut ws, _) = client_async(request(addr, "/tunnel", Some("example.com:22")), socket)
+        .await
+        .unwrap();
+    ws.send(Message::Binary(b"ping".to_vec())).await.unwrap();
+    assert_eq!(
+        ws.next().await.unwrap().unwrap(),
+        Message::Binary(b"ping".to_vec())
+    );
+    assert_eq!(server.await.unwrap(), "example.com:22");
+}
+
+#[tokio::test]
+async fn rejects_bad_upgrades() {
+    for (path, dest) in [
+        ("/other", Some("example.com:22")),
+        ("/tunnel", None),
+        ("/tunnel", Some("example.com")),
+    ] {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            websocket::accept(socket, "/tunnel").await.map(|_| ())
+        });
+        let socket = TcpStream::connect(addr).await.unwrap();
+        assert!(client_async(request(addr, path, dest), socket)
+            .await
+            .is_err());
+        assert!(server.await.unwrap().is_err(), "{} {:?}", path, dest);
+    }
+}
+
+#[tokio::test]
+async fn sni_does_not_override_tunnel_destination() {
+    let upstream = MockUpstream::spawn(Fault::None).await.unwrap();
+    let server = ProxyServer::new(Arc::new(Config::new(upstream.addr())), None);
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let stats = server.register_listener("ws-sni-test".into(), Role::Socks);
+    tokio::spawn(server.serve_websocket(Arc::new(listener), stats, "/tunnel".into()));
+
+    let socket = TcpStream::connect(addr).await.unwrap();
+    let (mut ws, _) = client_async(request(addr, "/tunnel", Some("example.com:443")), socket)
+        .await
+        .unwrap();
+    let hello = client_hello(Some("other.example.org"));
+    ws.send(Message::Binary(hello.clone())).await.unwrap();
+    let mut echoed = Vec::new();
+    while echoed.len() < hello.len() {
+        match ws.next().await.unwrap().unwrap() {
+            Message::Binary(data) => echoed.extend(data),
+            _ => continue,
+        }
+    }
+    assert_eq!(echoed, hello);
+    assert_eq!(upstream.requests(), ["example.com:443"]);
+}